@@ -7,14 +7,408 @@ export interface FileSystemEvent {
   eventType: string
   path: string
   objectType: string
+  /** Failure description, only set on "error" events */
+  message?: string
+  /** "mount", "provider", "resolver", "lagged", "platform" or "quota", only set on "error" events */
+  errorKind?: string
+  /** What was being done when the error happened, e.g. "mount" or "read", only set on "error" events */
+  operation?: string
+  /** errno or HRESULT of a failure reported by the platform, only set on "error" events */
+  code?: number
+  /** Bytes read through the handle, only set on "accessed" events */
+  bytes?: number
+  /** Milliseconds since the Unix epoch */
+  time: number
+  /** Size after the operation, set on "created", "modified" and "deleted" events for entries the state holds */
+  size?: number
+  /** Inode number the entry has on the FUSE mount, kept across renames. Not set on "deleted" events since the inode is freed with the entry */
+  ino?: bigint
+  /** Where the entry was before, only set on "renamed" events whose `path` is the new location */
+  oldPath?: string
+}
+export interface FuseFSOptions {
+  /** Defaults to 4GB */
+  totalSpaceBytes?: number
+  /** Files and directories, defaults to 1M */
+  maxFiles?: number
+  /** Paths differing only in case refer to the same entry, which keeps the casing it was added with */
+  caseInsensitive?: boolean
+  /** Events a listener can fall behind on before missing some, defaults to 100 */
+  eventBufferSize?: number
+  /** Announce renames as a "deleted" then a "created" event instead of a single "renamed" one */
+  splitRenameEvents?: boolean
+  /**
+   * Count sparse files at their full size against totalSpaceBytes. By default only the bytes written to them count,
+   * holes left by truncating up or writing past the end of file take no space
+   */
+  quotaLogicalSize?: boolean
+}
+export interface FileStat {
+  size: number
+  isDirectory: boolean
+  /** Milliseconds since the Unix epoch */
+  mtime: number
+  /** Milliseconds since the Unix epoch */
+  atime: number
+  /** Permission bits, e.g. 0o644 */
+  mode: number
+  /** Owner ids, unset when the entry belongs to the user the mount runs as */
+  uid?: number
+  gid?: number
+  readonly: boolean
+  protected: boolean
+  ino: bigint
+}
+export interface LockInfo {
+  /** Process that took the lock */
+  pid: number
+  start: number
+  /** Last locked byte, unset when the lock runs to the end of the file */
+  end?: number
+  /** Write lock, otherwise a shared read lock */
+  exclusive: boolean
+}
+export interface DirectoryEntry {
+  name: string
+  path: string
+  isDirectory: boolean
+  size: number
+  /** Milliseconds since the Unix epoch */
+  mtime: number
+}
+export interface FindOptions {
+  /** Stop after this many matches, in path order */
+  limit?: number
+  /** Only match entries of this type: "file", "directory" or "symlink" */
+  objectType?: string
+  /** Return directory entries with metadata instead of plain paths */
+  withMetadata?: boolean
+}
+export interface CopyOptions {
+  /** Copy directories along with all of their descendants */
+  recursive?: boolean
+  /** Create missing parent directories of `dst` instead of failing with ENOENT, defaults to true */
+  createParents?: boolean
+}
+export interface MountOptions {
+  /** Overrides the quota given to the constructor */
+  totalSpaceBytes?: number
+  /** Let other users see the mount, needs user_allow_other in /etc/fuse.conf when not root. FUSE only */
+  allowOther?: boolean
+  /** Let root see the mount besides the user who mounted it. FUSE only */
+  allowRoot?: boolean
+  /** Have fusermount unmount the path if the process dies without unmounting, defaults to true. FUSE only */
+  autoUnmount?: boolean
+  /** Have the kernel check entry modes against the caller, defaults to true. FUSE only */
+  defaultPermissions?: boolean
+  /** Source shown in the mount table, "virtual" by default. FUSE only */
+  fsName?: string
+  /** Shown as the filesystem type after "fuse.". FUSE only */
+  subtype?: string
+  /**
+   * Directory of the virtual tree shown at the mount point, entries outside of it are not visible.
+   * Events and quotas still concern the whole tree
+   */
+  root?: string
+  /** Create the mount point and its missing parents instead of rejecting with ENOENT */
+  createMountPoint?: boolean
+  /**
+   * When reads through the mount update access times: "relatime" (default) when the access time is not newer
+   * than the modification time or is a day old, "noatime" never, "strictatime" on every read. FUSE only
+   */
+  atime?: string
+  /**
+   * How the kernel caches file content read through the mount: "auto" (default) drops cached pages when a file
+   * is opened again, "keep" retains them across opens, "direct" bypasses the cache so replaced content is seen
+   * by handles already open. FUSE only
+   */
+  cache?: string
+  /**
+   * Milliseconds the kernel caches name lookups, 1000 by default. 0 disables the cache so entries added or
+   * removed by the host are seen at once, at the cost of a lookup for every path resolution. FUSE only
+   */
+  entryTtlMs?: number
+  /**
+   * Milliseconds the kernel caches attributes such as the size and times, 1000 by default. 0 makes every stat
+   * reach the filesystem, so content replaced by the host is reflected immediately. FUSE only
+   */
+  attrTtlMs?: number
+  /**
+   * Extra options handed to the FUSE mount as is, e.g. "max_read=131072". Options the fields above control
+   * are rejected, as are entries containing commas. FUSE only
+   */
+  rawOptions?: Array<string>
+  /** Volume name Finder shows for the mount, defaults to `fsName`. macOS only */
+  volumeName?: string
+  /** Let Finder store "._" AppleDouble files and .DS_Store in the tree, off by default. macOS only */
+  appleDouble?: boolean
+  /** Allow com.apple.* extended attributes such as Finder info and quarantine flags, on by default. macOS only */
+  appleXattr?: boolean
+  /** Have Finder treat the mount as a local disk rather than a network volume, on by default. macOS only */
+  local?: boolean
+  /** Block size statfs reports the quota in, a power of two from 512 to 1MB, 4096 by default. FUSE only */
+  blockSize?: number
+  /** Longest file name statfs reports, up to 1024, 255 by default. FUSE only */
+  nameMax?: number
+  /**
+   * Changes made through the mount reported as events: any of "created", "modified", "deleted" and "renamed",
+   * all of them by default. Deletions and renames left out are not applied to the tree either. ProjFS only, FUSE
+   * sees every change as it happens
+   */
+  notifications?: Array<string>
+}
+export interface UnmountOptions {
+  /** Unmount even with files open through the mount, further I/O on them fails with EBADF */
+  force?: boolean
+}
+export interface RemoveOptions {
+  /** Remove directories along with all of their descendants instead of failing with ENOTEMPTY, defaults to true */
+  recursive?: boolean
+}
+export interface FileEntry {
+  path: string
+  content?: Buffer
+  isDirectory?: boolean
+}
+export interface AddFilesResult {
+  inserted: number
+  /** Entries left untouched because the path already existed or one of its ancestors is a file */
+  skipped: number
+}
+export interface AddOptions {
+  /** Create missing parent directories instead of failing with ENOENT, defaults to true */
+  createParents?: boolean
+}
+export interface TreeOptions {
+  /** Replace entries that already exist instead of failing with EEXIST */
+  overwrite?: boolean
+}
+export interface ImportProgress {
+  filesCopied: number
+  bytesCopied: number
+}
+export interface SkippedEntry {
+  /** Path on disk, or name inside the archive */
+  path: string
+  reason: string
+}
+export interface ImportResult {
+  files: number
+  directories: number
+  bytes: number
+  /** Symlinks, unreadable entries and files that would exceed the remaining quota */
+  skipped: Array<SkippedEntry>
+}
+export interface ZipImportResult {
+  imported: Array<string>
+  /** Unsafe names, unreadable entries and entries that would exceed a quota */
+  skipped: Array<SkippedEntry>
+}
+export interface ExportOptions {
+  /** Replace files that already exist on disk instead of skipping them */
+  overwrite?: boolean
+}
+export interface ExportResult {
+  files: number
+  directories: number
+  bytes: number
+  /** Existing files when not overwriting, and entries that could not be written */
+  skipped: Array<SkippedEntry>
+}
+export interface StreamOptions {
+  /**
+   * Store whatever was written so far when the stream fails, replacing any existing file, instead of
+   * leaving the path as it was
+   */
+  keepPartial?: boolean
+}
+export interface Usage {
+  /**
+   * Bytes under the requested prefix, the whole tree by default. Each entry counts its content plus a fixed
+   * overhead, the same figure the quota and statfs go by
+   */
+  usedBytes: number
+  totalBytes: number
+  /** Files and directories under the requested prefix */
+  fileCount: number
+  maxFiles: number
+}
+export interface QuotaOptions {
+  totalSpaceBytes?: number
+  maxFiles?: number
+}
+export interface MountInfo {
+  path: string
+  /** Milliseconds since the Unix epoch */
+  since: number
+  /** Files whose content was served through this mount */
+  filesProjected: number
+  bytesProjected: number
+}
+export interface ResolvedPath {
+  isDirectory?: boolean
+  content?: Buffer
+}
+export interface EventFilterOptions {
+  /** Glob the event path has to match, `*` stopping at path separators */
+  pathGlob?: string
+  /** Event types to deliver, e.g. ["created", "deleted"] */
+  types?: Array<string>
+}
+export interface WaitForEventOptions {
+  /** Event type to wait for, e.g. "created" */
+  type?: string
+  path?: string
+  /** Reject with ETIMEDOUT when no matching event arrives in time */
+  timeoutMs?: number
+}
+export interface EventStreamOptions {
+  /** Events held for a consumer that has not called next() yet, 1024 by default */
+  bufferSize?: number
+  /** "wait" (default) holds further events back until the consumer catches up, "drop" discards them */
+  overflow?: string
+  filter?: EventFilterOptions
+}
+export interface EventIteratorResult {
+  done: boolean
+  value?: FileSystemEvent
+}
+/** Returned by watch(), events stop being delivered once closed */
+export declare class Watcher {
+  close(): void
+}
+/** Returned by events(), an async iterator over the events sent after it was created */
+export declare class EventStream {
+  next(): Promise<IteratorResult<FileSystemEvent, undefined>>
+  /** Stops the stream and frees its subscription, called by `break` in a for await loop */
+  return(): Promise<IteratorResult<FileSystemEvent, undefined>>
+  [Symbol.asyncIterator](): EventStream
 }
 export type JsFuseFS = FuseFS
 export declare class FuseFS {
-  constructor()
-  mount(path: string, totalSpaceBytes: number): Promise<void>
-  unmount(): Promise<void>
-  addFile(path: string, content: Buffer): Promise<void>
-  addDirectory(path: string): Promise<void>
-  removePath(path: string): Promise<void>
-  on(callback: (...args: any[]) => any): void
+  constructor(options?: FuseFSOptions | undefined | null)
+  /**
+   * Resolves once the path is served. Rejects with ENOENT, ENOTDIR, EBUSY or ENOTEMPTY when the
+   * directory cannot be mounted on. When mounting itself fails FUSE rejects with ENODEV if it is not
+   * available, EACCES if the mount is not permitted, EBUSY or EINVAL if the kernel refuses it and EIO
+   * otherwise, ProjFS with its own error.
+   * A number in place of `options` is taken as `totalSpaceBytes`
+   */
+  mount(path: string, options?: number | MountOptions | undefined | null): Promise<void>
+  /**
+   * Moves the mount to `newPath`, keeping its options and the whole tree. Rejects with EBUSY like
+   * unmount() while files are open through the old path. A "mounted" event is sent once `newPath` is served
+   */
+  remount(newPath: string): Promise<void>
+  /**
+   * Resolves once the session is torn down, the directory can be removed or mounted again right away.
+   * Rejects with EBUSY, listing the open paths, while files are open through the mount unless forced.
+   * Resolves as well once the mount went away from outside, e.g. with umount, which already sent "unmounted"
+   */
+  unmount(options?: UnmountOptions | undefined | null): Promise<void>
+  /**
+   * Blocking unmount() for process 'exit' handlers, which cannot wait for a promise. Throws ETIMEDOUT
+   * when the teardown takes longer than 5 seconds. Waits for an unmount() already under way within
+   * that same time, and does nothing once either of them has completed
+   */
+  unmountSync(options?: UnmountOptions | undefined | null): void
+  /** False as soon as the mount went away, including from outside, e.g. with umount */
+  isMounted(): boolean
+  mounts(): Promise<Array<MountInfo>>
+  /** Quotas are global, only the used counts are narrowed down by `prefix` */
+  getUsage(prefix?: string | undefined | null): Promise<Usage>
+  /**
+   * Takes effect immediately, including on mounted sessions. Lowering a limit below the
+   * current usage is allowed, new writes are rejected until enough is freed
+   */
+  setQuota(quota: QuotaOptions): Promise<void>
+  /** Null unless a session is currently mounted */
+  mountPath(): string | null
+  addFile(path: string, content: Buffer, options?: AddOptions | undefined | null): Promise<void>
+  addFiles(entries: Array<FileEntry>): Promise<AddFilesResult>
+  addTree(tree: Record<string, any>, options?: TreeOptions | undefined | null): Promise<Array<string>>
+  /** Entries already present at the destination are replaced by their on-disk counterpart */
+  importDirectory(realPath: string, prefix: string, onProgress?: ((progress: ImportProgress) => void) | undefined | null): Promise<ImportResult>
+  /**
+   * `source` is either the archive itself or its path on disk. Entries already present
+   * at the destination are replaced
+   */
+  importFromZip(source: Buffer | string, prefix?: string | undefined | null): Promise<ZipImportResult>
+  /** Only takes read locks, lazy files are fetched from their provider without being cached */
+  exportToDirectory(prefix: string, realPath: string, options?: ExportOptions | undefined | null): Promise<ExportResult>
+  /** Entry names are relative to `prefix`, which defaults to the whole tree */
+  exportToTar(destination: string | NodeJS.WritableStream, prefix?: string | undefined | null): Promise<void>
+  addFileFromStream(path: string, stream: NodeJS.ReadableStream, options?: StreamOptions | undefined | null): Promise<void>
+  addLazyFile(path: string, size: number, provider: (path: string) => Buffer | Promise<Buffer>): Promise<void>
+  appendFile(path: string, data: Buffer, create?: boolean | undefined | null): Promise<void>
+  writeFileAt(path: string, offset: number, data: Buffer): Promise<number>
+  setContent(path: string, content: Buffer): Promise<void>
+  setTimes(path: string, times: { mtime?: number | Date, atime?: number | Date }): Promise<void>
+  /** Permission bits are only enforced on Unix, ProjFS has no equivalent */
+  setMode(path: string, mode: number): Promise<void>
+  /** Ownership is only reported on Unix, an unset id leaves the current owner in place */
+  setOwner(path: string, uid?: number | undefined | null, gid?: number | undefined | null): Promise<void>
+  /** Read-only entries reject writes and deletions made through the mount, the methods of this class are unaffected */
+  setReadonly(path: string, readonly: boolean): Promise<void>
+  /** Protected entries cannot be deleted through the mount, nor replaced by a rename, but can still be written */
+  setProtected(path: string, protect: boolean): Promise<void>
+  /**
+   * Overrides the cache mount option for one file, e.g. "direct" for content replaced while readers have it open.
+   * Null reverts to the mount's policy. Applies to opens made after the call, FUSE only
+   */
+  setCachePolicy(path: string, policy: 'auto' | 'keep' | 'direct' | null): Promise<void>
+  /** Extended attributes are only exposed through the mount on Unix. Resolves to null when the entry has no such attribute */
+  getXattr(path: string, name: string): Promise<Buffer | null>
+  setXattr(path: string, name: string, value: Buffer): Promise<void>
+  /** Sorted attribute names */
+  listXattrs(path: string): Promise<Array<string>>
+  removeXattr(path: string, name: string): Promise<void>
+  /** On Windows the link is projected as a regular file whose content is the target path */
+  addSymlink(path: string, target: string): Promise<void>
+  readSymlink(path: string): Promise<string>
+  /** Rejects with EEXIST when an entry, directory or not, is already at `path` */
+  addDirectory(path: string, options?: AddOptions | undefined | null): Promise<void>
+  /** Rejects with EFBIG rather than returning more than 2GB, larger files have to be read in ranges */
+  readFile(path: string, offset?: number | undefined | null, length?: number | undefined | null): Promise<Buffer>
+  stat(path: string): Promise<FileStat>
+  /** POSIX locks processes hold on the entry through the mount, sorted by start offset */
+  getLocks(path: string): Promise<Array<LockInfo>>
+  exists(path: string): Promise<string | null>
+  listDirectory(path: string): Promise<Array<DirectoryEntry>>
+  /** `*` stops at path separators while `**` spans any number of directories */
+  find(pattern: string, options?: FindOptions | undefined | null): Promise<Array<string> | Array<DirectoryEntry>>
+  renamePath(oldPath: string, newPath: string, overwrite?: boolean | undefined | null): Promise<void>
+  copyPath(src: string, dst: string, options?: CopyOptions | undefined | null): Promise<void>
+  /** Subtrees larger than 1000 entries only produce a Deleted event for `path` itself */
+  removePath(path: string, options?: RemoveOptions | undefined | null): Promise<void>
+  clear(): Promise<void>
+  /** Lazy files are fetched from their provider first so their content is captured */
+  snapshot(): Promise<Buffer>
+  /** Replaces every entry with the ones captured by snapshot() */
+  restore(snapshot: Buffer): Promise<void>
+  onMissingPath(resolver: ((path: string) => ResolvedPath | Buffer | null | Promise<ResolvedPath | Buffer | null>) | null): void
+  /**
+   * Reads through the mount start producing "accessed" events, one per open handle on Unix
+   * and one per hydrated file on Windows
+   */
+  enableAccessEvents(): void
+  disableAccessEvents(): void
+  /** `filter` is a path, matching it and everything below it, or a glob pattern */
+  watch(filter: string, callback: (event: FileSystemEvent) => void): Watcher
+  /** Returns an id to pass to off(). Events not matching `filter` never reach JS */
+  on(callback: (event: FileSystemEvent) => void, filter?: EventFilterOptions | undefined | null): number
+  /**
+   * Called with the "error" events: failures of the mount, lazy file providers, the missing path
+   * resolver or listeners falling behind. Returns an id to pass to off()
+   */
+  onError(callback: (error: FileSystemEvent) => void): number
+  /** Removed after the first matching event, returns an id to pass to off() before that */
+  once(callback: (event: FileSystemEvent) => void, filter?: EventFilterOptions | undefined | null): number
+  /** Async iterator over the events sent from now on: `for await (const event of fs.events())` */
+  events(options?: EventStreamOptions | undefined | null): EventStream
+  /** Resolves with the first matching event sent after the call, every field of `options` is optional */
+  waitForEvent(options?: WaitForEventOptions | undefined | null): Promise<FileSystemEvent>
+  /** Returns whether a listener was registered under this id */
+  off(id: number): boolean
+  removeAllListeners(): void
 }
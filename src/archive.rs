@@ -0,0 +1,205 @@
+use crate::common::SharedFSState;
+use crate::content::Content;
+use napi::bindgen_prelude::*;
+use napi::threadsafe_function::{ErrorStrategy, ThreadsafeFunction, ThreadsafeFunctionCallMode};
+use std::io::Write;
+use std::sync::Arc;
+
+// Bytes buffered before a chunk is handed to the JS stream
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Forwards archive bytes to a JS writable stream: Some(chunk) writes, None ends the stream
+pub type StreamSink = ThreadsafeFunction<Option<Vec<u8>>, ErrorStrategy::Fatal>;
+
+pub struct StreamWriter {
+	sink: StreamSink,
+	pending: Vec<u8>,
+}
+
+impl StreamWriter {
+	pub fn new(sink: StreamSink) -> Self {
+		Self { sink, pending: Vec::with_capacity(CHUNK_SIZE) }
+	}
+
+	// Flushes what is left and resolves once the stream has finished
+	pub async fn end(mut self) -> Result<()> {
+		self.flush().map_err(|e| Error::from_reason(e.to_string()))?;
+		let finished = self.sink.call_async::<Promise<()>>(None).await?;
+		finished.await
+	}
+}
+
+impl Write for StreamWriter {
+	fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+		self.pending.extend_from_slice(data);
+		if self.pending.len() >= CHUNK_SIZE {
+			self.flush()?;
+		}
+		Ok(data.len())
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		if !self.pending.is_empty() {
+			let chunk = std::mem::replace(&mut self.pending, Vec::with_capacity(CHUNK_SIZE));
+			// Blocking on a bounded queue keeps a slow stream from piling up the whole archive in memory
+			self.sink.call(Some(chunk), ThreadsafeFunctionCallMode::Blocking);
+		}
+		Ok(())
+	}
+}
+
+// Writes every entry under `prefix` as a tar archive, one file's content in memory at a time.
+// Names are relative to the prefix, lazy files are fetched without being cached
+pub async fn write_tar<W: Write>(state: &SharedFSState, prefix: &str, writer: W) -> Result<W> {
+	let io_error = |e: std::io::Error| Error::from_reason(e.to_string());
+
+	let paths = {
+		let state = state.read().await;
+		if !prefix.is_empty() {
+			match state.files.get(prefix) {
+				Some(file) if file.is_directory => {}
+				Some(_) => return Err(Error::from_reason(format!("ENOTDIR: not a directory, '{}'", prefix))),
+				None => return Err(Error::from_reason(format!("ENOENT: no such file or directory, '{}'", prefix))),
+			}
+		}
+
+		let descendant = format!("{}/", prefix);
+		let mut paths: Vec<String> = state.files.keys()
+			.filter(|path| prefix.is_empty() || path.starts_with(&descendant))
+			.cloned()
+			.collect();
+		// Parents sort before their children
+		paths.sort();
+		paths
+	};
+
+	let mut builder = tar::Builder::new(writer);
+	for path in paths {
+		let name = if prefix.is_empty() { path.as_str() } else { &path[prefix.len() + 1..] };
+
+		let (is_directory, is_symlink, mode, mtime, content, provider) = match state.read().await.files.get(&path) {
+			Some(file) => (file.is_directory, file.is_symlink, file.perm(), file.mtime, file.content.clone(), file.provider.clone()),
+			// Removed since the listing was taken
+			None => continue,
+		};
+
+		let mut header = tar::Header::new_gnu();
+		header.set_mode(mode as u32);
+		header.set_mtime(mtime.duration_since(std::time::UNIX_EPOCH).map_or(0, |duration| duration.as_secs()));
+
+		if is_directory {
+			header.set_entry_type(tar::EntryType::Directory);
+			header.set_size(0);
+			builder.append_data(&mut header, format!("{}/", name), std::io::empty()).map_err(io_error)?;
+			continue;
+		}
+
+		let content = match provider {
+			Some(provider) => provider(path.clone()).await
+				.map(|content| Arc::new(Content::from(content)))
+				.map_err(|message| Error::from_reason(format!("EIO: {}, '{}'", message, path)))?,
+			None => content,
+		};
+
+		if is_symlink {
+			header.set_entry_type(tar::EntryType::Symlink);
+			header.set_size(0);
+			builder.append_link(&mut header, name, String::from_utf8_lossy(&content.to_vec()).as_ref()).map_err(io_error)?;
+		} else {
+			header.set_entry_type(tar::EntryType::Regular);
+			header.set_size(content.size());
+			// Streamed in bounded reads rather than copied out whole first
+			builder.append_data(&mut header, name, content.reader()).map_err(io_error)?;
+		}
+	}
+
+	builder.into_inner().map_err(io_error)
+}
+
+pub struct ZipEntry {
+	// Normalized, relative to the archive root
+	pub path: String,
+	pub is_directory: bool,
+	pub content: Vec<u8>,
+	pub mtime: std::time::SystemTime,
+	pub mode: Option<u16>,
+}
+
+// Rejects absolute names and ".." components so entries cannot escape the import prefix
+fn safe_zip_path(name: &str) -> Option<String> {
+	let name = name.replace('\\', "/");
+	let is_absolute = name.starts_with('/') || name.as_bytes().get(1) == Some(&b':');
+	if is_absolute || name.split('/').any(|component| component == "..") {
+		return None;
+	}
+
+	let path = name.split('/')
+		.filter(|component| !component.is_empty() && *component != ".")
+		.collect::<Vec<_>>()
+		.join("/");
+	(!path.is_empty()).then_some(path)
+}
+
+// Zip timestamps are local date/time fields without a timezone, they are taken as UTC
+fn zip_time_to_system_time(time: zip::DateTime) -> std::time::SystemTime {
+	// Days since the Unix epoch for a proleptic Gregorian date
+	let (year, month, day) = (time.year() as i64, time.month() as i64, time.day() as i64);
+	let year = if month <= 2 { year - 1 } else { year };
+	let era = year.div_euclid(400);
+	let year_of_era = year - era * 400;
+	let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+	let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+	let days = era * 146097 + day_of_era - 719468;
+
+	let seconds = days * 86400 + time.hour() as i64 * 3600 + time.minute() as i64 * 60 + time.second() as i64;
+	std::time::UNIX_EPOCH + std::time::Duration::from_secs(seconds.max(0) as u64)
+}
+
+pub struct ZipContents {
+	pub entries: Vec<ZipEntry>,
+	// (name, reason) of the entries that could not be read
+	pub skipped: Vec<(String, String)>,
+}
+
+pub fn read_zip<R: std::io::Read + std::io::Seek>(reader: R) -> std::result::Result<ZipContents, String> {
+	let mut archive = zip::ZipArchive::new(reader).map_err(|e| e.to_string())?;
+	let mut entries = Vec::new();
+	let mut skipped = Vec::new();
+
+	for index in 0..archive.len() {
+		let mut file = match archive.by_index(index) {
+			Ok(file) => file,
+			Err(e) => {
+				skipped.push((format!("#{}", index), e.to_string()));
+				continue;
+			}
+		};
+
+		let name = file.name().to_string();
+		let path = match safe_zip_path(&name) {
+			Some(path) => path,
+			None => {
+				skipped.push((name, "unsafe path".to_string()));
+				continue;
+			}
+		};
+
+		let mut content = Vec::new();
+		if !file.is_dir() {
+			if let Err(e) = std::io::Read::read_to_end(&mut file, &mut content) {
+				skipped.push((name, e.to_string()));
+				continue;
+			}
+		}
+
+		entries.push(ZipEntry {
+			path,
+			is_directory: file.is_dir(),
+			content,
+			mtime: zip_time_to_system_time(file.last_modified()),
+			mode: file.unix_mode().map(|mode| (mode & 0o7777) as u16).filter(|mode| *mode != 0),
+		});
+	}
+
+	Ok(ZipContents { entries, skipped })
+}
@@ -1,12 +1,86 @@
+use crate::content::Content;
 use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::{RwLock, broadcast};
-use std::time::SystemTime;
+use std::collections::hash_map::Drain;
+use std::future::Future;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use tokio::sync::{RwLock, broadcast, watch};
+use std::time::{Duration, Instant, SystemTime};
+
+// How long a "does not exist" answer from the path resolver is trusted
+const NEGATIVE_CACHE_TTL: Duration = Duration::from_secs(1);
 
 #[derive(Clone, Debug)]
 pub enum ObjectType {
 	File,
 	Directory,
+	Symlink,
+}
+
+impl ObjectType {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			ObjectType::File => "file",
+			ObjectType::Directory => "directory",
+			ObjectType::Symlink => "symlink",
+		}
+	}
+}
+
+// How the kernel page cache treats a file opened through the mount
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CachePolicy {
+	// Cached pages are dropped whenever the file is opened again
+	Auto,
+	// Cached pages survive reopening, for content that does not change
+	Keep,
+	// Every read reaches the filesystem, for content the host replaces while it is open
+	Direct,
+}
+
+impl CachePolicy {
+	pub fn from_name(name: &str) -> Option<Self> {
+		match name {
+			"auto" => Some(CachePolicy::Auto),
+			"keep" => Some(CachePolicy::Keep),
+			"direct" => Some(CachePolicy::Direct),
+			_ => None,
+		}
+	}
+}
+
+// What went wrong for an Error event
+#[derive(Clone, Copy, Debug)]
+pub enum ErrorKind {
+	// Starting or tearing down a mount failed
+	Mount,
+	// A lazy file provider rejected
+	Provider,
+	// The missing path resolver rejected
+	Resolver,
+	// A listener fell behind the broadcast channel and missed events
+	Lagged,
+	// A call into ProjFS failed. FUSE failures go back to the caller through the reply instead
+	#[cfg_attr(not(windows), allow(dead_code))]
+	Platform,
+	// A change made through the mount went over a quota, ProjFS only tells after the fact, or an
+	// entry the missing path resolver answered with did not fit
+	Quota,
+}
+
+impl ErrorKind {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			ErrorKind::Mount => "mount",
+			ErrorKind::Provider => "provider",
+			ErrorKind::Resolver => "resolver",
+			ErrorKind::Lagged => "lagged",
+			ErrorKind::Platform => "platform",
+			ErrorKind::Quota => "quota",
+		}
+	}
 }
 
 #[derive(Clone, Debug)]
@@ -14,63 +88,1175 @@ pub enum FSEvent {
 	Created { path: String, object_type: ObjectType },
 	Modified { path: String, object_type: ObjectType },
 	Deleted { path: String, object_type: ObjectType },
+	// Every entry was removed at once, sent instead of one Deleted per entry
+	Cleared,
+	// Many entries were inserted at once, sent instead of one Created per entry so bulk inserts
+	// do not flood the broadcast channel
+	BulkCreated,
+	// Something failed outside of a call made from JS. `path` is empty when no entry is concerned,
+	// `code` is the errno or HRESULT when the failure came from the platform
+	Error { path: String, message: String, kind: ErrorKind, operation: &'static str, code: Option<i32> },
+	// Content was read through the mount, one per open handle rather than per read
+	Accessed { path: String, bytes: u64 },
+	// An entry moved, directories along with their descendants which get no event of their own
+	Renamed { from: String, to: String, object_type: ObjectType },
+	// A mount started serving, `path` being the mount point on the host rather than an entry
+	Mounted { path: String },
+	// A mount stopped serving, whether through unmount() or from outside, e.g. with umount
+	Unmounted { path: String },
 }
 
+// What subscribers receive: the event along with what was known about the entry when it was sent
+#[derive(Clone, Debug)]
+pub struct EventRecord {
+	pub event: FSEvent,
+	pub time: SystemTime,
+	// Size once the operation completed: 0 for deletions, None when the entry is not in the state
+	pub size: Option<u64>,
+	// Inode the entry had once the operation completed, None when the entry is not in the state
+	pub ino: Option<u64>,
+}
+
+impl FSEvent {
+	// None for events that concern the whole tree rather than a single path
+	pub fn path(&self) -> Option<&str> {
+		match self {
+			FSEvent::Created { path, .. }
+			| FSEvent::Modified { path, .. }
+			| FSEvent::Deleted { path, .. }
+			| FSEvent::Error { path, .. }
+			| FSEvent::Accessed { path, .. }
+			| FSEvent::Renamed { to: path, .. } => Some(path),
+			FSEvent::Cleared | FSEvent::BulkCreated | FSEvent::Mounted { .. } | FSEvent::Unmounted { .. } => None,
+		}
+	}
+}
+
+pub type ContentFuture = Pin<Box<dyn Future<Output = Result<Vec<u8>, String>> + Send>>;
+
+// Produces the content of a lazy file the first time it is read, given its path
+pub type ContentProvider = Arc<dyn Fn(String) -> ContentFuture + Send + Sync>;
+
+pub type ResolveFuture = Pin<Box<dyn Future<Output = Result<Option<VirtualFile>, String>> + Send>>;
+
+// Consulted when a path is missing from the state, Ok(None) meaning it does not exist
+pub type PathResolver = Arc<dyn Fn(String) -> ResolveFuture + Send + Sync>;
+
+#[derive(Clone)]
 pub struct VirtualFile {
-	pub content: Vec<u8>,
+	// Shared so readers can take the content and release the state lock before copying it out,
+	// writes only copy it while such a reader still holds it
+	pub content: Arc<Content>,
 	pub size: u64,
 	pub is_directory: bool,
+	// Symlinks store their target path as content
+	pub is_symlink: bool,
 	pub mtime: SystemTime,
+	pub atime: SystemTime,
+	// Permission bits, None meaning the default for the entry type
+	pub mode: Option<u16>,
+	// Owner ids, None meaning the user the mount runs as
+	pub uid: Option<u32>,
+	pub gid: Option<u32>,
+	// Extended attributes by name, set through the mount or the host API
+	pub xattrs: HashMap<String, Vec<u8>>,
+	// Overrides the cache policy of the mount for this file
+	pub cache: Option<CachePolicy>,
+	// Rejects modifications coming from the mount, the host API can still update the entry
+	pub readonly: bool,
+	// Rejects deletion from the mount, including being replaced by a rename, while writes stay allowed
+	pub protected: bool,
+	// Set until the content of a lazy file has been fetched, `size` holds the announced size meanwhile
+	pub provider: Option<ContentProvider>,
 }
 
 impl Default for VirtualFile {
 	fn default() -> Self {
 		Self {
-			content: Vec::new(),
+			content: Arc::default(),
 			size: 0,
 			is_directory: false,
+			is_symlink: false,
 			mtime: SystemTime::now(),
+			atime: SystemTime::now(),
+			mode: None,
+			uid: None,
+			gid: None,
+			xattrs: HashMap::new(),
+			cache: None,
+			readonly: false,
+			protected: false,
+			provider: None,
 		}
 	}
 }
 
 impl VirtualFile {
+	pub fn perm(&self) -> u16 {
+		self.mode.unwrap_or(if self.is_directory {
+			0o755
+		} else if self.is_symlink {
+			0o777
+		} else {
+			0o644
+		})
+	}
+
 	pub fn get_type(&self) -> ObjectType {
 		if self.is_directory {
 			ObjectType::Directory
+		} else if self.is_symlink {
+			ObjectType::Symlink
 		} else {
 			ObjectType::File
 		}
 	}
+
+	// Content bytes the entry counts against total_space_bytes. Directories hold no content of their own,
+	// holes in sparse files only count with `logical`, lazy files count the size they announced
+	pub fn content_bytes(&self, logical: bool) -> u64 {
+		if self.is_directory {
+			0
+		} else if logical || self.provider.is_some() {
+			self.size
+		} else {
+			self.content.stored_bytes()
+		}
+	}
+
+	pub fn used_bytes(&self, logical: bool) -> u64 {
+		entry_bytes(self.content_bytes(logical))
+	}
+
+	// Content bytes once `len` bytes are written at `offset`
+	pub fn content_bytes_after_write(&self, offset: u64, len: u64, logical: bool) -> u64 {
+		if logical {
+			self.size.max(offset + len)
+		} else {
+			self.content_bytes(false) + len - self.content.stored_in(offset, len)
+		}
+	}
+
+	// Content bytes once truncated or extended to `size`, extending only adds a hole
+	#[cfg_attr(windows, allow(dead_code))]
+	pub fn content_bytes_after_resize(&self, size: u64, logical: bool) -> u64 {
+		if logical {
+			size
+		} else {
+			self.content.stored_in(0, size)
+		}
+	}
+
+	// Writes `data` at `offset`, any gap past the current end of file becoming a hole
+	pub fn write_at(&mut self, offset: u64, data: &[u8]) {
+		let content = Arc::make_mut(&mut self.content);
+		content.write(offset, data);
+		self.size = content.size();
+	}
+
+	#[cfg_attr(windows, allow(dead_code))]
+	pub fn resize(&mut self, size: u64) {
+		Arc::make_mut(&mut self.content).set_len(size);
+		self.size = size;
+	}
+}
+
+// Per-mount counters, shared between the session map and the filesystem callbacks
+pub struct SessionStats {
+	pub since: SystemTime,
+	pub files_projected: AtomicU64,
+	pub bytes_projected: AtomicU64,
+	// Path of every open FUSE handle or in-progress ProjFS hydration, these keep the session busy
+	handles: Mutex<HashMap<u64, String>>,
+	next_handle: AtomicU64,
+	// Set by a forced unmount so operations still waiting on a provider give up
+	cancelled: watch::Sender<bool>,
+	// Set once the platform tore the session down, which may happen without unmount() being called
+	ended: watch::Sender<bool>,
+}
+
+impl Default for SessionStats {
+	fn default() -> Self {
+		Self {
+			since: SystemTime::now(),
+			files_projected: AtomicU64::new(0),
+			bytes_projected: AtomicU64::new(0),
+			handles: Mutex::new(HashMap::new()),
+			next_handle: AtomicU64::new(1),
+			cancelled: watch::channel(false).0,
+			ended: watch::channel(false).0,
+		}
+	}
+}
+
+impl SessionStats {
+	// Content is served in chunks, a file counts once its first chunk is read
+	pub fn record_read(&self, offset: u64, len: usize) {
+		if offset == 0 {
+			self.files_projected.fetch_add(1, Ordering::Relaxed);
+		}
+		self.bytes_projected.fetch_add(len as u64, Ordering::Relaxed);
+	}
+
+	// Ids start at 1, so 0 is never an open handle
+	pub fn open_handle(&self, path: String) -> u64 {
+		let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
+		self.handles.lock().unwrap().insert(handle, path);
+		handle
+	}
+
+	pub fn close_handle(&self, handle: u64) {
+		self.handles.lock().unwrap().remove(&handle);
+	}
+
+	// False once the handle was closed or invalidated by a forced unmount
+	#[cfg_attr(windows, allow(dead_code))]
+	pub fn is_handle_open(&self, handle: u64) -> bool {
+		self.handles.lock().unwrap().contains_key(&handle)
+	}
+
+	pub fn busy_paths(&self) -> Vec<String> {
+		let mut paths: Vec<String> = self.handles.lock().unwrap().values().cloned().collect();
+		paths.sort();
+		paths.dedup();
+		paths
+	}
+
+	pub fn invalidate_handles(&self) {
+		self.handles.lock().unwrap().clear();
+		self.cancelled.send_replace(true);
+	}
+
+	// Resolves once a forced unmount invalidated the handles
+	#[cfg_attr(windows, allow(dead_code))]
+	pub async fn cancelled(&self) {
+		self.cancelled.subscribe().wait_for(|cancelled| *cancelled).await.ok();
+	}
+
+	pub fn end(&self) {
+		self.ended.send_replace(true);
+	}
+
+	#[cfg_attr(windows, allow(dead_code))]
+	pub fn has_ended(&self) -> bool {
+		*self.ended.borrow()
+	}
+
+	pub async fn ended(&self) {
+		self.ended.subscribe().wait_for(|ended| *ended).await.ok();
+	}
+}
+
+// POSIX byte-range lock taken through the mount, `end` is inclusive
+#[derive(Clone, Debug)]
+pub struct FileLock {
+	pub owner: u64,
+	pub pid: u32,
+	pub start: u64,
+	pub end: u64,
+	pub exclusive: bool,
+}
+
+impl FileLock {
+	fn overlaps(&self, start: u64, end: u64) -> bool {
+		self.start <= end && start <= self.end
+	}
+}
+
+// Locks by inode, shared by every session of the state. `released` wakes the requests
+// waiting for a conflicting lock to go away
+#[derive(Default)]
+pub struct LockTable {
+	locks: Mutex<HashMap<u64, Vec<FileLock>>>,
+	released: Condvar,
+}
+
+fn find_conflict(locks: &HashMap<u64, Vec<FileLock>>, ino: u64, lock: &FileLock) -> Option<FileLock> {
+	locks.get(&ino)?.iter()
+		.find(|held| held.owner != lock.owner && held.overlaps(lock.start, lock.end) && (held.exclusive || lock.exclusive))
+		.cloned()
+}
+
+// Clears what `owner` holds over start..=end, keeping the parts of its locks outside the range, then adds `lock`
+fn set_range(locks: &mut HashMap<u64, Vec<FileLock>>, ino: u64, owner: u64, start: u64, end: u64, lock: Option<FileLock>) {
+	let mut kept = Vec::new();
+	for held in locks.remove(&ino).unwrap_or_default() {
+		if held.owner != owner || !held.overlaps(start, end) {
+			kept.push(held);
+			continue;
+		}
+		if held.start < start {
+			kept.push(FileLock { end: start - 1, ..held.clone() });
+		}
+		if held.end > end {
+			kept.push(FileLock { start: end + 1, ..held });
+		}
+	}
+	kept.extend(lock);
+	if !kept.is_empty() {
+		locks.insert(ino, kept);
+	}
+}
+
+#[cfg_attr(windows, allow(dead_code))]
+impl LockTable {
+	// A lock of another owner that keeps `lock` from being taken
+	pub fn conflict(&self, ino: u64, lock: &FileLock) -> Option<FileLock> {
+		find_conflict(&self.locks.lock().unwrap(), ino, lock)
+	}
+
+	// Takes `lock` in place of what its owner held over the same range, or returns the lock in the way
+	pub fn try_lock(&self, ino: u64, lock: FileLock) -> Result<(), FileLock> {
+		let mut locks = self.locks.lock().unwrap();
+		if let Some(conflict) = find_conflict(&locks, ino, &lock) {
+			return Err(conflict);
+		}
+		set_range(&mut locks, ino, lock.owner, lock.start, lock.end, Some(lock));
+		Ok(())
+	}
+
+	// Blocks until no other owner's lock is in the way
+	pub fn lock_wait(&self, ino: u64, lock: FileLock) {
+		let mut locks = self.locks.lock().unwrap();
+		while find_conflict(&locks, ino, &lock).is_some() {
+			locks = self.released.wait(locks).unwrap();
+		}
+		set_range(&mut locks, ino, lock.owner, lock.start, lock.end, Some(lock));
+	}
+
+	pub fn unlock(&self, ino: u64, owner: u64, start: u64, end: u64) {
+		set_range(&mut self.locks.lock().unwrap(), ino, owner, start, end, None);
+		self.released.notify_all();
+	}
+
+	// Everything `owner` holds on the inode, dropped when it closes the file
+	pub fn release_owner(&self, ino: u64, owner: u64) {
+		self.unlock(ino, owner, 0, u64::MAX);
+	}
+
+	pub fn locks(&self, ino: u64) -> Vec<FileLock> {
+		let mut locks = self.locks.lock().unwrap().get(&ino).cloned().unwrap_or_default();
+		locks.sort_by_key(|lock| lock.start);
+		locks
+	}
+}
+
+// Entries keyed by path. In case-insensitive mode `folded` maps every lowercased key to the
+// key as it was first inserted, lookups go through it so the original casing is kept for display.
+// Hard links are extra keys in `links` that resolve to the entry, and inode, they share.
+// Iteration yields every key, links included, while values() and len() count each entry once
+pub struct FileMap {
+	entries: HashMap<String, VirtualFile>,
+	folded: Option<HashMap<String, String>>,
+	links: HashMap<String, String>,
+	// Inode numbers reported to FUSE, allocated when a key is first inserted and kept by renames.
+	// 1 stands for the mount root, which is not an entry, so allocation starts at 2
+	inodes: HashMap<String, u64>,
+	paths: HashMap<u64, String>,
+	next_inode: u64,
+}
+
+fn stored_key<'a>(folded: &'a Option<HashMap<String, String>>, path: &'a str) -> &'a str {
+	match folded {
+		Some(folded) => folded.get(&path.to_lowercase()).map_or(path, String::as_str),
+		None => path,
+	}
+}
+
+impl FileMap {
+	pub fn new(case_insensitive: bool) -> Self {
+		Self {
+			entries: HashMap::new(),
+			folded: case_insensitive.then(HashMap::new),
+			links: HashMap::new(),
+			inodes: HashMap::new(),
+			paths: HashMap::new(),
+			next_inode: 2,
+		}
+	}
+
+	// Key `path` is stored under, or would be once inserted: in case-insensitive mode the casing
+	// of an existing entry, or of its closest existing ancestor, wins over the requested one
+	pub fn key(&self, path: &str) -> String {
+		let Some(folded) = &self.folded else {
+			return path.to_string();
+		};
+		if let Some(key) = folded.get(&path.to_lowercase()) {
+			return key.clone();
+		}
+		match path.rsplit_once('/') {
+			Some((parent, name)) => format!("{}/{}", self.key(parent), name),
+			None => path.to_string(),
+		}
+	}
+
+	pub fn is_case_insensitive(&self) -> bool {
+		self.folded.is_some()
+	}
+
+	// Key of the entry `path` names, the linked entry for a hard link
+	fn resolve<'a>(&'a self, path: &'a str) -> &'a str {
+		let key = stored_key(&self.folded, path);
+		self.links.get(key).map_or(key, String::as_str)
+	}
+
+	pub fn get(&self, path: &str) -> Option<&VirtualFile> {
+		self.entries.get(self.resolve(path))
+	}
+
+	pub fn get_mut(&mut self, path: &str) -> Option<&mut VirtualFile> {
+		let key = self.resolve(path).to_string();
+		self.entries.get_mut(&key)
+	}
+
+	pub fn contains_key(&self, path: &str) -> bool {
+		self.entries.contains_key(self.resolve(path))
+	}
+
+	pub fn ino(&self, path: &str) -> Option<u64> {
+		self.inodes.get(self.resolve(path)).copied()
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = (&String, &VirtualFile)> {
+		let links = self.links.iter()
+			.filter_map(|(link, key)| self.entries.get(key).map(|file| (link, file)));
+		self.entries.iter().chain(links)
+	}
+
+	pub fn keys(&self) -> impl Iterator<Item = &String> {
+		self.iter().map(|(path, _)| path)
+	}
+
+	// Names the entry behind `path` also goes by, directories always counting 2
+	#[cfg_attr(windows, allow(dead_code))]
+	pub fn nlink(&self, path: &str) -> u32 {
+		let key = self.resolve(path);
+		match self.entries.get(key) {
+			Some(file) if file.is_directory => 2,
+			Some(_) => 1 + self.links.values().filter(|linked| linked.as_str() == key).count() as u32,
+			None => 0,
+		}
+	}
+
+	// Gives the entry at `path` another name. Returns false when there is no such entry
+	#[cfg_attr(windows, allow(dead_code))]
+	pub fn link(&mut self, path: &str, link: &str) -> bool {
+		let key = self.resolve(path).to_string();
+		if !self.entries.contains_key(&key) {
+			return false;
+		}
+		let link = self.key(link);
+		self.remove(&link);
+		if let Some(folded) = &mut self.folded {
+			folded.insert(link.to_lowercase(), link.clone());
+		}
+		self.links.insert(link, key);
+		true
+	}
+
+	fn attach(&mut self, key: String, file: VirtualFile, ino: u64) {
+		if let Some(folded) = &mut self.folded {
+			folded.insert(key.to_lowercase(), key.clone());
+		}
+		self.inodes.insert(key.clone(), ino);
+		self.paths.insert(ino, key.clone());
+		self.entries.insert(key, file);
+	}
+
+	fn detach(&mut self, key: &str) -> Option<(VirtualFile, u64)> {
+		let file = self.entries.remove(key)?;
+		if let Some(folded) = &mut self.folded {
+			folded.remove(&key.to_lowercase());
+		}
+		let ino = self.inodes.remove(key)?;
+		self.paths.remove(&ino);
+		Some((file, ino))
+	}
+
+	fn relink(&mut self, from: &str, to: &str) {
+		for key in self.links.values_mut() {
+			if key == from {
+				*key = to.to_string();
+			}
+		}
+	}
+
+	#[cfg_attr(windows, allow(dead_code))]
+	pub fn path_of(&self, ino: u64) -> Option<&String> {
+		self.paths.get(&ino)
+	}
+
+	// Replacing an entry keeps its inode, inserting at a hard link replaces the linked entry
+	pub fn insert(&mut self, path: String, file: VirtualFile) -> Option<VirtualFile> {
+		let key = self.key(&path);
+		let key = self.links.get(&key).cloned().unwrap_or(key);
+		if let Some(existing) = self.entries.get_mut(&key) {
+			return Some(std::mem::replace(existing, file));
+		}
+		let ino = self.next_inode;
+		self.next_inode += 1;
+		self.attach(key, file, ino);
+		None
+	}
+
+	// Removing one name of a linked entry leaves it, and its inode, to another of its names
+	pub fn remove(&mut self, path: &str) -> Option<VirtualFile> {
+		let key = stored_key(&self.folded, path).to_string();
+		if let Some(linked) = self.links.remove(&key) {
+			if let Some(folded) = &mut self.folded {
+				folded.remove(&key.to_lowercase());
+			}
+			return self.entries.get(&linked).cloned();
+		}
+
+		let (file, ino) = self.detach(&key)?;
+		let heir = self.links.iter()
+			.filter(|(_, linked)| **linked == key)
+			.map(|(link, _)| link.clone())
+			.min();
+		if let Some(heir) = heir {
+			self.links.remove(&heir);
+			self.attach(heir.clone(), file.clone(), ino);
+			self.relink(&key, &heir);
+		}
+		Some(file)
+	}
+
+	// Moves an entry to another key along with its inode, replacing whatever was there.
+	// Returns the key it ended up under
+	pub fn rename(&mut self, from: &str, to: &str) -> Option<String> {
+		let from = stored_key(&self.folded, from).to_string();
+		let to_key = stored_key(&self.folded, to);
+		// Two names of the same entry, which rename leaves alone
+		if to_key != from && self.contains_key(to) && self.resolve(to) == self.resolve(&from) {
+			return Some(from);
+		}
+
+		if let Some(linked) = self.links.remove(&from) {
+			if let Some(folded) = &mut self.folded {
+				folded.remove(&from.to_lowercase());
+			}
+			let to = self.key(to);
+			self.remove(&to);
+			if let Some(folded) = &mut self.folded {
+				folded.insert(to.to_lowercase(), to.clone());
+			}
+			self.links.insert(to.clone(), linked);
+			return Some(to);
+		}
+
+		let (file, ino) = self.detach(&from)?;
+		// Resolved once the entry is gone so a case-only rename keeps the new casing
+		let to = self.key(to);
+		self.remove(&to);
+		self.attach(to.clone(), file, ino);
+		self.relink(&from, &to);
+		Some(to)
+	}
+
+	pub fn retain(&mut self, mut keep: impl FnMut(&String, &mut VirtualFile) -> bool) {
+		let mut dropped: Vec<String> = self.entries.iter_mut()
+			.filter_map(|(path, file)| (!keep(path, file)).then(|| path.clone()))
+			.collect();
+		for (link, key) in &self.links {
+			if let Some(mut file) = self.entries.get(key).cloned() {
+				if !keep(link, &mut file) {
+					dropped.push(link.clone());
+				}
+			}
+		}
+		for path in dropped {
+			self.remove(&path);
+		}
+	}
+
+	pub fn drain(&mut self) -> Drain<'_, String, VirtualFile> {
+		if let Some(folded) = &mut self.folded {
+			folded.clear();
+		}
+		self.links.clear();
+		self.inodes.clear();
+		self.paths.clear();
+		self.entries.drain()
+	}
+
+	// Swaps in a whole new set of entries, folding their keys like individual inserts would
+	pub fn replace(&mut self, entries: HashMap<String, VirtualFile>) {
+		self.drain();
+		for (path, file) in entries {
+			self.insert(path, file);
+		}
+	}
+}
+
+impl Deref for FileMap {
+	type Target = HashMap<String, VirtualFile>;
+
+	fn deref(&self) -> &Self::Target {
+		&self.entries
+	}
 }
 
 pub struct FSState {
-	pub files: HashMap<String, VirtualFile>,
-	event_sender: broadcast::Sender<FSEvent>,
+	pub files: FileMap,
+	pub resolver: Option<PathResolver>,
+	// Live quotas, checked under the state lock by every operation that adds data or entries
+	pub total_space_bytes: u64,
+	pub max_files: u64,
+	// Reads are too hot to report unless asked for, see FSEvent::Accessed
+	access_events: AtomicBool,
+	// Announce renames as a Deleted/Created pair, the way they were before FSEvent::Renamed
+	pub split_rename_events: bool,
+	// Count holes in sparse files against total_space_bytes, only the bytes written otherwise
+	pub quota_logical_size: bool,
+	// Paths the resolver recently reported as missing, behind its own lock so read-locked lookups can update it
+	negative_cache: Mutex<HashMap<String, Instant>>,
+	// Behind an Arc so requests waiting for a lock do not hold the state lock
+	pub locks: Arc<LockTable>,
+	event_sender: broadcast::Sender<EventRecord>,
+}
+
+// Quotas used when the constructor options leave them out
+pub const DEFAULT_TOTAL_SPACE_BYTES: u64 = 4 * 1024 * 1024 * 1024;
+pub const DEFAULT_MAX_FILES: u64 = 1024 * 1024;
+
+// Bytes every entry counts against total_space_bytes on top of its content, so empty files and
+// directories are not free
+pub const ENTRY_OVERHEAD_BYTES: u64 = 256;
+
+// Quota bytes taken by an entry holding `content_bytes` of content
+pub fn entry_bytes(content_bytes: u64) -> u64 {
+	ENTRY_OVERHEAD_BYTES + content_bytes
 }
 
+// Events a subscriber can fall behind on before it starts missing them
+pub const DEFAULT_EVENT_BUFFER_SIZE: usize = 100;
+
 impl Default for FSState {
 	fn default() -> Self {
-		let (event_sender, _) = broadcast::channel(100);
+		Self::new(DEFAULT_EVENT_BUFFER_SIZE, false)
+	}
+}
+
+impl FSState {
+	pub fn new(event_buffer_size: usize, case_insensitive: bool) -> Self {
+		let (event_sender, _) = broadcast::channel(event_buffer_size);
 		Self {
-			files: HashMap::new(),
+			files: FileMap::new(case_insensitive),
+			resolver: None,
+			total_space_bytes: DEFAULT_TOTAL_SPACE_BYTES,
+			max_files: DEFAULT_MAX_FILES,
+			access_events: AtomicBool::new(false),
+			split_rename_events: false,
+			quota_logical_size: false,
+			negative_cache: Mutex::new(HashMap::new()),
+			locks: Arc::new(LockTable::default()),
 			event_sender,
 		}
 	}
-}
 
-impl FSState {
+	// Emitted under the same lock as the operation, so the entry is looked up as the operation left it
 	pub fn emit_event(&self, event: FSEvent) {
-		let _ = self.event_sender.send(event);
+		let (size, ino) = match &event {
+			FSEvent::Created { path, .. }
+			| FSEvent::Modified { path, .. }
+			| FSEvent::Renamed { to: path, .. } => (self.files.get(path).map(|file| file.size), self.files.ino(path)),
+			FSEvent::Deleted { .. } => (Some(0), None),
+			_ => (None, None),
+		};
+		let _ = self.event_sender.send(EventRecord { event, time: SystemTime::now(), size, ino });
 	}
 
-	pub fn subscribe_to_events(&self) -> broadcast::Receiver<FSEvent> {
+	pub fn emit_error(&self, kind: ErrorKind, operation: &'static str, path: &str, message: impl Into<String>, code: Option<i32>) {
+		self.emit_event(FSEvent::Error { path: path.to_string(), message: message.into(), kind, operation, code });
+	}
+
+	pub fn emit_renamed(&self, from: String, to: String, object_type: ObjectType) {
+		if self.split_rename_events {
+			self.emit_event(FSEvent::Deleted { path: from, object_type: object_type.clone() });
+			self.emit_event(FSEvent::Created { path: to, object_type });
+		} else {
+			self.emit_event(FSEvent::Renamed { from, to, object_type });
+		}
+	}
+
+	pub fn access_events_enabled(&self) -> bool {
+		self.access_events.load(Ordering::Relaxed)
+	}
+
+	pub fn set_access_events(&self, enabled: bool) {
+		self.access_events.store(enabled, Ordering::Relaxed);
+	}
+
+	pub fn subscribe_to_events(&self) -> broadcast::Receiver<EventRecord> {
 		self.event_sender.subscribe()
 	}
+
+	fn is_known_missing(&self, path: &str) -> bool {
+		self.negative_cache.lock().unwrap()
+			.get(path)
+			.is_some_and(|since| since.elapsed() < NEGATIVE_CACHE_TTL)
+	}
+
+	fn remember_missing(&self, path: &str) {
+		let mut cache = self.negative_cache.lock().unwrap();
+		if cache.len() >= 4096 {
+			cache.retain(|_, since| since.elapsed() < NEGATIVE_CACHE_TTL);
+		}
+		cache.insert(path.to_string(), Instant::now());
+	}
+
+	pub fn forget_missing(&self) {
+		self.negative_cache.lock().unwrap().clear();
+	}
+
+	// Creates `path` and every missing ancestor as directories, returning the created paths.
+	// Nothing is created when one of them is not a directory or they do not fit within max_files
+	pub fn create_directories(&mut self, path: &str) -> Result<Vec<String>, InsertError> {
+		let path = self.files.key(path);
+		let mut missing = Vec::new();
+		let mut ancestor = String::new();
+		for component in path.split('/').filter(|component| !component.is_empty()) {
+			ancestor = if ancestor.is_empty() { component.to_string() } else { format!("{}/{}", ancestor, component) };
+			match self.files.get(&ancestor) {
+				Some(file) if file.is_directory => {}
+				Some(_) => return Err(InsertError::NotADirectory(ancestor)),
+				None => missing.push(ancestor.clone()),
+			}
+		}
+		if !self.has_entries_for(0, missing.len() as u64) {
+			return Err(InsertError::MaxFiles);
+		}
+		for ancestor in &missing {
+			self.files.insert(ancestor.clone(), VirtualFile {
+				is_directory: true,
+				..Default::default()
+			});
+		}
+		Ok(missing)
+	}
+
+	// Inserts an entry whose parent exists or was created with create_directories, replacing what was
+	// at `path`. Every entry added from outside the mount goes through here or create_directories,
+	// so max_files holds whatever the entry comes from
+	pub fn insert_entry(&mut self, path: &str, file: VirtualFile) -> Result<Option<VirtualFile>, InsertError> {
+		if self.exceeds_max_files(path) {
+			return Err(InsertError::MaxFiles);
+		}
+		Ok(self.files.insert(path.to_string(), file))
+	}
+
+	// Entries inserting `path` adds: the entry itself and each of its missing ancestors, none when it exists
+	pub fn new_entries(&self, path: &str) -> u64 {
+		if self.files.contains_key(path) {
+			return 0;
+		}
+		let mut count = 1;
+		let mut ancestor = path;
+		while let Some((parent, _)) = ancestor.rsplit_once('/') {
+			if self.files.contains_key(parent) {
+				break;
+			}
+			count += 1;
+			ancestor = parent;
+		}
+		count
+	}
+
+	// The entry count counterpart of has_space: whether dropping `removed` entries and adding
+	// `added` ones stays within max_files
+	pub fn has_entries_for(&self, removed: u64, added: u64) -> bool {
+		added <= removed || (self.files.len() as u64).saturating_sub(removed) + added <= self.max_files
+	}
+
+	// Whether inserting `path`, along with any ancestor it would need created, would take the entry
+	// count past max_files. Replacing an existing entry never does
+	pub fn exceeds_max_files(&self, path: &str) -> bool {
+		!self.has_entries_for(0, self.new_entries(path))
+	}
+
+	// Total bytes counted against total_space_bytes, an entry with several names counting once
+	pub fn used_bytes(&self) -> u64 {
+		self.files.values()
+			.map(|file| file.used_bytes(self.quota_logical_size))
+			.sum()
+	}
+
+	// Every quota check goes through here: whether dropping entries taking `freed` bytes and adding
+	// ones taking `added` bytes stays within total_space_bytes
+	pub fn has_space(&self, freed: u64, added: u64) -> bool {
+		// Shrinking always fits, even once the quota was lowered below the current usage
+		added <= freed || self.used_bytes().saturating_sub(freed) + added <= self.total_space_bytes
+	}
+
+	// Whether the entry at `path` can hold `content_bytes` of content as counted by
+	// VirtualFile::content_bytes, creating it and any missing ancestor directory when needed
+	pub fn has_space_for(&self, path: &str, content_bytes: u64) -> bool {
+		let freed = self.files.get(path).map_or(0, |file| file.used_bytes(self.quota_logical_size));
+		let ancestors = self.new_entries(path).saturating_sub(1);
+		self.has_space(freed, entry_bytes(content_bytes) + ancestors * ENTRY_OVERHEAD_BYTES)
+	}
+
+	// Bytes and entries taken by `prefix` and everything below it, the whole tree when empty
+	pub fn usage_under(&self, prefix: &str) -> (u64, u64) {
+		let prefix = self.files.key(prefix);
+		let prefix = prefix.as_str();
+		let descendant = format!("{}/", prefix);
+		// Counted like used_bytes, each entry once under its primary name
+		(*self.files).iter()
+			.filter(|(path, _)| prefix.is_empty() || path.as_str() == prefix || path.starts_with(&descendant))
+			.fold((0, 0), |(bytes, count), (_, file)| (bytes + file.used_bytes(self.quota_logical_size), count + 1))
+	}
+
+	// Moves an entry to a new key, rewriting every descendant key when it is a directory.
+	// Returns the type of the moved entry, or None if `old_path` does not exist
+	pub fn move_entry(&mut self, old_path: &str, new_path: &str) -> Option<ObjectType> {
+		let old_path = self.files.key(old_path);
+		let file = self.files.get(&old_path)?;
+		let object_type = file.get_type();
+
+		let prefix = format!("{}/", old_path);
+		let paths_to_rename: Vec<String> = if file.is_directory {
+			self.files.keys()
+				.filter(|path| path.starts_with(&prefix))
+				.cloned()
+				.collect()
+		} else {
+			Vec::new()
+		};
+
+		// Every moved entry keeps its inode, so handles and cached lookups stay valid
+		let new_path = self.files.rename(&old_path, new_path)?;
+		for old_child_path in paths_to_rename {
+			let new_child_path = format!("{}/{}", new_path, &old_child_path[prefix.len()..]);
+			self.files.rename(&old_child_path, &new_child_path);
+		}
+		Some(object_type)
+	}
+
+	// Swaps two entries along with their descendants, each keeping its inode. Returns the types of the
+	// entries first at `a` and `b`, or None if either does not exist
+	#[cfg_attr(windows, allow(dead_code))]
+	pub fn exchange_entries(&mut self, a: &str, b: &str) -> Option<(ObjectType, ObjectType)> {
+		let (a, b) = (self.files.key(a), self.files.key(b));
+		if !self.files.contains_key(&a) || !self.files.contains_key(&b) {
+			return None;
+		}
+		// No path contains a NUL, so the parking key cannot clash with an entry
+		let parked = format!("{}\0", a);
+		let a_type = self.move_entry(&a, &parked)?;
+		let b_type = self.move_entry(&b, &a)?;
+		self.move_entry(&parked, &b)?;
+		Some((a_type, b_type))
+	}
+
+	// Removes an entry along with all of its descendants, returning the entry itself
+	pub fn remove_tree(&mut self, path: &str) -> Option<VirtualFile> {
+		let path = self.files.key(path);
+		let file = self.files.remove(&path)?;
+		if file.is_directory {
+			let prefix = format!("{}/", path);
+			self.files.retain(|child_path, _| !child_path.starts_with(&prefix));
+		}
+		Some(file)
+	}
+}
+
+// Why entries could not be added to the state
+#[derive(Debug)]
+pub enum InsertError {
+	// The ancestor at this path exists and is not a directory
+	NotADirectory(String),
+	// The entries would take the entry count past max_files
+	MaxFiles,
 }
 
 pub type SharedFSState = Arc<RwLock<FSState>>;
 
-pub fn create_fs_state() -> SharedFSState {
-	Arc::new(RwLock::new(FSState::default()))
-} 
\ No newline at end of file
+pub fn create_fs_state(state: FSState) -> SharedFSState {
+	Arc::new(RwLock::new(state))
+}
+
+// Fetches the content of a lazy file through its provider and caches it in the state.
+// No lock may be held by the caller: the provider calls into JS, which can itself use the state
+pub async fn materialize(state: &SharedFSState, path: &str) -> Result<(), String> {
+	let provider = match state.read().await.files.get(path).and_then(|file| file.provider.clone()) {
+		Some(provider) => provider,
+		None => return Ok(()),
+	};
+
+	match provider(path.to_string()).await {
+		Ok(content) => {
+			let mut state = state.write().await;
+			// Another reader may have materialized it, or the host replaced it, in the meantime
+			if let Some(file) = state.files.get_mut(path).filter(|file| file.provider.is_some()) {
+				file.size = content.len() as u64;
+				file.content = Arc::new(content.into());
+				file.provider = None;
+			}
+			Ok(())
+		}
+		Err(message) => {
+			state.read().await.emit_error(ErrorKind::Provider, "read", path, message.clone(), None);
+			Err(message)
+		}
+	}
+}
+
+// Converts a JS-supplied path to the key format used in FSState.files:
+// forward slashes only, no leading, trailing or repeated separator
+pub fn normalize_path(path: &str) -> String {
+	path.replace('\\', "/")
+		.split('/')
+		.filter(|component| !component.is_empty())
+		.collect::<Vec<_>>()
+		.join("/")
+}
+
+// State key of a path given relative to a mount showing only `root` ("" being the whole tree)
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn join_root(root: &str, path: &str) -> String {
+	match (root.is_empty(), path.is_empty()) {
+		(true, _) => path.to_string(),
+		(false, true) => root.to_string(),
+		(false, false) => format!("{}/{}", root, path),
+	}
+}
+
+// Inverse of `join_root`, None for keys the mount does not show
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn strip_root<'a>(root: &str, path: &'a str) -> Option<&'a str> {
+	if root.is_empty() {
+		return Some(path);
+	}
+	match path.strip_prefix(root) {
+		Some("") => Some(""),
+		Some(rest) => rest.strip_prefix('/'),
+		None => None,
+	}
+}
+
+// Order of the names in a FUSE directory listing. Case-insensitive when `fold_case`, names differing
+// only by case then sorting byte-wise, and byte-wise otherwise. ProjFS sorts with PrjFileNameCompare
+#[cfg_attr(windows, allow(dead_code))]
+pub fn compare_names(a: &str, b: &str, fold_case: bool) -> std::cmp::Ordering {
+	if fold_case {
+		a.to_lowercase().cmp(&b.to_lowercase()).then_with(|| a.cmp(b))
+	} else {
+		a.cmp(b)
+	}
+}
+
+// Whether `path` sits directly inside `dir_path` ("" being the root)
+pub fn is_direct_child(dir_path: &str, path: &str) -> bool {
+	if dir_path.is_empty() {
+		!path.is_empty() && !path.contains('/')
+	} else {
+		path.strip_prefix(dir_path)
+			.and_then(|rest| rest.strip_prefix('/'))
+			.is_some_and(|name| !name.is_empty() && !name.contains('/'))
+	}
+}
+
+// Asks the registered resolver about a path that is missing from the state and inserts
+// whatever it answers. Returns whether the path exists afterwards. Like `materialize`,
+// this calls into JS and must be awaited without holding a lock on the state
+pub async fn resolve_missing(state: &SharedFSState, path: &str) -> bool {
+	let resolver = {
+		let state = state.read().await;
+		if state.files.contains_key(path) {
+			return true;
+		}
+		match &state.resolver {
+			Some(resolver) if !state.is_known_missing(path) => resolver.clone(),
+			_ => return false,
+		}
+	};
+
+	match resolver(path.to_string()).await {
+		Ok(Some(file)) => {
+			let mut state = state.write().await;
+			if !state.files.contains_key(path) {
+				let object_type = file.get_type();
+				if state.insert_entry(path, file).is_err() {
+					state.emit_error(ErrorKind::Quota, "lookup", path, "ENOSPC: file count quota reached", None);
+					return false;
+				}
+				state.emit_event(FSEvent::Created { path: path.to_string(), object_type });
+			}
+			true
+		}
+		Ok(None) => {
+			state.read().await.remember_missing(path);
+			false
+		}
+		Err(message) => {
+			let state = state.read().await;
+			state.remember_missing(path);
+			state.emit_error(ErrorKind::Resolver, "lookup", path, message, None);
+			false
+		}
+	}
+}
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn state_with_max_files(max_files: u64) -> FSState {
+		FSState { max_files, ..Default::default() }
+	}
+
+	#[test]
+	fn max_files_counts_missing_ancestors() {
+		let mut state = state_with_max_files(3);
+		state.create_directories("a").unwrap();
+
+		assert!(!state.exceeds_max_files("a/b/c"));
+		assert!(state.exceeds_max_files("a/b/c/d"));
+		// Replacing never adds an entry, even over the quota
+		state.max_files = 1;
+		assert!(!state.exceeds_max_files("a"));
+	}
+
+	#[test]
+	fn space_counts_missing_ancestors() {
+		let mut state = FSState { total_space_bytes: 3 * ENTRY_OVERHEAD_BYTES, ..Default::default() };
+		state.create_directories("a").unwrap();
+
+		assert!(state.has_space_for("a/b/c", 0));
+		assert!(!state.has_space_for("a/b/c/d", 0));
+		assert!(!state.has_space_for("a/b/c", 1));
+	}
+
+	#[test]
+	fn create_directories_fits_all_or_nothing() {
+		let mut state = state_with_max_files(2);
+
+		assert!(matches!(state.create_directories("a/b/c"), Err(InsertError::MaxFiles)));
+		assert!(state.files.is_empty());
+		assert_eq!(state.create_directories("a/b").unwrap(), vec!["a".to_string(), "a/b".to_string()]);
+	}
+
+	#[test]
+	fn insert_entry_enforces_max_files() {
+		let mut state = state_with_max_files(1);
+
+		assert!(state.insert_entry("a", VirtualFile::default()).is_ok());
+		assert!(matches!(state.insert_entry("b", VirtualFile::default()), Err(InsertError::MaxFiles)));
+		assert!(state.insert_entry("a", VirtualFile::default()).unwrap().is_some());
+	}
+
+	#[test]
+	fn reads_share_the_content_until_it_is_written() {
+		let mut file = VirtualFile::default();
+		file.write_at(0, b"before");
+		let unshared = Arc::as_ptr(&file.content);
+		file.write_at(0, b"second");
+		// Nothing else held it, so it was written in place
+		assert_eq!(Arc::as_ptr(&file.content), unshared);
+
+		let read = file.content.clone();
+		assert!(Arc::ptr_eq(&read, &file.content));
+		file.write_at(0, b"after!");
+		// The reader keeps what it took while the file moves on to a copy
+		assert!(!Arc::ptr_eq(&read, &file.content));
+		assert_eq!(read.to_vec(), b"second");
+		assert_eq!(file.content.to_vec(), b"after!");
+
+		let read = file.content.clone();
+		file.resize(2);
+		assert_eq!(read.size(), 6);
+		assert_eq!(file.content.to_vec(), b"af");
+	}
+
+	#[test]
+	fn cloned_entries_share_their_content() {
+		let mut file = VirtualFile::default();
+		file.write_at(0, b"data");
+		let mut copy = file.clone();
+		assert!(Arc::ptr_eq(&file.content, &copy.content));
+
+		copy.write_at(4, b"more");
+		assert_eq!(file.content.to_vec(), b"data");
+		assert_eq!(copy.content.to_vec(), b"datamore");
+	}
+
+	// Every entry has an inode, and the inode leads back to it
+	fn assert_inodes_consistent(files: &FileMap) {
+		assert_eq!(files.inodes.len(), files.entries.len());
+		assert_eq!(files.paths.len(), files.entries.len());
+		for (path, ino) in &files.inodes {
+			assert!(files.entries.contains_key(path));
+			assert_eq!(files.paths.get(ino), Some(path));
+		}
+	}
+
+	#[test]
+	fn rename_keeps_the_inode_and_moves_its_path() {
+		let mut files = FileMap::new(false);
+		files.insert("a".to_string(), VirtualFile::default());
+		files.insert("b".to_string(), VirtualFile::default());
+		let (a, b) = (files.ino("a").unwrap(), files.ino("b").unwrap());
+
+		assert_eq!(files.rename("a", "c").as_deref(), Some("c"));
+		assert_eq!(files.ino("c"), Some(a));
+		assert_eq!(files.path_of(a).map(String::as_str), Some("c"));
+		// Replacing drops the inode of the entry that was there
+		files.rename("c", "b");
+		assert_eq!(files.ino("b"), Some(a));
+		assert_eq!(files.path_of(b), None);
+		assert_inodes_consistent(&files);
+	}
+
+	#[test]
+	fn remove_frees_the_inode() {
+		let mut files = FileMap::new(false);
+		files.insert("a".to_string(), VirtualFile::default());
+		let ino = files.ino("a").unwrap();
+
+		files.remove("a");
+		assert_eq!(files.path_of(ino), None);
+		// Inodes are never reused
+		files.insert("a".to_string(), VirtualFile::default());
+		assert_ne!(files.ino("a"), Some(ino));
+		assert_inodes_consistent(&files);
+	}
+
+	#[test]
+	fn removing_a_linked_name_hands_the_inode_over() {
+		let mut files = FileMap::new(false);
+		files.insert("a".to_string(), VirtualFile::default());
+		files.link("a", "b");
+		let ino = files.ino("a").unwrap();
+		assert_eq!(files.ino("b"), Some(ino));
+
+		files.remove("a");
+		assert_eq!(files.path_of(ino).map(String::as_str), Some("b"));
+		assert_inodes_consistent(&files);
+	}
+
+	#[test]
+	fn case_only_rename_follows_the_new_casing() {
+		let mut files = FileMap::new(true);
+		files.insert("File".to_string(), VirtualFile::default());
+		let ino = files.ino("file").unwrap();
+
+		files.rename("file", "FILE");
+		assert_eq!(files.path_of(ino).map(String::as_str), Some("FILE"));
+		assert_eq!(files.ino("File"), Some(ino));
+		assert_inodes_consistent(&files);
+	}
+
+	#[test]
+	fn moving_a_directory_moves_the_inodes_of_its_children() {
+		let mut state = FSState::default();
+		state.create_directories("dir/sub").unwrap();
+		state.insert_entry("dir/sub/file", VirtualFile::default()).unwrap();
+		let ino = state.files.ino("dir/sub/file").unwrap();
+
+		state.move_entry("dir", "moved");
+		assert_eq!(state.files.path_of(ino).map(String::as_str), Some("moved/sub/file"));
+		assert!(!state.files.contains_key("dir/sub"));
+		assert_inodes_consistent(&state.files);
+	}
+}
@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::sync::Arc;
+
+// File content kept as the runs of bytes actually written, by offset. Whatever lies between them
+// and up to `len` is a hole that reads as zeros, so truncating up or writing far past the end of
+// file does not allocate the gap
+#[derive(Clone, Default, Debug)]
+pub struct Content {
+	// Neither overlapping nor adjacent, a write touching a run extends it
+	extents: BTreeMap<u64, Vec<u8>>,
+	len: u64,
+}
+
+impl From<Vec<u8>> for Content {
+	fn from(data: Vec<u8>) -> Self {
+		let len = data.len() as u64;
+		let mut extents = BTreeMap::new();
+		if !data.is_empty() {
+			extents.insert(0, data);
+		}
+		Self { extents, len }
+	}
+}
+
+impl Content {
+	// Logical size, holes included
+	pub fn size(&self) -> u64 {
+		self.len
+	}
+
+	// Bytes actually held, holes left out
+	pub fn stored_bytes(&self) -> u64 {
+		self.extents.values().map(|chunk| chunk.len() as u64).sum()
+	}
+
+	// Bytes held within `offset..offset + len`
+	pub fn stored_in(&self, offset: u64, len: u64) -> u64 {
+		let end = offset.saturating_add(len);
+		self.overlapping(offset, end)
+			.map(|(start, chunk)| (start + chunk.len() as u64).min(end) - start.max(offset))
+			.sum()
+	}
+
+	// Up to `size` bytes from `offset`, short at the end of file and with zeros for holes
+	pub fn read(&self, offset: u64, size: usize) -> Vec<u8> {
+		let start = offset.min(self.len);
+		let end = start.saturating_add(size as u64).min(self.len);
+		let mut data = vec![0; (end - start) as usize];
+		for (chunk_start, chunk) in self.overlapping(start, end) {
+			let from = chunk_start.max(start);
+			let to = (chunk_start + chunk.len() as u64).min(end);
+			data[(from - start) as usize..(to - start) as usize]
+				.copy_from_slice(&chunk[(from - chunk_start) as usize..(to - chunk_start) as usize]);
+		}
+		data
+	}
+
+	// Allocates the logical size, holes included, only meant for contents known to be small
+	pub fn to_vec(&self) -> Vec<u8> {
+		self.read(0, self.len as usize)
+	}
+
+	// Runs of bytes actually held, in order, with their offset
+	pub fn extents(&self) -> impl Iterator<Item = (u64, &[u8])> {
+		self.extents.iter().map(|(&start, chunk)| (start, chunk.as_slice()))
+	}
+
+	// Where the first byte held at or after `offset` is, None when only a hole follows
+	#[cfg_attr(windows, allow(dead_code))]
+	pub fn next_data(&self, offset: u64) -> Option<u64> {
+		self.overlapping(offset, u64::MAX).next().map(|(start, _)| start.max(offset))
+	}
+
+	// Where the first hole at or after `offset` starts, the end of file counting as one
+	#[cfg_attr(windows, allow(dead_code))]
+	pub fn next_hole(&self, offset: u64) -> u64 {
+		// Runs are never adjacent, so whatever follows the run holding `offset` is a hole
+		match self.overlapping(offset, offset.saturating_add(1)).next() {
+			Some((start, chunk)) => (start + chunk.len() as u64).min(self.len),
+			None => offset.min(self.len),
+		}
+	}
+
+	// Writes `data` at `offset`, leaving a hole for any gap past the current end of file
+	pub fn write(&mut self, offset: u64, data: &[u8]) {
+		let end = offset + data.len() as u64;
+		self.len = self.len.max(end);
+		if data.is_empty() {
+			return;
+		}
+
+		// A run reaching `offset` is extended rather than left next to a new one
+		let (start, mut chunk) = match self.extents.range(..=offset).next_back() {
+			Some((&start, chunk)) if start + chunk.len() as u64 >= offset => (start, self.extents.remove(&start).unwrap_or_default()),
+			_ => (offset, Vec::new()),
+		};
+		if chunk.len() < (end - start) as usize {
+			chunk.resize((end - start) as usize, 0);
+		}
+		chunk[(offset - start) as usize..(end - start) as usize].copy_from_slice(data);
+
+		// Runs starting within the written range or right after it are folded in, only the last one can
+		// go on past its end
+		let following: Vec<u64> = self.extents.range(offset..=end).map(|(&start, _)| start).collect();
+		for following_start in following {
+			if let Some(following) = self.extents.remove(&following_start) {
+				let following_end = following_start + following.len() as u64;
+				if following_end > end {
+					chunk.extend_from_slice(&following[(end - following_start) as usize..]);
+				}
+			}
+		}
+		self.extents.insert(start, chunk);
+	}
+
+	// Truncates or extends the content, extending only adds a hole
+	pub fn set_len(&mut self, len: u64) {
+		if len < self.len {
+			self.extents.retain(|&start, _| start < len);
+			if let Some((&start, chunk)) = self.extents.range_mut(..len).next_back() {
+				chunk.truncate((len - start) as usize);
+			}
+		}
+		self.len = len;
+	}
+
+	// Reads the content from the start without copying it whole, holes coming out as zeros
+	pub fn reader(self: Arc<Self>) -> ContentReader {
+		ContentReader { content: self, position: 0 }
+	}
+
+	// Runs overlapping `start..end`, in order
+	fn overlapping(&self, start: u64, end: u64) -> impl Iterator<Item = (u64, &Vec<u8>)> {
+		let before = self.extents.range(..start).next_back()
+			.filter(|(&chunk_start, chunk)| chunk_start + chunk.len() as u64 > start);
+		before.into_iter()
+			.chain(self.extents.range(start..end))
+			.map(|(&chunk_start, chunk)| (chunk_start, chunk))
+	}
+}
+
+pub struct ContentReader {
+	content: Arc<Content>,
+	position: u64,
+}
+
+impl Read for ContentReader {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		let data = self.content.read(self.position, buf.len());
+		buf[..data.len()].copy_from_slice(&data);
+		self.position += data.len() as u64;
+		Ok(data.len())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// 4 bytes at 0, a hole, 4 bytes at 16, and a trailing hole up to 32
+	fn sparse() -> Content {
+		let mut content = Content::default();
+		content.write(0, b"head");
+		content.write(16, b"tail");
+		content.set_len(32);
+		content
+	}
+
+	#[test]
+	fn next_data_skips_holes() {
+		let content = sparse();
+		assert_eq!(content.next_data(0), Some(0));
+		assert_eq!(content.next_data(2), Some(2));
+		assert_eq!(content.next_data(4), Some(16));
+		assert_eq!(content.next_data(18), Some(18));
+		assert_eq!(content.next_data(20), None);
+	}
+
+	#[test]
+	fn next_hole_stops_at_the_end_of_a_run() {
+		let content = sparse();
+		assert_eq!(content.next_hole(0), 4);
+		assert_eq!(content.next_hole(8), 8);
+		assert_eq!(content.next_hole(17), 20);
+		assert_eq!(content.next_hole(24), 24);
+		assert_eq!(content.next_hole(40), 32);
+	}
+
+	#[test]
+	fn reads_past_the_end_of_file_are_clamped() {
+		let content = Content::from(b"data".to_vec());
+		assert_eq!(content.read(2, 10), b"ta");
+		assert!(content.read(4, 10).is_empty());
+		assert!(content.read(100, 10).is_empty());
+		assert!(content.read(u64::MAX, usize::MAX).is_empty());
+	}
+
+	#[test]
+	fn reader_fills_holes_with_zeros() {
+		let mut data = Vec::new();
+		Arc::new(sparse()).reader().read_to_end(&mut data).unwrap();
+		let mut expected = vec![0; 32];
+		expected[..4].copy_from_slice(b"head");
+		expected[16..20].copy_from_slice(b"tail");
+		assert_eq!(data, expected);
+	}
+}
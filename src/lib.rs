@@ -2,18 +2,27 @@
 
 use napi::bindgen_prelude::*;
 use napi_derive::napi;
-use napi::threadsafe_function::ThreadsafeFunction;
+use napi::threadsafe_function::{ErrorStrategy, ThreadsafeFunction};
+use napi::{JsBuffer, JsDeferred, JsObject, JsString, JsUnknown};
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::path::PathBuf;
+use std::rc::Rc;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use tokio::sync::Mutex;
 
+mod archive;
 mod common;
+mod content;
+mod snapshot;
 #[cfg(unix)]
 mod unix;
 #[cfg(windows)]
 mod windows;
 
-use common::{SharedFSState, create_fs_state, FSEvent};
+use common::{SharedFSState, create_fs_state, FSEvent, CachePolicy};
+use content::Content;
 #[cfg(unix)]
 use unix::FSImpl;
 #[cfg(windows)]
@@ -24,159 +33,2850 @@ pub struct FileSystemEvent {
 	pub event_type: String,
 	pub path: String,
 	pub object_type: String,
+	/// Failure description, only set on "error" events
+	pub message: Option<String>,
+	/// "mount", "provider", "resolver", "lagged", "platform" or "quota", only set on "error" events
+	pub error_kind: Option<String>,
+	/// What was being done when the error happened, e.g. "mount" or "read", only set on "error" events
+	pub operation: Option<String>,
+	/// errno or HRESULT of a failure reported by the platform, only set on "error" events
+	pub code: Option<i32>,
+	/// Bytes read through the handle, only set on "accessed" events
+	pub bytes: Option<i64>,
+	/// Milliseconds since the Unix epoch
+	pub time: f64,
+	/// Size after the operation, set on "created", "modified" and "deleted" events for entries the state holds
+	pub size: Option<i64>,
+	/// Inode number the entry has on the FUSE mount, kept across renames. Not set on "deleted" events since the inode is freed with the entry
+	pub ino: Option<BigInt>,
+	/// Where the entry was before, only set on "renamed" events whose `path` is the new location
+	pub old_path: Option<String>,
 }
 
+// Past this many descendants remove_path() sends a single Deleted event for the subtree
+// rather than flooding the broadcast channel
+const MAX_DELETED_EVENTS: usize = 1000;
+
+// How long unmountSync() blocks the JS thread at most, so a hung kernel cannot freeze shutdown
+const UNMOUNT_SYNC_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+const UNMOUNT_SYNC_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(10);
+
+// Most readFile() returns at once, the limit Node's own fs.readFile has. Sparse files can be far larger
+// than what they hold, reading one whole would allocate all of its holes
+const MAX_READ_BYTES: u64 = 2 * 1024 * 1024 * 1024;
+
+// Builds an errno-style error ("ENOENT: no such file or directory, 'path'") so
+// JS callers can branch on the code the same way they would with node:fs
+fn fs_error(code: &str, message: &str, path: &str) -> Error {
+	Error::from_reason(format!("{}: {}, '{}'", code, message, path))
+}
+
+// Normalizes a path given to a mutating method, rejecting what cannot name an entry:
+// the root itself, "." and ".." components and NUL characters
+fn entry_key(path: &str) -> Result<String> {
+	let key = prefix_key(path)?;
+	if key.is_empty() {
+		return Err(fs_error("EINVAL", "path must name an entry below the root", path));
+	}
+	Ok(key)
+}
+
+// Same validation for destination prefixes, where the root is allowed
+fn prefix_key(path: &str) -> Result<String> {
+	if path.contains('\0') {
+		return Err(fs_error("EINVAL", "path must not contain NUL characters", path));
+	}
+	let key = common::normalize_path(path);
+	if key.split('/').any(|component| component == "." || component == "..") {
+		return Err(fs_error("EINVAL", "'.' and '..' components are not allowed", path));
+	}
+	Ok(key)
+}
+
+// Makes sure the parent of `key` is a directory so the entry is reachable from the mount.
+// Missing ancestors are created and announced unless `create` is false, which fails with ENOENT
+fn ensure_parent(state: &mut common::FSState, key: &str, create: bool) -> Result<()> {
+	let Some((parent, _)) = key.rsplit_once('/') else {
+		return Ok(());
+	};
+	if !create {
+		return match state.files.get(parent) {
+			Some(file) if file.is_directory => Ok(()),
+			Some(_) => Err(fs_error("ENOTDIR", "not a directory", parent)),
+			None => Err(fs_error("ENOENT", "no such file or directory", parent)),
+		};
+	}
+
+	for created in state.create_directories(parent).map_err(|e| insert_error(e, key))? {
+		state.emit_event(FSEvent::Created { path: created, object_type: common::ObjectType::Directory });
+	}
+	Ok(())
+}
+
+// Error for an entry that could not be added at `path`
+fn insert_error(error: common::InsertError, path: &str) -> Error {
+	match error {
+		common::InsertError::NotADirectory(ancestor) => fs_error("ENOTDIR", "not a directory", &ancestor),
+		common::InsertError::MaxFiles => fs_error("ENOSPC", "file count quota reached", path),
+	}
+}
+
+#[napi(object)]
+pub struct FuseFSOptions {
+	/// Defaults to 4GB
+	pub total_space_bytes: Option<i64>,
+	/// Files and directories, defaults to 1M
+	pub max_files: Option<i64>,
+	/// Paths differing only in case refer to the same entry, which keeps the casing it was added with
+	pub case_insensitive: Option<bool>,
+	/// Events a listener can fall behind on before missing some, defaults to 100
+	pub event_buffer_size: Option<i64>,
+	/// Announce renames as a "deleted" then a "created" event instead of a single "renamed" one
+	pub split_rename_events: Option<bool>,
+	/// Count sparse files at their full size against totalSpaceBytes. By default only the bytes written to them count,
+	/// holes left by truncating up or writing past the end of file take no space
+	pub quota_logical_size: Option<bool>,
+}
+
+#[napi(object)]
+pub struct FileStat {
+	pub size: i64,
+	pub is_directory: bool,
+	/// Milliseconds since the Unix epoch
+	pub mtime: f64,
+	/// Milliseconds since the Unix epoch
+	pub atime: f64,
+	/// Permission bits, e.g. 0o644
+	pub mode: u32,
+	/// Owner ids, unset when the entry belongs to the user the mount runs as
+	pub uid: Option<u32>,
+	pub gid: Option<u32>,
+	pub readonly: bool,
+	pub protected: bool,
+	pub ino: BigInt,
+}
+
+#[napi(object)]
+pub struct LockInfo {
+	/// Process that took the lock
+	pub pid: u32,
+	pub start: i64,
+	/// Last locked byte, unset when the lock runs to the end of the file
+	pub end: Option<i64>,
+	/// Write lock, otherwise a shared read lock
+	pub exclusive: bool,
+}
+
+#[napi(object)]
+pub struct DirectoryEntry {
+	pub name: String,
+	pub path: String,
+	pub is_directory: bool,
+	pub size: i64,
+	/// Milliseconds since the Unix epoch
+	pub mtime: f64,
+}
+
+#[napi(object)]
+pub struct FindOptions {
+	/// Stop after this many matches, in path order
+	pub limit: Option<u32>,
+	/// Only match entries of this type: "file", "directory" or "symlink"
+	pub object_type: Option<String>,
+	/// Return directory entries with metadata instead of plain paths
+	pub with_metadata: Option<bool>,
+}
+
+#[napi(object)]
+pub struct CopyOptions {
+	/// Copy directories along with all of their descendants
+	pub recursive: Option<bool>,
+	/// Create missing parent directories of `dst` instead of failing with ENOENT, defaults to true
+	pub create_parents: Option<bool>,
+}
+
+#[napi(object)]
+#[derive(Clone, Default)]
+pub struct MountOptions {
+	/// Overrides the quota given to the constructor
+	pub total_space_bytes: Option<i64>,
+	/// Let other users see the mount, needs user_allow_other in /etc/fuse.conf when not root. FUSE only
+	pub allow_other: Option<bool>,
+	/// Let root see the mount besides the user who mounted it. FUSE only
+	pub allow_root: Option<bool>,
+	/// Have fusermount unmount the path if the process dies without unmounting, defaults to true. FUSE only
+	pub auto_unmount: Option<bool>,
+	/// Have the kernel check entry modes against the caller, defaults to true. FUSE only
+	pub default_permissions: Option<bool>,
+	/// Source shown in the mount table, "virtual" by default. FUSE only
+	pub fs_name: Option<String>,
+	/// Shown as the filesystem type after "fuse.". FUSE only
+	pub subtype: Option<String>,
+	/// Directory of the virtual tree shown at the mount point, entries outside of it are not visible.
+	/// Events and quotas still concern the whole tree
+	pub root: Option<String>,
+	/// Create the mount point and its missing parents instead of rejecting with ENOENT
+	pub create_mount_point: Option<bool>,
+	/// When reads through the mount update access times: "relatime" (default) when the access time is not newer
+	/// than the modification time or is a day old, "noatime" never, "strictatime" on every read. FUSE only
+	pub atime: Option<String>,
+	/// How the kernel caches file content read through the mount: "auto" (default) drops cached pages when a file
+	/// is opened again, "keep" retains them across opens, "direct" bypasses the cache so replaced content is seen
+	/// by handles already open. FUSE only
+	pub cache: Option<String>,
+	/// Milliseconds the kernel caches name lookups, 1000 by default. 0 disables the cache so entries added or
+	/// removed by the host are seen at once, at the cost of a lookup for every path resolution. FUSE only
+	pub entry_ttl_ms: Option<u32>,
+	/// Milliseconds the kernel caches attributes such as the size and times, 1000 by default. 0 makes every stat
+	/// reach the filesystem, so content replaced by the host is reflected immediately. FUSE only
+	pub attr_ttl_ms: Option<u32>,
+	/// Extra options handed to the FUSE mount as is, e.g. "max_read=131072". Options the fields above control
+	/// are rejected, as are entries containing commas. FUSE only
+	pub raw_options: Option<Vec<String>>,
+	/// Volume name Finder shows for the mount, defaults to `fsName`. macOS only
+	pub volume_name: Option<String>,
+	/// Let Finder store "._" AppleDouble files and .DS_Store in the tree, off by default. macOS only
+	pub apple_double: Option<bool>,
+	/// Allow com.apple.* extended attributes such as Finder info and quarantine flags, on by default. macOS only
+	pub apple_xattr: Option<bool>,
+	/// Have Finder treat the mount as a local disk rather than a network volume, on by default. macOS only
+	pub local: Option<bool>,
+	/// Block size statfs reports the quota in, a power of two from 512 to 1MB, 4096 by default. FUSE only
+	pub block_size: Option<u32>,
+	/// Longest file name statfs reports, up to 1024, 255 by default. FUSE only
+	pub name_max: Option<u32>,
+	/// Changes made through the mount reported as events: any of "created", "modified", "deleted" and "renamed",
+	/// all of them by default. Deletions and renames left out are not applied to the tree either. ProjFS only, FUSE
+	/// sees every change as it happens
+	pub notifications: Option<Vec<String>>,
+}
+
+#[napi(object)]
+pub struct UnmountOptions {
+	/// Unmount even with files open through the mount, further I/O on them fails with EBADF
+	pub force: Option<bool>,
+}
+
+#[napi(object)]
+pub struct RemoveOptions {
+	/// Remove directories along with all of their descendants instead of failing with ENOTEMPTY, defaults to true
+	pub recursive: Option<bool>,
+}
+
+#[napi(object)]
+pub struct FileEntry {
+	pub path: String,
+	pub content: Option<Buffer>,
+	pub is_directory: Option<bool>,
+}
+
+#[napi(object)]
+pub struct AddFilesResult {
+	pub inserted: u32,
+	/// Entries left untouched because the path already existed or one of its ancestors is a file
+	pub skipped: u32,
+}
+
+#[napi(object)]
+pub struct AddOptions {
+	/// Create missing parent directories instead of failing with ENOENT, defaults to true
+	pub create_parents: Option<bool>,
+}
+
+#[napi(object)]
+pub struct TreeOptions {
+	/// Replace entries that already exist instead of failing with EEXIST
+	pub overwrite: Option<bool>,
+}
+
+#[napi(object)]
+pub struct ImportProgress {
+	pub files_copied: u32,
+	pub bytes_copied: i64,
+}
+
+#[napi(object)]
+pub struct SkippedEntry {
+	/// Path on disk, or name inside the archive
+	pub path: String,
+	pub reason: String,
+}
+
+#[napi(object)]
+pub struct ImportResult {
+	pub files: u32,
+	pub directories: u32,
+	pub bytes: i64,
+	/// Symlinks, unreadable entries and files that would exceed the remaining quota
+	pub skipped: Vec<SkippedEntry>,
+}
+
+#[napi(object)]
+pub struct ZipImportResult {
+	pub imported: Vec<String>,
+	/// Unsafe names, unreadable entries and entries that would exceed a quota
+	pub skipped: Vec<SkippedEntry>,
+}
+
+#[napi(object)]
+pub struct ExportOptions {
+	/// Replace files that already exist on disk instead of skipping them
+	pub overwrite: Option<bool>,
+}
+
+#[napi(object)]
+pub struct ExportResult {
+	pub files: u32,
+	pub directories: u32,
+	pub bytes: i64,
+	/// Existing files when not overwriting, and entries that could not be written
+	pub skipped: Vec<SkippedEntry>,
+}
+
+#[napi(object)]
+pub struct StreamOptions {
+	/// Store whatever was written so far when the stream fails, replacing any existing file, instead of
+	/// leaving the path as it was
+	pub keep_partial: Option<bool>,
+}
+
+type StreamDeferred = JsDeferred<(), fn(Env) -> Result<()>>;
+
+// Collects chunks from a JS stream aside and stores them as a single VirtualFile once it ends, so a file
+// already at the path is served whole until then and left as it was when the stream fails. Every
+// callback runs on the JS thread, outside of any tokio runtime, so the blocking lock variants are safe to use
+struct StreamIngest {
+	state: SharedFSState,
+	inner: Arc<Mutex<FSImpl>>,
+	path: String,
+	keep_partial: bool,
+	staged: RefCell<Content>,
+	deferred: RefCell<Option<StreamDeferred>>,
+}
+
+impl StreamIngest {
+	fn is_settled(&self) -> bool {
+		self.deferred.borrow().is_none()
+	}
+
+	fn append(&self, chunk: &[u8]) -> Result<()> {
+		let mut staged = self.staged.borrow_mut();
+		// Counted against the quota as it grows, as if it already replaced the file at the path
+		let state = self.state.blocking_read();
+		if !state.has_space_for(&self.path, staged.size() + chunk.len() as u64) {
+			return Err(fs_error("ENOSPC", "no space left on device", &self.path));
+		}
+		drop(state);
+
+		let offset = staged.size();
+		staged.write(offset, chunk);
+		Ok(())
+	}
+
+	// Swaps the staged content in at the path
+	fn store(&self) -> Result<()> {
+		let content = Arc::new(self.staged.take());
+		let mut state = self.state.blocking_write();
+		// The path may have changed while streaming
+		if state.files.get(&self.path).is_some_and(|file| file.is_directory) {
+			return Err(fs_error("EISDIR", "illegal operation on a directory", &self.path));
+		}
+		if !state.has_space_for(&self.path, content.size()) {
+			return Err(fs_error("ENOSPC", "no space left on device", &self.path));
+		}
+		if state.exceeds_max_files(&self.path) {
+			return Err(fs_error("ENOSPC", "file count quota reached", &self.path));
+		}
+		ensure_parent(&mut state, &self.path, true)?;
+
+		let replaced = state.insert_entry(&self.path, common::VirtualFile {
+			size: content.size(),
+			content,
+			mtime: std::time::SystemTime::now(),
+			..Default::default()
+		}).map_err(|e| insert_error(e, &self.path))?;
+		state.emit_event(FSEvent::Created { path: self.path.clone(), object_type: common::ObjectType::File });
+		drop(state);
+
+		// A consumer that already read the old content would keep it otherwise
+		if replaced.is_some() {
+			self.inner.blocking_lock().invalidate_paths(std::slice::from_ref(&self.path));
+		}
+		Ok(())
+	}
+
+	fn finish(&self) {
+		if let Some(deferred) = self.deferred.borrow_mut().take() {
+			match self.store() {
+				Ok(()) => deferred.resolve(|_| Ok(())),
+				Err(error) => deferred.reject(error),
+			}
+		}
+	}
+
+	fn fail(&self, error: Error) {
+		if let Some(deferred) = self.deferred.borrow_mut().take() {
+			// Failing to store the partial content changes nothing to what the stream failed with
+			if self.keep_partial {
+				let _ = self.store();
+			}
+			deferred.reject(error);
+		}
+	}
+}
+
+#[napi(object)]
+pub struct Usage {
+	/// Bytes under the requested prefix, the whole tree by default. Each entry counts its content plus a fixed
+	/// overhead, the same figure the quota and statfs go by
+	pub used_bytes: i64,
+	pub total_bytes: i64,
+	/// Files and directories under the requested prefix
+	pub file_count: i64,
+	pub max_files: i64,
+}
+
+#[napi(object)]
+pub struct QuotaOptions {
+	pub total_space_bytes: Option<i64>,
+	pub max_files: Option<i64>,
+}
+
+#[napi(object)]
+pub struct MountInfo {
+	pub path: String,
+	/// Milliseconds since the Unix epoch
+	pub since: f64,
+	/// Files whose content was served through this mount
+	pub files_projected: i64,
+	pub bytes_projected: i64,
+}
+
+#[napi(object)]
+pub struct ResolvedPath {
+	pub is_directory: Option<bool>,
+	pub content: Option<Buffer>,
+}
+
+// Values FileSystemEvent.eventType can take
+const EVENT_TYPES: [&str; 10] = ["created", "modified", "deleted", "cleared", "bulkCreated", "error", "accessed", "renamed", "mounted", "unmounted"];
+
+fn event_type_name(event: &FSEvent) -> &'static str {
+	match event {
+		FSEvent::Created { .. } => "created",
+		FSEvent::Modified { .. } => "modified",
+		FSEvent::Deleted { .. } => "deleted",
+		FSEvent::Cleared => "cleared",
+		FSEvent::BulkCreated => "bulkCreated",
+		FSEvent::Error { .. } => "error",
+		FSEvent::Accessed { .. } => "accessed",
+		FSEvent::Renamed { .. } => "renamed",
+		FSEvent::Mounted { .. } => "mounted",
+		FSEvent::Unmounted { .. } => "unmounted",
+	}
+}
+
+// Rejects event types FileSystemEvent never carries, so a typo does not silently match nothing
+fn check_event_type(event_type: &str) -> Result<()> {
+	if !EVENT_TYPES.contains(&event_type) {
+		return Err(Error::new(Status::InvalidArg, format!("unknown event type '{}'", event_type)));
+	}
+	Ok(())
+}
+
+fn to_js_event(record: common::EventRecord) -> FileSystemEvent {
+	let event_type = event_type_name(&record.event);
+	let old_path = match &record.event {
+		FSEvent::Renamed { from, .. } => Some(from.clone()),
+		_ => None,
+	};
+	let (error_kind, operation, code) = match &record.event {
+		FSEvent::Error { kind, operation, code, .. } => (Some(kind.as_str().to_string()), Some(operation.to_string()), *code),
+		_ => (None, None, None),
+	};
+	let (path, object_type, message, bytes) = match record.event {
+		FSEvent::Created { path, object_type }
+		| FSEvent::Modified { path, object_type }
+		| FSEvent::Deleted { path, object_type }
+		| FSEvent::Renamed { to: path, object_type, .. } => (path, object_type, None, None),
+		FSEvent::Cleared | FSEvent::BulkCreated => (String::new(), common::ObjectType::Directory, None, None),
+		FSEvent::Error { path, message, .. } => (path, common::ObjectType::File, Some(message), None),
+		FSEvent::Accessed { path, bytes } => (path, common::ObjectType::File, None, Some(bytes as i64)),
+		FSEvent::Mounted { path } | FSEvent::Unmounted { path } => (path, common::ObjectType::Directory, None, None),
+	};
+
+	FileSystemEvent {
+		event_type: event_type.to_string(),
+		path,
+		object_type: object_type.as_str().to_string(),
+		message,
+		error_kind,
+		operation,
+		code,
+		bytes,
+		time: system_time_to_ms(record.time),
+		size: record.size.map(|size| size as i64),
+		ino: record.ino.map(BigInt::from),
+		old_path,
+	}
+}
+
+fn system_time_to_ms(time: std::time::SystemTime) -> f64 {
+	time.duration_since(std::time::UNIX_EPOCH)
+		.map(|duration| duration.as_secs_f64() * 1000.0)
+		.unwrap_or(0.0)
+}
+
+// None for NaN, infinities and times the platform cannot represent
+fn ms_to_system_time(ms: f64) -> Option<std::time::SystemTime> {
+	let offset = std::time::Duration::try_from_secs_f64(ms.abs() / 1000.0).ok()?;
+	if ms < 0.0 {
+		std::time::UNIX_EPOCH.checked_sub(offset)
+	} else {
+		std::time::UNIX_EPOCH.checked_add(offset)
+	}
+}
+
+// Flattens a nested { name: Buffer | string | { ... } } object into (path, content) pairs,
+// directories having no content and always coming before their children
+fn collect_tree(tree: &JsObject, prefix: &str, entries: &mut Vec<(String, Option<Vec<u8>>)>) -> Result<()> {
+	let names = tree.get_property_names()?;
+	for i in 0..names.get_array_length()? {
+		let name = names.get_element::<JsString>(i)?.into_utf8()?.into_owned()?;
+		let key = entry_key(&name)?;
+		let path = if prefix.is_empty() { key } else { format!("{}/{}", prefix, key) };
+		let value: JsUnknown = tree.get_named_property(&name)?;
+
+		if value.is_buffer()? {
+			entries.push((path, Some(JsBuffer::try_from(value)?.into_value()?.to_vec())));
+			continue;
+		}
+		match value.get_type()? {
+			ValueType::String => {
+				entries.push((path, Some(JsString::try_from(value)?.into_utf8()?.into_owned()?.into_bytes())));
+			}
+			ValueType::Object if !value.is_array()? => {
+				entries.push((path.clone(), None));
+				collect_tree(&JsObject::try_from(value)?, &path, entries)?;
+			}
+			_ => return Err(Error::new(Status::InvalidArg, format!("'{}' must be a Buffer, a string or an object", path))),
+		}
+	}
+	Ok(())
+}
+
+// Writes only the runs the content holds, so its holes stay holes on disk too
+async fn write_file_with_mtime(path: &std::path::Path, content: &Content, mtime: std::time::SystemTime) -> std::io::Result<()> {
+	use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+	let mut file = tokio::fs::OpenOptions::new().write(true).create(true).truncate(true).open(path).await?;
+	for (start, chunk) in content.extents() {
+		file.seek(std::io::SeekFrom::Start(start)).await?;
+		file.write_all(chunk).await?;
+	}
+	file.set_len(content.size()).await?;
+	file.into_std().await.set_modified(mtime)
+}
+
+#[cfg(unix)]
+async fn write_symlink(path: &std::path::Path, target: &[u8], _mtime: std::time::SystemTime) -> std::io::Result<()> {
+	let _ = tokio::fs::remove_file(path).await;
+	tokio::fs::symlink(String::from_utf8_lossy(target).as_ref(), path).await
+}
+
+// Like the projection itself, symlinks end up as regular files holding their target
+#[cfg(windows)]
+async fn write_symlink(path: &std::path::Path, target: &[u8], mtime: std::time::SystemTime) -> std::io::Result<()> {
+	write_file_with_mtime(path, &Content::from(target.to_vec()), mtime).await
+}
+
+// Property names MountOptions is read from, anything else is rejected rather than ignored
+const MOUNT_OPTION_NAMES: [&str; 21] = [
+	"totalSpaceBytes", "allowOther", "allowRoot", "autoUnmount", "defaultPermissions", "fsName", "subtype", "root", "createMountPoint", "atime",
+	"cache", "entryTtlMs", "attrTtlMs", "rawOptions", "volumeName", "appleDouble", "appleXattr", "local", "blockSize", "nameMax",
+	"notifications",
+];
+// FUSE options set from the fields of MountOptions or by fuser itself, which rawOptions may not override
+const MANAGED_RAW_OPTIONS: [&str; 18] = [
+	"fsname", "subtype", "default_permissions", "auto_unmount", "allow_other", "allow_root", "atime", "noatime", "relatime",
+	"strictatime", "fd", "rootmode", "user_id", "group_id", "volname", "noappledouble", "noapplexattr", "local",
+];
+const ATIME_MODES: [&str; 3] = ["relatime", "noatime", "strictatime"];
+const NOTIFICATION_TYPES: [&str; 4] = ["created", "modified", "deleted", "renamed"];
+
+fn parse_mount_options(options: &JsObject) -> Result<MountOptions> {
+	let names = options.get_property_names()?;
+	for i in 0..names.get_array_length()? {
+		let name = names.get_element::<JsString>(i)?.into_utf8()?.into_owned()?;
+		if !MOUNT_OPTION_NAMES.contains(&name.as_str()) {
+			return Err(Error::new(Status::InvalidArg, format!("unknown mount option '{}'", name)));
+		}
+	}
+
+	fn get<V: FromNapiValue>(options: &JsObject, name: &str, expected: &str) -> Result<Option<V>> {
+		options.get(name).map_err(|_| Error::new(Status::InvalidArg, format!("mount option '{}' must be {}", name, expected)))
+	}
+	let atime: Option<String> = get(options, "atime", "a string")?;
+	if let Some(atime) = &atime {
+		if !ATIME_MODES.contains(&atime.as_str()) {
+			return Err(Error::new(Status::InvalidArg, format!("unknown atime mode '{}'", atime)));
+		}
+	}
+	let cache: Option<String> = get(options, "cache", "a string")?;
+	if let Some(cache) = &cache {
+		parse_cache_policy(cache)?;
+	}
+	let raw_options: Option<Vec<String>> = get(options, "rawOptions", "an array of strings")?;
+	for option in raw_options.iter().flatten() {
+		let name = option.split('=').next().unwrap_or_default();
+		if name.is_empty() || option.contains(',') {
+			return Err(Error::new(Status::InvalidArg, format!("invalid raw mount option '{}'", option)));
+		}
+		if MANAGED_RAW_OPTIONS.contains(&name) {
+			return Err(Error::new(Status::InvalidArg, format!("raw mount option '{}' conflicts with a managed option", option)));
+		}
+	}
+	let notifications: Option<Vec<String>> = get(options, "notifications", "an array of strings")?;
+	for notification in notifications.iter().flatten() {
+		if !NOTIFICATION_TYPES.contains(&notification.as_str()) {
+			return Err(Error::new(Status::InvalidArg, format!("unknown notification '{}'", notification)));
+		}
+	}
+	Ok(MountOptions {
+		total_space_bytes: get(options, "totalSpaceBytes", "a number")?,
+		allow_other: get(options, "allowOther", "a boolean")?,
+		allow_root: get(options, "allowRoot", "a boolean")?,
+		auto_unmount: get(options, "autoUnmount", "a boolean")?,
+		default_permissions: get(options, "defaultPermissions", "a boolean")?,
+		fs_name: get(options, "fsName", "a string")?,
+		subtype: get(options, "subtype", "a string")?,
+		root: get(options, "root", "a string")?,
+		create_mount_point: get(options, "createMountPoint", "a boolean")?,
+		atime,
+		cache,
+		entry_ttl_ms: get(options, "entryTtlMs", "a number")?,
+		attr_ttl_ms: get(options, "attrTtlMs", "a number")?,
+		raw_options,
+		volume_name: get(options, "volumeName", "a string")?,
+		apple_double: get(options, "appleDouble", "a boolean")?,
+		apple_xattr: get(options, "appleXattr", "a boolean")?,
+		local: get(options, "local", "a boolean")?,
+		block_size: get(options, "blockSize", "a number")?,
+		name_max: get(options, "nameMax", "a number")?,
+		notifications,
+	})
+}
+
+fn parse_cache_policy(name: &str) -> Result<CachePolicy> {
+	CachePolicy::from_name(name).ok_or_else(|| Error::new(Status::InvalidArg, format!("unknown cache policy '{}'", name)))
+}
+
+// Reads an optional timestamp property given either as milliseconds or as a Date
+fn get_time_property(times: &JsObject, name: &str) -> Result<Option<std::time::SystemTime>> {
+	let value: JsUnknown = times.get_named_property(name)?;
+	match value.get_type()? {
+		ValueType::Undefined | ValueType::Null => Ok(None),
+		// A Date coerces to its milliseconds since the epoch
+		ValueType::Number | ValueType::Object => {
+			let ms = value.coerce_to_number()?.get_double()?;
+			match ms_to_system_time(ms) {
+				Some(time) => Ok(Some(time)),
+				None => Err(Error::new(Status::InvalidArg, format!("{} is not a valid time", name))),
+			}
+		}
+		_ => Err(Error::new(Status::InvalidArg, format!("{} must be a number or a Date", name))),
+	}
+}
+
+#[napi(object)]
+pub struct EventFilterOptions {
+	/// Glob the event path has to match, `*` stopping at path separators
+	pub path_glob: Option<String>,
+	/// Event types to deliver, e.g. ["created", "deleted"]
+	pub types: Option<Vec<String>>,
+}
+
+#[napi(object)]
+pub struct WaitForEventOptions {
+	/// Event type to wait for, e.g. "created"
+	#[napi(js_name = "type")]
+	pub event_type: Option<String>,
+	pub path: Option<String>,
+	/// Reject with ETIMEDOUT when no matching event arrives in time
+	pub timeout_ms: Option<u32>,
+}
+
+#[napi(object)]
+pub struct EventStreamOptions {
+	/// Events held for a consumer that has not called next() yet, 1024 by default
+	pub buffer_size: Option<u32>,
+	/// "wait" (default) holds further events back until the consumer catches up, "drop" discards them
+	pub overflow: Option<String>,
+	pub filter: Option<EventFilterOptions>,
+}
+
+#[napi(object)]
+pub struct EventIteratorResult {
+	pub done: bool,
+	pub value: Option<FileSystemEvent>,
+}
+
+// Events a watch() subscription forwards, checked before anything crosses into JS
+enum WatchFilter {
+	// The path itself and everything below it
+	Path(String),
+	Glob(globset::GlobMatcher),
+}
+
+impl WatchFilter {
+	// Paths are resolved against the current entries so events, which carry the stored casing, match
+	fn new(filter: &str, files: &common::FileMap) -> Result<Self> {
+		let filter = common::normalize_path(filter);
+		if !filter.contains(['*', '?', '[', '{']) {
+			return Ok(WatchFilter::Path(files.key(&filter)));
+		}
+		Self::glob(&filter, files)
+	}
+
+	fn glob(pattern: &str, files: &common::FileMap) -> Result<Self> {
+		let glob = globset::GlobBuilder::new(pattern)
+			.literal_separator(true)
+			.case_insensitive(files.is_case_insensitive())
+			.build()
+			.map_err(|e| Error::new(Status::InvalidArg, e.to_string()))?;
+		Ok(WatchFilter::Glob(glob.compile_matcher()))
+	}
+
+	fn matches(&self, event: &FSEvent) -> bool {
+		let path = match event.path() {
+			Some(path) => path,
+			// Whole-tree events may concern any watched path
+			None => return true,
+		};
+		// Moving an entry out of the watched paths concerns them as much as moving one in
+		if let FSEvent::Renamed { from, .. } = event {
+			if self.matches_path(from) {
+				return true;
+			}
+		}
+		self.matches_path(path)
+	}
+
+	fn matches_path(&self, path: &str) -> bool {
+		match self {
+			WatchFilter::Path(prefix) => {
+				prefix.is_empty() || path == prefix || path.strip_prefix(prefix.as_str()).is_some_and(|rest| rest.starts_with('/'))
+			}
+			WatchFilter::Glob(matcher) => matcher.is_match(path),
+		}
+	}
+}
+
+// Everything a subscription checks before an event crosses into JS, the default lets all through
+#[derive(Default)]
+struct EventFilter {
+	path: Option<WatchFilter>,
+	types: Option<Vec<String>>,
+}
+
+impl EventFilter {
+	fn new(options: Option<EventFilterOptions>, files: &common::FileMap) -> Result<Self> {
+		let Some(options) = options else {
+			return Ok(Self::default());
+		};
+		for event_type in options.types.iter().flatten() {
+			check_event_type(event_type)?;
+		}
+		let path = match &options.path_glob {
+			Some(pattern) => Some(WatchFilter::glob(&common::normalize_path(pattern), files)?),
+			None => None,
+		};
+		Ok(Self { path, types: options.types })
+	}
+
+	fn matches(&self, event: &FSEvent) -> bool {
+		self.types.as_ref().is_none_or(|types| types.iter().any(|event_type| event_type == event_type_name(event)))
+			&& self.path.as_ref().is_none_or(|path| path.matches(event))
+	}
+}
+
+async fn report_lag(state: &SharedFSState, missed: u64) {
+	let message = format!("{} events were dropped before reaching a listener", missed);
+	state.read().await.emit_error(common::ErrorKind::Lagged, "deliver", "", message, None);
+}
+
+// Forwards events from the broadcast channel to a JS callback until closed
+struct EventForwarder {
+	close_sender: Option<tokio::sync::oneshot::Sender<()>>,
+	// Cleared on close so deliveries already queued on the JS thread are dropped too
+	active: Arc<AtomicBool>,
+}
+
+impl EventForwarder {
+	// With `once` the forwarder closes itself after the first delivered event
+	fn spawn(env: &Env, state: &SharedFSState, callback: JsFunction, filter: EventFilter, once: bool) -> Result<Self> {
+		// Deliveries made after close arrive as null and never reach the callback
+		let wrap: JsFunction = env.run_script("(callback) => (event) => { if (event !== null) callback(event) }")?;
+		let callback: JsFunction = wrap.call(None, &[callback])?.try_into()?;
+
+		let active = Arc::new(AtomicBool::new(true));
+		let tsfn: ThreadsafeFunction<FileSystemEvent, ErrorStrategy::Fatal> = {
+			let active = active.clone();
+			callback.create_threadsafe_function(0, move |ctx| Ok(vec![active.load(Ordering::SeqCst).then_some(ctx.value)]))?
+		};
+
+		// Subscribe right away so no event sent after registration is missed
+		let mut rx = state.blocking_read().subscribe_to_events();
+		let (close_sender, mut close_receiver) = tokio::sync::oneshot::channel();
+
+		let state = state.clone();
+		spawn(async move {
+			loop {
+				let event = tokio::select! {
+					_ = &mut close_receiver => break,
+					event = rx.recv() => event,
+				};
+
+				match event {
+					Ok(record) if filter.matches(&record.event) => {
+						tsfn.call(to_js_event(record), napi::threadsafe_function::ThreadsafeFunctionCallMode::Blocking);
+						if once {
+							break;
+						}
+					}
+					Ok(_) => {}
+					// A slow callback only loses the events it fell behind on
+					Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => report_lag(&state, missed).await,
+					Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+				}
+			}
+		});
+
+		Ok(Self { close_sender: Some(close_sender), active })
+	}
+
+	// The forwarding task is gone, either closed or done after its single event
+	fn is_finished(&self) -> bool {
+		self.close_sender.as_ref().is_none_or(|sender| sender.is_closed())
+	}
+
+	fn close(&mut self) {
+		self.active.store(false, Ordering::SeqCst);
+		if let Some(sender) = self.close_sender.take() {
+			sender.send(()).ok();
+		}
+	}
+}
+
+/// Returned by watch(), events stop being delivered once closed
+#[napi]
+pub struct Watcher {
+	forwarder: EventForwarder,
+}
+
+#[napi]
+impl Watcher {
+	#[napi]
+	pub fn close(&mut self) {
+		self.forwarder.close();
+	}
+}
+
+/// Returned by events(), an async iterator over the events sent after it was created
+#[napi]
+pub struct EventStream {
+	// None once the stream is closed
+	receiver: Arc<Mutex<Option<tokio::sync::mpsc::Receiver<FileSystemEvent>>>>,
+	close_sender: std::sync::Mutex<Option<tokio::sync::oneshot::Sender<()>>>,
+}
+
+#[napi]
+impl EventStream {
+	#[napi(ts_return_type = "Promise<IteratorResult<FileSystemEvent, undefined>>")]
+	pub async fn next(&self) -> Result<EventIteratorResult> {
+		let mut receiver = self.receiver.lock().await;
+		let value = match receiver.as_mut() {
+			Some(receiver) => receiver.recv().await,
+			None => None,
+		};
+		Ok(EventIteratorResult { done: value.is_none(), value })
+	}
+
+	/// Stops the stream and frees its subscription, called by `break` in a for await loop
+	#[napi(js_name = "return", ts_return_type = "Promise<IteratorResult<FileSystemEvent, undefined>>")]
+	pub async fn close(&self) -> Result<EventIteratorResult> {
+		// Ending the pump first drops the sending side, so a pending next() resolves as done
+		if let Some(sender) = self.close_sender.lock().unwrap().take() {
+			sender.send(()).ok();
+		}
+		self.receiver.lock().await.take();
+		Ok(EventIteratorResult { done: true, value: None })
+	}
+}
+
+// Sent to the mount thread: whether to force the unmount, and the channel it signals once the session is torn down
+type UnmountRequest = (bool, tokio::sync::oneshot::Sender<()>);
+
 #[napi(js_name = "FuseFS")]
 pub struct JsFuseFS {
 	inner: Arc<Mutex<FSImpl>>,
 	state: SharedFSState,
 	mount_path: Arc<Mutex<Option<PathBuf>>>,
-	unmount_sender: Arc<Mutex<Option<tokio::sync::oneshot::Sender<()>>>>,
+	// Options of the latest successful mount, reused by remount()
+	mount_options: Arc<Mutex<Option<MountOptions>>>,
+	unmount_sender: Arc<Mutex<Option<tokio::sync::oneshot::Sender<UnmountRequest>>>>,
+	// Forwarders registered through on(), keyed by the id handed back to JS
+	listeners: Arc<std::sync::Mutex<HashMap<u32, EventForwarder>>>,
+	next_listener_id: AtomicU32,
 }
 
-#[napi]
-impl JsFuseFS {
-	#[napi(constructor)]
-	pub fn new() -> Self {
-		let state = create_fs_state();
-		JsFuseFS {
-			inner: Arc::new(Mutex::new(FSImpl::new(state.clone()))),
-			state,
-			mount_path: Arc::new(Mutex::new(None)),
-			unmount_sender: Arc::new(Mutex::new(None)),
+// Mounts on a thread of its own and keeps the session there until an unmount request arrives.
+// `ready` gets the outcome of the mount itself
+fn spawn_session_thread(
+	inner: Arc<Mutex<FSImpl>>,
+	state: SharedFSState,
+	mount_path: PathBuf,
+	options: MountOptions,
+	ready: tokio::sync::oneshot::Sender<Result<()>>,
+	unmount_receiver: tokio::sync::oneshot::Receiver<UnmountRequest>,
+) {
+	std::thread::spawn(move || {
+		let rt = tokio::runtime::Runtime::new().unwrap();
+		rt.block_on(async {
+			// The platform layer also reports mount failures as error events, with their error code
+			let (mounted, stats) = {
+				let mut inner = inner.lock().await;
+				let mounted = inner.mount(&mount_path, &options).await;
+				let stats = inner.mounts().into_iter().find(|(path, _)| *path == mount_path).map(|(_, stats)| stats);
+				(mounted, stats)
+			};
+			let failed = mounted.is_err();
+			ready.send(mounted).ok();
+			if failed {
+				return;
+			}
+			let ended = async {
+				if let Some(stats) = &stats {
+					stats.ended().await;
+				}
+			};
+			let request = tokio::select! {
+				request = unmount_receiver => request,
+				_ = ended => {
+					// Unmounted from outside, e.g. with umount, only the dead session is left to drop. Unless
+					// a new session took the path over in the meantime, which must be left alone
+					let mut inner = inner.lock().await;
+					if !inner.is_mounted(&mount_path) {
+						inner.unmount(&mount_path, false).await.ok();
+					}
+					return;
+				}
+			};
+			let (force, done) = request.map_or((false, None), |(force, done)| (force, Some(done)));
+			if let Err(e) = inner.lock().await.unmount(&mount_path, force).await {
+				state.read().await.emit_error(common::ErrorKind::Mount, "unmount", &mount_path.to_string_lossy(), e.reason, None);
+			}
+			if let Some(done) = done {
+				done.send(()).ok();
+			}
+		});
+	});
+}
+
+// Rejects with a distinct code for each reason the directory cannot be mounted on, before any
+// thread is spawned: ENOENT, ENOTDIR, EBUSY when already mounted and ENOTEMPTY
+async fn check_mount_point(inner: &Mutex<FSImpl>, mount_path: &std::path::Path, create: bool) -> Result<()> {
+	let display = mount_path.to_string_lossy();
+	match tokio::fs::metadata(mount_path).await {
+		Ok(metadata) if metadata.is_dir() => {}
+		Ok(_) => return Err(fs_error("ENOTDIR", "mount point is not a directory", &display)),
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound && create => {
+			tokio::fs::create_dir_all(mount_path).await.map_err(|e| fs_error("EIO", &e.to_string(), &display))?;
+		}
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+			return Err(fs_error("ENOENT", "mount point does not exist", &display));
+		}
+		// E.g. a FUSE mount whose process died, which fails with ENOTCONN
+		Err(e) => return Err(fs_error("EIO", &e.to_string(), &display)),
+	}
+
+	if inner.lock().await.is_mounted(mount_path) {
+		return Err(fs_error("EBUSY", "already mounted", &display));
+	}
+	FSImpl::check_mount_point(mount_path)
+}
+
+// EBUSY listing the paths still open through the mount, if any
+fn check_not_busy(busy: Vec<String>) -> Result<()> {
+	if busy.is_empty() {
+		return Ok(());
+	}
+	let paths = busy.iter().map(|path| format!("'{}'", path)).collect::<Vec<_>>().join(", ");
+	Err(Error::from_reason(format!("EBUSY: resource busy or locked, {}", paths)))
+}
+
+// Asks the mount thread for a forced unmount, the receiver is signalled once it is done. None when
+// nothing is mounted or an unmount() is already under way
+fn request_forced_unmount(unmount_sender: &Mutex<Option<tokio::sync::oneshot::Sender<UnmountRequest>>>) -> Option<tokio::sync::oneshot::Receiver<()>> {
+	let sender = unmount_sender.try_lock().ok()?.take()?;
+	let (done_tx, done_rx) = tokio::sync::oneshot::channel();
+	sender.send((true, done_tx)).ok()?;
+	Some(done_rx)
+}
+
+// A garbage collected instance would otherwise leave its mount point wedged until fusermount -u
+impl Drop for JsFuseFS {
+	fn drop(&mut self) {
+		request_forced_unmount(&self.unmount_sender);
+	}
+}
+
+#[napi]
+impl JsFuseFS {
+	#[napi(constructor)]
+	pub fn new(mut env: Env, options: Option<FuseFSOptions>) -> Result<Self> {
+		let options = options.unwrap_or(FuseFSOptions {
+			total_space_bytes: None,
+			max_files: None,
+			case_insensitive: None,
+			event_buffer_size: None,
+			split_rename_events: None,
+			quota_logical_size: None,
+		});
+		for (name, value) in [
+			("totalSpaceBytes", options.total_space_bytes),
+			("maxFiles", options.max_files),
+			("eventBufferSize", options.event_buffer_size),
+		] {
+			if value.is_some_and(|value| value <= 0) {
+				return Err(Error::new(Status::InvalidArg, format!("{} must be greater than 0", name)));
+			}
+		}
+
+		let mut state = common::FSState::new(
+			options.event_buffer_size.map_or(common::DEFAULT_EVENT_BUFFER_SIZE, |size| size as usize),
+			options.case_insensitive.unwrap_or(false),
+		);
+		if let Some(total_space_bytes) = options.total_space_bytes {
+			state.total_space_bytes = total_space_bytes as u64;
+		}
+		if let Some(max_files) = options.max_files {
+			state.max_files = max_files as u64;
+		}
+		state.split_rename_events = options.split_rename_events.unwrap_or(false);
+		state.quota_logical_size = options.quota_logical_size.unwrap_or(false);
+
+		// Finalizers do not run when the process exits, so tear down whatever is still mounted then.
+		// Waiting is fine there, a forced unmount does not depend on the event loop
+		let unmount_sender = Arc::new(Mutex::new(None));
+		env.add_env_cleanup_hook(Arc::downgrade(&unmount_sender), |unmount_sender| {
+			if let Some(done) = unmount_sender.upgrade().and_then(|unmount_sender| request_forced_unmount(&unmount_sender)) {
+				done.blocking_recv().ok();
+			}
+		})?;
+
+		let state = create_fs_state(state);
+		Ok(JsFuseFS {
+			inner: Arc::new(Mutex::new(FSImpl::new(state.clone()))),
+			state,
+			mount_path: Arc::new(Mutex::new(None)),
+			mount_options: Arc::new(Mutex::new(None)),
+			unmount_sender,
+			listeners: Arc::new(std::sync::Mutex::new(HashMap::new())),
+			next_listener_id: AtomicU32::new(1),
+		})
+	}
+
+	/// Resolves once the path is served. Rejects with ENOENT, ENOTDIR, EBUSY or ENOTEMPTY when the
+	/// directory cannot be mounted on. When mounting itself fails FUSE rejects with ENODEV if it is not
+	/// available, EACCES if the mount is not permitted, EBUSY or EINVAL if the kernel refuses it and EIO
+	/// otherwise, ProjFS with its own error.
+	/// A number in place of `options` is taken as `totalSpaceBytes`
+	#[napi(ts_args_type = "path: string, options?: number | MountOptions | undefined | null", ts_return_type = "Promise<void>")]
+	pub fn mount(&self, env: Env, path: String, options: Option<Either<i64, JsObject>>) -> Result<JsObject> {
+		let mut options = match options {
+			None => MountOptions::default(),
+			Some(Either::A(total_space_bytes)) => MountOptions { total_space_bytes: Some(total_space_bytes), ..Default::default() },
+			Some(Either::B(options)) => parse_mount_options(&options)?,
+		};
+		if options.total_space_bytes.is_some_and(|bytes| bytes <= 0) {
+			return Err(Error::new(Status::InvalidArg, "totalSpaceBytes must be greater than 0"));
+		}
+		if options.allow_other == Some(true) && options.allow_root == Some(true) {
+			return Err(Error::new(Status::InvalidArg, "allowOther and allowRoot cannot be combined"));
+		}
+		if options.block_size.is_some_and(|size| !size.is_power_of_two() || !(512..=1024 * 1024).contains(&size)) {
+			return Err(Error::new(Status::InvalidArg, "blockSize must be a power of two from 512 to 1048576"));
+		}
+		// FUSE itself does not pass longer names
+		if options.name_max.is_some_and(|name_max| !(1..=1024).contains(&name_max)) {
+			return Err(Error::new(Status::InvalidArg, "nameMax must be from 1 to 1024"));
+		}
+		if let Some(root) = &options.root {
+			let state = self.state.blocking_read();
+			let root = state.files.key(&prefix_key(root)?);
+			match state.files.get(&root) {
+				Some(file) if file.is_directory => {}
+				Some(_) => return Err(fs_error("ENOTDIR", "not a directory", &root)),
+				None if !root.is_empty() => return Err(fs_error("ENOENT", "no such file or directory", &root)),
+				None => {}
+			}
+			// The platform layers take the root as a state key
+			options.root = Some(root);
+		}
+
+		env.spawn_future(self.start_session(PathBuf::from(path), options))
+	}
+
+	// Everything mount() does once the options are validated, resolving once the path is served
+	fn start_session(&self, mount_path: PathBuf, options: MountOptions) -> impl std::future::Future<Output = Result<()>> + Send + 'static {
+		let mount_path_slot = self.mount_path.clone();
+		let mount_options_slot = self.mount_options.clone();
+		let unmount_sender = self.unmount_sender.clone();
+		let inner = self.inner.clone();
+		let state = self.state.clone();
+		async move {
+			check_mount_point(&inner, &mount_path, options.create_mount_point.unwrap_or(false)).await?;
+			*mount_path_slot.lock().await = Some(mount_path.clone());
+
+			let (tx, rx) = tokio::sync::oneshot::channel();
+			*unmount_sender.lock().await = Some(tx);
+
+			// Configure the filesystem before spawning the thread
+			if let Some(total_space_bytes) = options.total_space_bytes {
+				state.write().await.total_space_bytes = total_space_bytes as u64;
+			}
+
+			let (ready_tx, ready_rx) = tokio::sync::oneshot::channel();
+			spawn_session_thread(inner, state.clone(), mount_path.clone(), options.clone(), ready_tx, rx);
+
+			// Only resolve once the path is served, so a failed mount rejects instead of going unnoticed
+			let mounted = ready_rx.await.unwrap_or_else(|_| Err(Error::from_reason("Mount failed: mount thread exited")));
+			match &mounted {
+				Ok(()) => {
+					*mount_options_slot.lock().await = Some(options);
+					state.read().await.emit_event(FSEvent::Mounted { path: mount_path.to_string_lossy().into_owned() });
+				}
+				Err(_) => {
+					*unmount_sender.lock().await = None;
+					*mount_path_slot.lock().await = None;
+				}
+			}
+			mounted
+		}
+	}
+
+	/// Moves the mount to `newPath`, keeping its options and the whole tree. Rejects with EBUSY like
+	/// unmount() while files are open through the old path. A "mounted" event is sent once `newPath` is served
+	#[napi]
+	pub async fn remount(&self, new_path: String) -> Result<()> {
+		if self.mount_path.lock().await.is_none() {
+			return Err(Error::from_reason("EINVAL: not mounted, call mount() first"));
+		}
+		let options = self.mount_options.lock().await.clone().unwrap_or_default();
+		self.unmount(None).await?;
+		self.start_session(PathBuf::from(new_path), options).await
+	}
+
+	/// Resolves once the session is torn down, the directory can be removed or mounted again right away.
+	/// Rejects with EBUSY, listing the open paths, while files are open through the mount unless forced.
+	/// Resolves as well once the mount went away from outside, e.g. with umount, which already sent "unmounted"
+	#[napi]
+	pub async fn unmount(&self, options: Option<UnmountOptions>) -> Result<()> {
+		let force = options.and_then(|options| options.force).unwrap_or(false);
+		let mut unmount_sender = self.unmount_sender.lock().await;
+		if !force {
+			if let Some(mount_path) = self.mount_path.lock().await.as_ref() {
+				check_not_busy(self.inner.lock().await.busy_paths(mount_path))?;
+			}
+		}
+
+		if let Some(sender) = unmount_sender.take() {
+			// Resolve only once the session is gone so isMounted() reflects it right away
+			let (done_tx, done_rx) = tokio::sync::oneshot::channel();
+			if sender.send((force, done_tx)).is_ok() {
+				done_rx.await.ok();
+			}
+		}
+		*self.mount_path.lock().await = None;
+		Ok(())
+	}
+
+	/// Blocking unmount() for process 'exit' handlers, which cannot wait for a promise. Throws ETIMEDOUT
+	/// when the teardown takes longer than 5 seconds. Waits for an unmount() already under way within
+	/// that same time, and does nothing once either of them has completed
+	#[napi]
+	pub fn unmount_sync(&self, options: Option<UnmountOptions>) -> Result<()> {
+		let force = options.and_then(|options| options.force).unwrap_or(false);
+		let deadline = std::time::Instant::now() + UNMOUNT_SYNC_TIMEOUT;
+		let timed_out = || Error::from_reason("ETIMEDOUT: unmount did not complete in time");
+
+		// unmount() holds the lock until its teardown is done
+		let mut unmount_sender = loop {
+			match self.unmount_sender.try_lock() {
+				Ok(sender) => break sender,
+				Err(_) if std::time::Instant::now() < deadline => std::thread::sleep(UNMOUNT_SYNC_POLL_INTERVAL),
+				Err(_) => return Err(timed_out()),
+			}
+		};
+		if !force {
+			if let Some(mount_path) = self.mount_path.blocking_lock().as_ref() {
+				check_not_busy(self.inner.blocking_lock().busy_paths(mount_path))?;
+			}
+		}
+
+		if let Some(sender) = unmount_sender.take() {
+			let (done_tx, mut done_rx) = tokio::sync::oneshot::channel();
+			if sender.send((force, done_tx)).is_ok() {
+				while let Err(tokio::sync::oneshot::error::TryRecvError::Empty) = done_rx.try_recv() {
+					if std::time::Instant::now() >= deadline {
+						*self.mount_path.blocking_lock() = None;
+						return Err(timed_out());
+					}
+					std::thread::sleep(UNMOUNT_SYNC_POLL_INTERVAL);
+				}
+			}
+		}
+		*self.mount_path.blocking_lock() = None;
+		Ok(())
+	}
+
+	/// False as soon as the mount went away, including from outside, e.g. with umount
+	#[napi]
+	pub fn is_mounted(&self) -> bool {
+		match self.mount_path.blocking_lock().as_ref() {
+			Some(path) => self.inner.blocking_lock().is_mounted(path),
+			None => false,
+		}
+	}
+
+	#[napi]
+	pub async fn mounts(&self) -> Result<Vec<MountInfo>> {
+		let mounts = self.inner.lock().await.mounts();
+		Ok(mounts.into_iter()
+			.map(|(path, stats)| MountInfo {
+				path: path.to_string_lossy().into_owned(),
+				since: system_time_to_ms(stats.since),
+				files_projected: stats.files_projected.load(std::sync::atomic::Ordering::Relaxed) as i64,
+				bytes_projected: stats.bytes_projected.load(std::sync::atomic::Ordering::Relaxed) as i64,
+			})
+			.collect())
+	}
+
+	/// Quotas are global, only the used counts are narrowed down by `prefix`
+	#[napi]
+	pub async fn get_usage(&self, prefix: Option<String>) -> Result<Usage> {
+		let state = self.state.read().await;
+		let (used_bytes, file_count) = state.usage_under(&common::normalize_path(&prefix.unwrap_or_default()));
+		Ok(Usage {
+			used_bytes: used_bytes as i64,
+			total_bytes: state.total_space_bytes as i64,
+			file_count: file_count as i64,
+			max_files: state.max_files as i64,
+		})
+	}
+
+	/// Takes effect immediately, including on mounted sessions. Lowering a limit below the
+	/// current usage is allowed, new writes are rejected until enough is freed
+	#[napi]
+	pub async fn set_quota(&self, quota: QuotaOptions) -> Result<()> {
+		for (name, value) in [
+			("totalSpaceBytes", quota.total_space_bytes),
+			("maxFiles", quota.max_files),
+		] {
+			if value.is_some_and(|value| value <= 0) {
+				return Err(Error::new(Status::InvalidArg, format!("{} must be greater than 0", name)));
+			}
+		}
+
+		let mut state = self.state.write().await;
+		if let Some(total_space_bytes) = quota.total_space_bytes {
+			state.total_space_bytes = total_space_bytes as u64;
+		}
+		if let Some(max_files) = quota.max_files {
+			state.max_files = max_files as u64;
+		}
+		Ok(())
+	}
+
+	/// Null unless a session is currently mounted
+	#[napi]
+	pub fn mount_path(&self) -> Option<String> {
+		self.mount_path.blocking_lock().clone()
+			.filter(|path| self.inner.blocking_lock().is_mounted(path))
+			.map(|path| path.to_string_lossy().into_owned())
+	}
+
+	#[napi]
+	pub async fn add_file(&self, path: String, content: Buffer, options: Option<AddOptions>) -> Result<()> {
+		let key = entry_key(&path)?;
+		let content = Arc::new(Content::from(content.to_vec()));
+		let mut state = self.state.write().await;
+		let path = state.files.key(&key);
+
+		if state.files.get(&path).is_some_and(|file| file.is_directory) {
+			return Err(fs_error("EISDIR", "illegal operation on a directory", &path));
+		}
+		// Check if adding this file would exceed the limit
+		if !state.has_space_for(&path, content.size()) {
+			return Err(fs_error("ENOSPC", "no space left on device", &path));
+		}
+		if state.exceeds_max_files(&path) {
+			return Err(fs_error("ENOSPC", "file count quota reached", &path));
+		}
+		ensure_parent(&mut state, &path, options.and_then(|options| options.create_parents).unwrap_or(true))?;
+
+		let replaced = state.insert_entry(&path, common::VirtualFile {
+			size: content.size(),
+			content,
+			is_directory: false,
+			mtime: std::time::SystemTime::now(),
+			..Default::default()
+		}).map_err(|e| insert_error(e, &path))?;
+		state.emit_event(FSEvent::Created { path: path.clone(), object_type: common::ObjectType::File });
+		drop(state);
+
+		// A consumer that already read the old content would keep it otherwise
+		if replaced.is_some() {
+			self.inner.lock().await.invalidate_paths(&[path]);
+		}
+		Ok(())
+	}
+
+	#[napi]
+	pub async fn add_files(&self, entries: Vec<FileEntry>) -> Result<AddFilesResult> {
+		// A single invalid path rejects the whole batch before anything is inserted
+		let keys = entries.iter()
+			.map(|entry| entry_key(&entry.path))
+			.collect::<Result<Vec<_>>>()?;
+		let mut state = self.state.write().await;
+
+		// Check the quota once for the whole batch rather than per entry
+		let batch_size: u64 = entries.iter()
+			.map(|entry| match &entry.content {
+				Some(content) if !entry.is_directory.unwrap_or(false) => common::entry_bytes(content.len() as u64),
+				_ => common::entry_bytes(0),
+			})
+			.sum();
+		if !state.has_space(0, batch_size) {
+			return Err(Error::from_reason("ENOSPC: no space left on device"));
+		}
+
+		let now = std::time::SystemTime::now();
+		let mut result = AddFilesResult { inserted: 0, skipped: 0 };
+		for (entry, key) in entries.into_iter().zip(keys) {
+			// Checked along with the missing parents, which are then sure to fit
+			if state.files.contains_key(&key) || state.exceeds_max_files(&key) {
+				result.skipped += 1;
+				continue;
+			}
+			// Covered by the summary event below like the entries themselves
+			let parent = key.rsplit_once('/').map_or("", |(parent, _)| parent);
+			if state.create_directories(parent).is_err() {
+				result.skipped += 1;
+				continue;
+			}
+
+			let is_directory = entry.is_directory.unwrap_or(false);
+			let content = match entry.content {
+				Some(content) if !is_directory => content.to_vec(),
+				_ => Vec::new(),
+			};
+			let file = common::VirtualFile {
+				size: content.len() as u64,
+				content: Arc::new(content.into()),
+				is_directory,
+				mtime: now,
+				..Default::default()
+			};
+			if state.insert_entry(&key, file).is_err() {
+				result.skipped += 1;
+				continue;
+			}
+			result.inserted += 1;
+		}
+
+		if result.inserted > 0 {
+			state.emit_event(FSEvent::BulkCreated);
+		}
+		Ok(result)
+	}
+
+	#[napi(
+		ts_args_type = "tree: Record<string, any>, options?: TreeOptions | undefined | null",
+		ts_return_type = "Promise<Array<string>>"
+	)]
+	pub fn add_tree(&self, env: Env, tree: JsObject, options: Option<TreeOptions>) -> Result<JsObject> {
+		let overwrite = options.and_then(|options| options.overwrite).unwrap_or(false);
+		let mut entries = Vec::new();
+		collect_tree(&tree, "", &mut entries)?;
+
+		let state = self.state.clone();
+		let inner = self.inner.clone();
+		env.spawn_future(async move {
+			let mut state = state.write().await;
+
+			// Existing directories are merged into, anything else is a conflict
+			let conflicts: Vec<&String> = entries.iter()
+				.filter(|(path, content)| match state.files.get(path) {
+					Some(file) => !(file.is_directory && content.is_none()),
+					None => false,
+				})
+				.map(|(path, _)| path)
+				.collect();
+			if let Some(path) = conflicts.first() {
+				if !overwrite {
+					return Err(fs_error("EEXIST", "file already exists", path));
+				}
+			}
+
+			// Check the quota once for the whole tree, counting the content it replaces
+			let replaced_size: u64 = conflicts.iter()
+				.filter_map(|path| state.files.get(path))
+				.map(|file| file.used_bytes(state.quota_logical_size))
+				.sum();
+			let tree_size: u64 = entries.iter()
+				.map(|(_, content)| common::entry_bytes(content.as_ref().map_or(0, |content| content.len() as u64)))
+				.sum();
+			if !state.has_space(replaced_size, tree_size) {
+				return Err(Error::from_reason("ENOSPC: no space left on device"));
+			}
+			let new_entries = entries.iter().filter(|(path, _)| !state.files.contains_key(path)).count();
+			if !state.has_entries_for(0, new_entries as u64) {
+				return Err(Error::from_reason("ENOSPC: file count quota reached"));
+			}
+			let conflicts: Vec<String> = conflicts.into_iter().cloned().collect();
+
+			let now = std::time::SystemTime::now();
+			let mut created = Vec::new();
+			for (path, content) in entries {
+				if let Some(existing) = state.files.get(&path) {
+					if existing.is_directory && content.is_none() {
+						continue;
+					}
+					// A file replacing a directory takes its whole subtree with it
+					state.remove_tree(&path);
+				}
+
+				let is_directory = content.is_none();
+				let content = content.unwrap_or_default();
+				state.insert_entry(&path, common::VirtualFile {
+					size: content.len() as u64,
+					content: Arc::new(content.into()),
+					is_directory,
+					mtime: now,
+					..Default::default()
+				}).map_err(|e| insert_error(e, &path))?;
+				created.push(path);
+			}
+
+			if !created.is_empty() {
+				state.emit_event(FSEvent::BulkCreated);
+			}
+			drop(state);
+
+			if !conflicts.is_empty() {
+				inner.lock().await.invalidate_paths(&conflicts);
+			}
+			Ok(created)
+		})
+	}
+
+	/// Entries already present at the destination are replaced by their on-disk counterpart
+	#[napi(
+		ts_args_type = "realPath: string, prefix: string, onProgress?: ((progress: ImportProgress) => void) | undefined | null",
+		ts_return_type = "Promise<ImportResult>"
+	)]
+	pub fn import_directory(&self, env: Env, real_path: String, prefix: String, on_progress: Option<JsFunction>) -> Result<JsObject> {
+		let progress: Option<ThreadsafeFunction<ImportProgress, ErrorStrategy::Fatal>> = match on_progress {
+			Some(callback) => Some(callback.create_threadsafe_function(0, |ctx| Ok(vec![ctx.value]))?),
+			None => None,
+		};
+
+		let state = self.state.clone();
+		let inner = self.inner.clone();
+		let root = PathBuf::from(&real_path);
+		let prefix = prefix_key(&prefix)?;
+		env.spawn_future(async move {
+			match tokio::fs::metadata(&root).await {
+				Ok(metadata) if metadata.is_dir() => {}
+				Ok(_) => return Err(fs_error("ENOTDIR", "not a directory", &real_path)),
+				Err(_) => return Err(fs_error("ENOENT", "no such file or directory", &real_path)),
+			}
+
+			// The prefix and its ancestors have to exist for the imported entries to be reachable
+			{
+				let mut state = state.write().await;
+				for created in state.create_directories(&prefix).map_err(|e| insert_error(e, &prefix))? {
+					state.emit_event(FSEvent::Created { path: created, object_type: common::ObjectType::Directory });
+				}
+			}
+
+			// walkdir is blocking, so list the tree on a blocking thread first
+			let walk_root = root.clone();
+			let walked = tokio::task::spawn_blocking(move || {
+				walkdir::WalkDir::new(walk_root).min_depth(1).into_iter().collect::<Vec<_>>()
+			}).await.map_err(|e| Error::from_reason(e.to_string()))?;
+
+			let mut result = ImportResult { files: 0, directories: 0, bytes: 0, skipped: Vec::new() };
+			let mut replaced = Vec::new();
+			for entry in walked {
+				let entry = match entry {
+					Ok(entry) => entry,
+					Err(e) => {
+						let path = e.path().map_or(real_path.clone(), |path| path.to_string_lossy().into_owned());
+						result.skipped.push(SkippedEntry { path, reason: e.to_string() });
+						continue;
+					}
+				};
+
+				let disk_path = entry.path().to_string_lossy().into_owned();
+				let relative = entry.path().strip_prefix(&root).unwrap_or(entry.path());
+				let relative = common::normalize_path(&relative.to_string_lossy());
+				let key = if prefix.is_empty() { relative } else { format!("{}/{}", prefix, relative) };
+
+				if entry.file_type().is_symlink() {
+					result.skipped.push(SkippedEntry { path: disk_path, reason: "symlinks are not imported".to_string() });
+					continue;
+				}
+
+				let metadata = match entry.metadata() {
+					Ok(metadata) => metadata,
+					Err(e) => {
+						result.skipped.push(SkippedEntry { path: disk_path, reason: e.to_string() });
+						continue;
+					}
+				};
+				let mtime = metadata.modified().unwrap_or_else(|_| std::time::SystemTime::now());
+				let atime = metadata.accessed().unwrap_or(mtime);
+
+				if metadata.is_dir() {
+					let mut state = state.write().await;
+					if state.exceeds_max_files(&key) {
+						result.skipped.push(SkippedEntry { path: disk_path, reason: "ENOSPC: exceeds the file count quota".to_string() });
+						continue;
+					}
+					match state.files.get_mut(&key) {
+						Some(existing) if existing.is_directory => existing.mtime = mtime,
+						existing => {
+							if existing.is_some() {
+								state.files.remove(&key);
+								replaced.push(key.clone());
+							}
+							state.insert_entry(&key, common::VirtualFile {
+								is_directory: true,
+								mtime,
+								atime,
+								..Default::default()
+							}).map_err(|e| insert_error(e, &key))?;
+							result.directories += 1;
+						}
+					}
+					continue;
+				}
+
+				let content = match tokio::fs::read(entry.path()).await {
+					Ok(content) => content,
+					Err(e) => {
+						result.skipped.push(SkippedEntry { path: disk_path, reason: e.to_string() });
+						continue;
+					}
+				};
+
+				let mut state = state.write().await;
+				if !state.has_space_for(&key, content.len() as u64) {
+					result.skipped.push(SkippedEntry { path: disk_path, reason: "ENOSPC: exceeds the remaining quota".to_string() });
+					continue;
+				}
+				if state.exceeds_max_files(&key) {
+					result.skipped.push(SkippedEntry { path: disk_path, reason: "ENOSPC: exceeds the file count quota".to_string() });
+					continue;
+				}
+
+				if state.remove_tree(&key).is_some() {
+					replaced.push(key.clone());
+				}
+				result.files += 1;
+				result.bytes += content.len() as i64;
+				state.insert_entry(&key, common::VirtualFile {
+					size: content.len() as u64,
+					content: Arc::new(content.into()),
+					mtime,
+					atime,
+					..Default::default()
+				}).map_err(|e| insert_error(e, &key))?;
+				drop(state);
+
+				if let Some(progress) = &progress {
+					progress.call(
+						ImportProgress { files_copied: result.files, bytes_copied: result.bytes },
+						napi::threadsafe_function::ThreadsafeFunctionCallMode::NonBlocking,
+					);
+				}
+			}
+
+			if result.files + result.directories > 0 {
+				state.read().await.emit_event(FSEvent::BulkCreated);
+			}
+			if !replaced.is_empty() {
+				inner.lock().await.invalidate_paths(&replaced);
+			}
+			Ok(result)
+		})
+	}
+
+	/// `source` is either the archive itself or its path on disk. Entries already present
+	/// at the destination are replaced
+	#[napi]
+	pub async fn import_from_zip(&self, source: Either<Buffer, String>, prefix: Option<String>) -> Result<ZipImportResult> {
+		let prefix = prefix_key(&prefix.unwrap_or_default())?;
+
+		// Decompression is blocking work
+		let read = tokio::task::spawn_blocking(move || match source {
+			Either::A(buffer) => archive::read_zip(std::io::Cursor::new(buffer.to_vec())),
+			Either::B(path) => match std::fs::File::open(&path) {
+				Ok(file) => archive::read_zip(std::io::BufReader::new(file)),
+				Err(e) => Err(format!("{}, '{}'", e, path)),
+			},
+		}).await.map_err(|e| Error::from_reason(e.to_string()))?;
+		let contents = read.map_err(Error::from_reason)?;
+
+		let mut result = ZipImportResult {
+			imported: Vec::new(),
+			skipped: contents.skipped.into_iter().map(|(path, reason)| SkippedEntry { path, reason }).collect(),
+		};
+		let mut replaced = Vec::new();
+
+		let mut state = self.state.write().await;
+
+		for created in state.create_directories(&prefix).map_err(|e| insert_error(e, &prefix))? {
+			state.emit_event(FSEvent::Created { path: created, object_type: common::ObjectType::Directory });
+		}
+
+		for entry in contents.entries {
+			let key = if prefix.is_empty() { entry.path.clone() } else { format!("{}/{}", prefix, entry.path) };
+			let existing = state.files.get(&key).map(|file| file.is_directory);
+
+			// Counts the missing parents as well, which are then sure to fit
+			if state.exceeds_max_files(&key) {
+				result.skipped.push(SkippedEntry { path: entry.path, reason: "ENOSPC: exceeds the file count quota".to_string() });
+				continue;
+			}
+			if !state.has_space_for(&key, entry.content.len() as u64) {
+				result.skipped.push(SkippedEntry { path: entry.path, reason: "ENOSPC: exceeds the remaining quota".to_string() });
+				continue;
+			}
+
+			let parent = key.rsplit_once('/').map_or("", |(parent, _)| parent);
+			let created = match state.create_directories(parent) {
+				Ok(created) => created,
+				Err(common::InsertError::NotADirectory(ancestor)) => {
+					result.skipped.push(SkippedEntry { path: entry.path, reason: format!("ENOTDIR: not a directory, '{}'", ancestor) });
+					continue;
+				}
+				Err(common::InsertError::MaxFiles) => {
+					result.skipped.push(SkippedEntry { path: entry.path, reason: "ENOSPC: exceeds the file count quota".to_string() });
+					continue;
+				}
+			};
+			for created in created {
+				state.emit_event(FSEvent::Created { path: created, object_type: common::ObjectType::Directory });
+			}
+
+			match existing {
+				// Directories listed after their content was created only update the metadata
+				Some(true) if entry.is_directory => {
+					if let Some(directory) = state.files.get_mut(&key) {
+						directory.mtime = entry.mtime;
+						directory.mode = entry.mode.or(directory.mode);
+					}
+					result.imported.push(key);
+					continue;
+				}
+				Some(_) => {
+					state.remove_tree(&key);
+					replaced.push(key.clone());
+				}
+				None => {}
+			}
+
+			let file = common::VirtualFile {
+				size: entry.content.len() as u64,
+				content: Arc::new(entry.content.into()),
+				is_directory: entry.is_directory,
+				mtime: entry.mtime,
+				mode: entry.mode,
+				..Default::default()
+			};
+			let object_type = file.get_type();
+			state.insert_entry(&key, file).map_err(|e| insert_error(e, &key))?;
+			state.emit_event(FSEvent::Created { path: key.clone(), object_type });
+			result.imported.push(key);
+		}
+		drop(state);
+
+		if !replaced.is_empty() {
+			self.inner.lock().await.invalidate_paths(&replaced);
+		}
+		Ok(result)
+	}
+
+	/// Only takes read locks, lazy files are fetched from their provider without being cached
+	#[napi]
+	pub async fn export_to_directory(&self, prefix: String, real_path: String, options: Option<ExportOptions>) -> Result<ExportResult> {
+		let overwrite = options.and_then(|options| options.overwrite).unwrap_or(false);
+		let prefix = self.state.read().await.files.key(&common::normalize_path(&prefix));
+		let root = PathBuf::from(&real_path);
+
+		// Snapshot the layout up front, contents are copied one file at a time below
+		let (directories, files) = {
+			let state = self.state.read().await;
+			if !prefix.is_empty() {
+				match state.files.get(&prefix) {
+					Some(file) if file.is_directory => {}
+					Some(_) => return Err(fs_error("ENOTDIR", "not a directory", &prefix)),
+					None => return Err(fs_error("ENOENT", "no such file or directory", &prefix)),
+				}
+			}
+
+			let descendant = format!("{}/", prefix);
+			let mut directories = Vec::new();
+			let mut files = Vec::new();
+			for (path, file) in state.files.iter() {
+				let relative = if prefix.is_empty() {
+					path.as_str()
+				} else {
+					match path.strip_prefix(&descendant) {
+						Some(relative) => relative,
+						None => continue,
+					}
+				};
+				if file.is_directory {
+					directories.push(relative.to_string());
+				} else {
+					files.push((path.clone(), relative.to_string()));
+				}
+			}
+			// Parents sort before their children
+			directories.sort();
+			(directories, files)
+		};
+
+		tokio::fs::create_dir_all(&root).await
+			.map_err(|e| Error::from_reason(format!("{}, '{}'", e, real_path)))?;
+
+		let mut result = ExportResult { files: 0, directories: 0, bytes: 0, skipped: Vec::new() };
+		for relative in directories {
+			let destination = root.join(&relative);
+			match tokio::fs::create_dir_all(&destination).await {
+				Ok(()) => result.directories += 1,
+				Err(e) => result.skipped.push(SkippedEntry { path: destination.to_string_lossy().into_owned(), reason: e.to_string() }),
+			}
+		}
+
+		for (path, relative) in files {
+			let destination = root.join(&relative);
+			let disk_path = destination.to_string_lossy().into_owned();
+			if !overwrite && tokio::fs::symlink_metadata(&destination).await.is_ok() {
+				result.skipped.push(SkippedEntry { path: disk_path, reason: "already exists".to_string() });
+				continue;
+			}
+
+			let (content, provider, mtime, is_symlink) = match self.state.read().await.files.get(&path) {
+				Some(file) => (file.content.clone(), file.provider.clone(), file.mtime, file.is_symlink),
+				// Removed since the layout was captured
+				None => continue,
+			};
+			let content = match provider {
+				Some(provider) => match provider(path.clone()).await {
+					Ok(content) => Arc::new(Content::from(content)),
+					Err(message) => {
+						result.skipped.push(SkippedEntry { path: disk_path, reason: message });
+						continue;
+					}
+				},
+				None => content,
+			};
+
+			let written = if is_symlink {
+				write_symlink(&destination, &content.to_vec(), mtime).await
+			} else {
+				write_file_with_mtime(&destination, &content, mtime).await
+			};
+
+			match written {
+				Ok(()) => {
+					result.files += 1;
+					result.bytes += content.size() as i64;
+				}
+				Err(e) => result.skipped.push(SkippedEntry { path: disk_path, reason: e.to_string() }),
+			}
+		}
+
+		Ok(result)
+	}
+
+	/// Entry names are relative to `prefix`, which defaults to the whole tree
+	#[napi(
+		ts_args_type = "destination: string | NodeJS.WritableStream, prefix?: string | undefined | null",
+		ts_return_type = "Promise<void>"
+	)]
+	pub fn export_to_tar(&self, env: Env, destination: Either<String, JsObject>, prefix: Option<String>) -> Result<JsObject> {
+		let state = self.state.clone();
+		let prefix = state.blocking_read().files.key(&common::normalize_path(&prefix.unwrap_or_default()));
+
+		match destination {
+			Either::A(path) => env.spawn_future(async move {
+				let file = std::fs::File::create(&path)
+					.map_err(|e| Error::from_reason(format!("{}, '{}'", e, path)))?;
+				let mut writer = archive::write_tar(&state, &prefix, std::io::BufWriter::new(file)).await?;
+				std::io::Write::flush(&mut writer).map_err(|e| Error::from_reason(e.to_string()))
+			}),
+			Either::B(stream) => {
+				// Writing a falsy chunk ends the stream, resolving once it has finished
+				let wrap: JsFunction = env.run_script(
+					"(stream) => (chunk) => chunk ? void stream.write(chunk) : new Promise((resolve, reject) => { stream.once('error', reject); stream.end(() => resolve()); })"
+				)?;
+				let sink: JsFunction = wrap.call(None, &[stream])?.try_into()?;
+				let sink: archive::StreamSink = sink.create_threadsafe_function(16, |ctx: napi::threadsafe_function::ThreadSafeCallContext<Option<Vec<u8>>>| {
+					let chunk = match ctx.value {
+						Some(chunk) => ctx.env.create_buffer_with_data(chunk)?.into_raw().into_unknown(),
+						None => ctx.env.get_null()?.into_unknown(),
+					};
+					Ok(vec![chunk])
+				})?;
+
+				env.spawn_future(async move {
+					let writer = archive::write_tar(&state, &prefix, archive::StreamWriter::new(sink)).await?;
+					writer.end().await
+				})
+			}
+		}
+	}
+
+	#[napi(
+		ts_args_type = "path: string, stream: NodeJS.ReadableStream, options?: StreamOptions | undefined | null",
+		ts_return_type = "Promise<void>"
+	)]
+	pub fn add_file_from_stream(&self, env: Env, path: String, stream: JsObject, options: Option<StreamOptions>) -> Result<JsObject> {
+		// Checked again once the stream ends, failing early saves reading the whole stream
+		let key = {
+			let state = self.state.blocking_read();
+			let key = state.files.key(&entry_key(&path)?);
+			if state.files.get(&key).is_some_and(|file| file.is_directory) {
+				return Err(fs_error("EISDIR", "illegal operation on a directory", &path));
+			}
+			if state.exceeds_max_files(&key) {
+				return Err(fs_error("ENOSPC", "file count quota reached", &path));
+			}
+			key
+		};
+
+		let (deferred, promise) = env.create_deferred::<(), fn(Env) -> Result<()>>()?;
+		let ingest = Rc::new(StreamIngest {
+			state: self.state.clone(),
+			inner: self.inner.clone(),
+			path: key,
+			keep_partial: options.and_then(|options| options.keep_partial).unwrap_or(false),
+			staged: RefCell::new(Content::default()),
+			deferred: RefCell::new(Some(deferred)),
+		});
+
+		let on_data = {
+			let ingest = ingest.clone();
+			env.create_function_from_closure("onData", move |ctx| {
+				if ingest.is_settled() {
+					return Ok(());
+				}
+
+				let chunk = ctx.get::<JsUnknown>(0)?;
+				let result = if chunk.is_buffer()? {
+					ingest.append(&unsafe { chunk.cast::<JsBuffer>() }.into_value()?)
+				} else {
+					Err(fs_error("EINVAL", "stream chunks must be Buffers", &ingest.path))
+				};
+
+				if let Err(error) = result {
+					ingest.fail(error);
+					// Listeners are invoked with the stream as `this`, stop it from producing more data
+					let stream = ctx.this::<JsObject>()?;
+					let destroy: JsFunction = stream.get_named_property("destroy")?;
+					destroy.call_without_args(Some(&stream))?;
+				}
+				Ok(())
+			})?
+		};
+		let on_end = {
+			let ingest = ingest.clone();
+			env.create_function_from_closure("onEnd", move |_| {
+				ingest.finish();
+				Ok(())
+			})?
+		};
+		let on_error = env.create_function_from_closure("onError", move |ctx| {
+			let reason = ctx.get::<JsUnknown>(0)?.coerce_to_string()?.into_utf8()?.into_owned()?;
+			ingest.fail(Error::from_reason(reason));
+			Ok(())
+		})?;
+
+		let on: JsFunction = stream.get_named_property("on")?;
+		on.call(Some(&stream), &[env.create_string("data")?.into_unknown(), on_data.into_unknown()])?;
+		on.call(Some(&stream), &[env.create_string("end")?.into_unknown(), on_end.into_unknown()])?;
+		on.call(Some(&stream), &[env.create_string("error")?.into_unknown(), on_error.into_unknown()])?;
+
+		Ok(promise)
+	}
+
+	#[napi(
+		ts_args_type = "path: string, size: number, provider: (path: string) => Buffer | Promise<Buffer>",
+		ts_return_type = "Promise<void>"
+	)]
+	pub fn add_lazy_file(&self, env: Env, path: String, size: i64, provider: JsFunction) -> Result<JsObject> {
+		if size < 0 {
+			return Err(fs_error("EINVAL", "size must not be negative", &path));
+		}
+
+		// Always hand back a promise so synchronous throws and bad return values surface as
+		// rejections instead of tripping the fatal path of the threadsafe function
+		let wrap: JsFunction = env.run_script("(provider) => (path) => new Promise((resolve) => resolve(provider(path)))")?;
+		let provider: JsFunction = wrap.call(None, &[provider])?.try_into()?;
+
+		let mut tsfn: ThreadsafeFunction<String, ErrorStrategy::Fatal> =
+			provider.create_threadsafe_function(0, |ctx| Ok(vec![ctx.value]))?;
+		// A registered provider alone should not keep the process alive
+		tsfn.unref(&env)?;
+
+		let provider: common::ContentProvider = Arc::new(move |path| {
+			let tsfn = tsfn.clone();
+			Box::pin(async move {
+				let promise = tsfn.call_async::<Promise<Buffer>>(path).await.map_err(|e| e.reason)?;
+				let content = promise.await.map_err(|e| e.reason)?;
+				Ok(content.to_vec())
+			})
+		});
+
+		let state = self.state.clone();
+		let key = entry_key(&path)?;
+		env.spawn_future(async move {
+			let mut state = state.write().await;
+			let key = state.files.key(&key);
+			if !state.has_space_for(&key, size as u64) {
+				return Err(fs_error("ENOSPC", "no space left on device", &path));
+			}
+			if state.exceeds_max_files(&key) {
+				return Err(fs_error("ENOSPC", "file count quota reached", &path));
+			}
+			ensure_parent(&mut state, &key, true)?;
+
+			// Content stays empty until the first read, the announced size is reported meanwhile
+			state.insert_entry(&key, common::VirtualFile {
+				size: size as u64,
+				provider: Some(provider),
+				..Default::default()
+			}).map_err(|e| insert_error(e, &key))?;
+			state.emit_event(FSEvent::Created { path: key, object_type: common::ObjectType::File });
+			Ok(())
+		})
+	}
+
+	#[napi]
+	pub async fn append_file(&self, path: String, data: Buffer, create: Option<bool>) -> Result<()> {
+		let key = entry_key(&path)?;
+		if let Err(message) = common::materialize(&self.state, &key).await {
+			return Err(fs_error("EIO", &message, &path));
+		}
+
+		let mut state = self.state.write().await;
+		let key = state.files.key(&key);
+
+		match state.files.get(&key) {
+			Some(file) if file.is_directory => return Err(fs_error("EISDIR", "illegal operation on a directory", &path)),
+			Some(_) => {}
+			None if create.unwrap_or(false) => {}
+			None => return Err(fs_error("ENOENT", "no such file or directory", &path)),
 		}
+
+		let len = data.len() as u64;
+		let content_bytes = state.files.get(&key).map_or(len, |file| file.content_bytes_after_write(file.size, len, state.quota_logical_size));
+		if !state.has_space_for(&key, content_bytes) {
+			return Err(fs_error("ENOSPC", "no space left on device", &path));
+		}
+		if state.exceeds_max_files(&key) {
+			return Err(fs_error("ENOSPC", "file count quota reached", &path));
+		}
+
+		let now = std::time::SystemTime::now();
+		if let Some(file) = state.files.get_mut(&key) {
+			let offset = file.size;
+			file.write_at(offset, &data);
+			file.mtime = now;
+			state.emit_event(FSEvent::Modified { path: key.clone(), object_type: common::ObjectType::File });
+			drop(state);
+
+			self.inner.lock().await.invalidate_paths(&[key]);
+		} else {
+			ensure_parent(&mut state, &key, true)?;
+			state.insert_entry(&key, common::VirtualFile {
+				content: Arc::new(data.to_vec().into()),
+				size: data.len() as u64,
+				is_directory: false,
+				mtime: now,
+				..Default::default()
+			}).map_err(|e| insert_error(e, &key))?;
+			state.emit_event(FSEvent::Created { path: key, object_type: common::ObjectType::File });
+		}
+		Ok(())
 	}
 
 	#[napi]
-	pub async fn mount(&self, path: String, total_space_bytes: i64) -> Result<()> {
-		if total_space_bytes <= 0 {
-			return Err(Error::from_reason("total_space_bytes must be greater than 0"));
+	pub async fn write_file_at(&self, path: String, offset: i64, data: Buffer) -> Result<i64> {
+		let key = entry_key(&path)?;
+		// Before the provider is asked for content the write would not use
+		if offset < 0 {
+			return Err(fs_error("EINVAL", "offset must not be negative", &path));
+		}
+		if let Err(message) = common::materialize(&self.state, &key).await {
+			return Err(fs_error("EIO", &message, &path));
 		}
 
-		let mount_path = PathBuf::from(path);
-		*self.mount_path.lock().await = Some(mount_path.clone());
+		let mut state = self.state.write().await;
+		let key = state.files.key(&key);
+
+		let content_bytes = match state.files.get(&key) {
+			Some(file) if file.is_directory => return Err(fs_error("EISDIR", "illegal operation on a directory", &path)),
+			Some(file) => file.content_bytes_after_write(offset as u64, data.len() as u64, state.quota_logical_size),
+			None => return Err(fs_error("ENOENT", "no such file or directory", &path)),
+		};
+		if !state.has_space_for(&key, content_bytes) {
+			return Err(fs_error("ENOSPC", "no space left on device", &path));
+		}
+		let Some(file) = state.files.get_mut(&key) else {
+			return Err(fs_error("ENOENT", "no such file or directory", &path));
+		};
+
+		file.write_at(offset as u64, &data);
+		file.mtime = std::time::SystemTime::now();
+		state.emit_event(FSEvent::Modified { path: key.clone(), object_type: common::ObjectType::File });
+		drop(state);
 
-		let (tx, rx) = tokio::sync::oneshot::channel();
-		*self.unmount_sender.lock().await = Some(tx);
+		self.inner.lock().await.invalidate_paths(&[key]);
+		Ok(data.len() as i64)
+	}
+
+	#[napi]
+	pub async fn set_content(&self, path: String, content: Buffer) -> Result<()> {
+		// Copied before taking the lock, which large contents would otherwise hold for the whole copy
+		let content = Arc::new(Content::from(content.to_vec()));
+		let mut state = self.state.write().await;
+		let key = state.files.key(&entry_key(&path)?);
 
-		// Configure the filesystem before spawning the thread
-		{
-			let mut fs = self.inner.lock().await;
-			*fs = FSImpl::with_size(
-				self.state.clone(),
-				total_space_bytes as u64,
-				1024 * 1024 // Default max files, not exposed to JS
-			);
+		match state.files.get(&key) {
+			Some(file) if file.is_directory => return Err(fs_error("EISDIR", "illegal operation on a directory", &path)),
+			Some(_) => {}
+			None => return Err(fs_error("ENOENT", "no such file or directory", &path)),
 		}
+		if !state.has_space_for(&key, content.size()) {
+			return Err(fs_error("ENOSPC", "no space left on device", &path));
+		}
+		let Some(file) = state.files.get_mut(&key) else {
+			return Err(fs_error("ENOENT", "no such file or directory", &path));
+		};
+
+		file.size = content.size();
+		file.content = content;
+		file.mtime = std::time::SystemTime::now();
+		file.provider = None;
+		state.emit_event(FSEvent::Modified { path: key.clone(), object_type: common::ObjectType::File });
+		drop(state);
+
+		self.inner.lock().await.invalidate_paths(&[key]);
+		Ok(())
+	}
+
+	#[napi(
+		ts_args_type = "path: string, times: { mtime?: number | Date, atime?: number | Date }",
+		ts_return_type = "Promise<void>"
+	)]
+	pub fn set_times(&self, env: Env, path: String, times: JsObject) -> Result<JsObject> {
+		let mtime = get_time_property(&times, "mtime")?;
+		let atime = get_time_property(&times, "atime")?;
 
+		let state = self.state.clone();
 		let inner = self.inner.clone();
-		std::thread::spawn(move || {
-			let rt = tokio::runtime::Runtime::new().unwrap();
-			rt.block_on(async {
-				inner.lock().await.mount(&mount_path).await?;
-				rx.await.ok();
-				let mount_path = mount_path.clone();
-				inner.lock().await.unmount(&mount_path).await
-			}).unwrap_or_else(|e| eprintln!("Mount error: {}", e));
-		});
+		let key = entry_key(&path)?;
+		env.spawn_future(async move {
+			let mut state = state.write().await;
+			let file = state.files.get_mut(&key)
+				.ok_or_else(|| fs_error("ENOENT", "no such file or directory", &path))?;
 
+			if let Some(mtime) = mtime {
+				file.mtime = mtime;
+			}
+			if let Some(atime) = atime {
+				file.atime = atime;
+			}
+			drop(state);
+
+			inner.lock().await.invalidate_paths(&[key]);
+			Ok(())
+		})
+	}
+
+	/// Permission bits are only enforced on Unix, ProjFS has no equivalent
+	#[napi]
+	pub async fn set_mode(&self, path: String, mode: u32) -> Result<()> {
+		if mode > 0o7777 {
+			return Err(fs_error("EINVAL", "invalid mode", &path));
+		}
+
+		let mut state = self.state.write().await;
+		let key = state.files.key(&entry_key(&path)?);
+		match state.files.get_mut(&key) {
+			Some(file) => file.mode = Some(mode as u16),
+			None => return Err(fs_error("ENOENT", "no such file or directory", &path)),
+		}
+		drop(state);
+
+		self.inner.lock().await.invalidate_paths(&[key]);
 		Ok(())
 	}
 
+	/// Ownership is only reported on Unix, an unset id leaves the current owner in place
 	#[napi]
-	pub async fn unmount(&self) -> Result<()> {
-		if let Some(sender) = self.unmount_sender.lock().await.take() {
-			sender.send(()).ok();
+	pub async fn set_owner(&self, path: String, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+		let mut state = self.state.write().await;
+		let key = state.files.key(&entry_key(&path)?);
+		match state.files.get_mut(&key) {
+			Some(file) => {
+				file.uid = uid.or(file.uid);
+				file.gid = gid.or(file.gid);
+			}
+			None => return Err(fs_error("ENOENT", "no such file or directory", &path)),
 		}
+		drop(state);
+
+		self.inner.lock().await.invalidate_paths(&[key]);
 		Ok(())
 	}
 
+	/// Read-only entries reject writes and deletions made through the mount, the methods of this class are unaffected
 	#[napi]
-	pub async fn add_file(&self, path: String, content: Buffer) -> Result<()> {
+	pub async fn set_readonly(&self, path: String, readonly: bool) -> Result<()> {
 		let mut state = self.state.write().await;
+		let key = state.files.key(&entry_key(&path)?);
+		match state.files.get_mut(&key) {
+			Some(file) => file.readonly = readonly,
+			None => return Err(fs_error("ENOENT", "no such file or directory", &path)),
+		}
+		drop(state);
 
-		// Calculate current total size
-		let total_size: u64 = state.files.values()
-			.map(|file| file.size)
-			.sum();
+		self.inner.lock().await.invalidate_paths(&[key]);
+		Ok(())
+	}
 
-		// Get the configured size limit
-		let size_limit = self.inner.lock().await.total_space_bytes;
+	/// Protected entries cannot be deleted through the mount, nor replaced by a rename, but can still be written
+	#[napi]
+	pub async fn set_protected(&self, path: String, protect: bool) -> Result<()> {
+		let mut state = self.state.write().await;
+		let key = state.files.key(&entry_key(&path)?);
+		match state.files.get_mut(&key) {
+			Some(file) => file.protected = protect,
+			None => return Err(fs_error("ENOENT", "no such file or directory", &path)),
+		}
+		Ok(())
+	}
 
-		// Check if adding this file would exceed the limit
-		if total_size + content.len() as u64 > size_limit {
-			return Err(Error::from_reason("No space left on device"));
+	/// Overrides the cache mount option for one file, e.g. "direct" for content replaced while readers have it open.
+	/// Null reverts to the mount's policy. Applies to opens made after the call, FUSE only
+	#[napi(ts_args_type = "path: string, policy: 'auto' | 'keep' | 'direct' | null")]
+	pub async fn set_cache_policy(&self, path: String, policy: Option<String>) -> Result<()> {
+		let policy = policy.as_deref().map(parse_cache_policy).transpose()?;
+		let mut state = self.state.write().await;
+		match state.files.get_mut(&entry_key(&path)?) {
+			Some(file) => {
+				file.cache = policy;
+				Ok(())
+			}
+			None => Err(fs_error("ENOENT", "no such file or directory", &path)),
+		}
+	}
+
+	/// Extended attributes are only exposed through the mount on Unix. Resolves to null when the entry has no such attribute
+	#[napi]
+	pub async fn get_xattr(&self, path: String, name: String) -> Result<Option<Buffer>> {
+		let state = self.state.read().await;
+		match state.files.get(&entry_key(&path)?) {
+			Some(file) => Ok(file.xattrs.get(&name).map(|value| value.clone().into())),
+			None => Err(fs_error("ENOENT", "no such file or directory", &path)),
 		}
+	}
 
-		state.files.insert(path.clone(), common::VirtualFile {
-			content: content.to_vec(),
-			size: content.len() as u64,
-			is_directory: false,
-			mtime: std::time::SystemTime::now(),
-		});
-		state.emit_event(FSEvent::Created { path, object_type: common::ObjectType::File });
+	#[napi]
+	pub async fn set_xattr(&self, path: String, name: String, value: Buffer) -> Result<()> {
+		let mut state = self.state.write().await;
+		match state.files.get_mut(&entry_key(&path)?) {
+			Some(file) => {
+				file.xattrs.insert(name, value.to_vec());
+				Ok(())
+			}
+			None => Err(fs_error("ENOENT", "no such file or directory", &path)),
+		}
+	}
+
+	/// Sorted attribute names
+	#[napi]
+	pub async fn list_xattrs(&self, path: String) -> Result<Vec<String>> {
+		let state = self.state.read().await;
+		match state.files.get(&entry_key(&path)?) {
+			Some(file) => {
+				let mut names: Vec<String> = file.xattrs.keys().cloned().collect();
+				names.sort();
+				Ok(names)
+			}
+			None => Err(fs_error("ENOENT", "no such file or directory", &path)),
+		}
+	}
+
+	#[napi]
+	pub async fn remove_xattr(&self, path: String, name: String) -> Result<()> {
+		let mut state = self.state.write().await;
+		match state.files.get_mut(&entry_key(&path)?) {
+			Some(file) => match file.xattrs.remove(&name) {
+				Some(_) => Ok(()),
+				None => Err(fs_error("ENODATA", "no such attribute", &path)),
+			},
+			None => Err(fs_error("ENOENT", "no such file or directory", &path)),
+		}
+	}
+
+	/// On Windows the link is projected as a regular file whose content is the target path
+	#[napi]
+	pub async fn add_symlink(&self, path: String, target: String) -> Result<()> {
+		let mut state = self.state.write().await;
+		let key = state.files.key(&entry_key(&path)?);
+
+		if !state.has_space_for(&key, target.len() as u64) {
+			return Err(fs_error("ENOSPC", "no space left on device", &path));
+		}
+		if state.exceeds_max_files(&key) {
+			return Err(fs_error("ENOSPC", "file count quota reached", &path));
+		}
+		ensure_parent(&mut state, &key, true)?;
+
+		// Like the FUSE symlink callback, the target is stored verbatim as the content
+		state.insert_entry(&key, common::VirtualFile {
+			size: target.len() as u64,
+			content: Arc::new(target.into_bytes().into()),
+			is_symlink: true,
+			..Default::default()
+		}).map_err(|e| insert_error(e, &key))?;
+		state.emit_event(FSEvent::Created { path: key, object_type: common::ObjectType::Symlink });
 		Ok(())
 	}
 
 	#[napi]
-	pub async fn add_directory(&self, path: String) -> Result<()> {
+	pub async fn read_symlink(&self, path: String) -> Result<String> {
+		let state = self.state.read().await;
+		match state.files.get(&common::normalize_path(&path)) {
+			Some(file) if file.is_symlink => Ok(String::from_utf8_lossy(&file.content.to_vec()).into_owned()),
+			Some(_) => Err(fs_error("EINVAL", "not a symbolic link", &path)),
+			None => Err(fs_error("ENOENT", "no such file or directory", &path)),
+		}
+	}
+
+	/// Rejects with EEXIST when an entry, directory or not, is already at `path`
+	#[napi]
+	pub async fn add_directory(&self, path: String, options: Option<AddOptions>) -> Result<()> {
+		let key = entry_key(&path)?;
 		let mut state = self.state.write().await;
-		state.files.insert(path.clone(), common::VirtualFile {
-			content: vec![],
+		let path = state.files.key(&key);
+		if state.files.contains_key(&path) {
+			return Err(fs_error("EEXIST", "file already exists", &path));
+		}
+		if !state.has_space_for(&path, 0) {
+			return Err(fs_error("ENOSPC", "no space left on device", &path));
+		}
+		if state.exceeds_max_files(&path) {
+			return Err(fs_error("ENOSPC", "file count quota reached", &path));
+		}
+		ensure_parent(&mut state, &path, options.and_then(|options| options.create_parents).unwrap_or(true))?;
+
+		state.insert_entry(&path, common::VirtualFile {
+			content: Arc::default(),
 			size: 0,
 			is_directory: true,
 			mtime: std::time::SystemTime::now(),
-		});
+			..Default::default()
+		}).map_err(|e| insert_error(e, &path))?;
 		state.emit_event(FSEvent::Created { path, object_type: common::ObjectType::Directory });
 		Ok(())
 	}
 
+	/// Rejects with EFBIG rather than returning more than 2GB, larger files have to be read in ranges
+	#[napi]
+	pub async fn read_file(&self, path: String, offset: Option<i64>, length: Option<i64>) -> Result<Buffer> {
+		let key = common::normalize_path(&path);
+		if let Err(message) = common::materialize(&self.state, &key).await {
+			return Err(fs_error("EIO", &message, &path));
+		}
+
+		// Copied out once the state lock is released
+		let content = match self.state.read().await.files.get(&key) {
+			Some(file) if file.is_directory => return Err(fs_error("EISDIR", "illegal operation on a directory", &path)),
+			Some(file) => file.content.clone(),
+			None => return Err(fs_error("ENOENT", "no such file or directory", &path)),
+		};
+
+		let offset = offset.unwrap_or(0);
+		if offset < 0 || length.is_some_and(|length| length < 0) {
+			return Err(fs_error("EINVAL", "offset and length must not be negative", &path));
+		}
+
+		// The range is clamped to the file so peeking past EOF yields a short buffer
+		let length = length.map_or(content.size(), |length| length as u64)
+			.min(content.size().saturating_sub(offset as u64));
+		if length > MAX_READ_BYTES {
+			return Err(fs_error("EFBIG", "file too large to read at once, read it in ranges", &path));
+		}
+		Ok(content.read(offset as u64, length as usize).into())
+	}
+
+	#[napi]
+	pub async fn stat(&self, path: String) -> Result<FileStat> {
+		let state = self.state.read().await;
+		let key = common::normalize_path(&path);
+		match state.files.get(&key) {
+			Some(file) => Ok(FileStat {
+				size: file.size as i64,
+				is_directory: file.is_directory,
+				mtime: system_time_to_ms(file.mtime),
+				atime: system_time_to_ms(file.atime),
+				mode: file.perm() as u32,
+				uid: file.uid,
+				gid: file.gid,
+				readonly: file.readonly,
+				protected: file.protected,
+				ino: BigInt::from(state.files.ino(&key).unwrap_or_default()),
+			}),
+			None => Err(fs_error("ENOENT", "no such file or directory", &path)),
+		}
+	}
+
+	/// POSIX locks processes hold on the entry through the mount, sorted by start offset
+	#[napi]
+	pub async fn get_locks(&self, path: String) -> Result<Vec<LockInfo>> {
+		let state = self.state.read().await;
+		let ino = match state.files.ino(&entry_key(&path)?) {
+			Some(ino) => ino,
+			None => return Err(fs_error("ENOENT", "no such file or directory", &path)),
+		};
+		Ok(state.locks.locks(ino).into_iter()
+			.map(|lock| LockInfo {
+				pid: lock.pid,
+				start: lock.start as i64,
+				// Locks to the end of the file reach OFFSET_MAX
+				end: (lock.end < i64::MAX as u64).then_some(lock.end as i64),
+				exclusive: lock.exclusive,
+			})
+			.collect())
+	}
+
+	#[napi]
+	pub async fn exists(&self, path: String) -> Result<Option<String>> {
+		let state = self.state.read().await;
+		Ok(state.files.get(&common::normalize_path(&path))
+			.map(|file| file.get_type().as_str().to_string()))
+	}
+
+	#[napi]
+	pub async fn list_directory(&self, path: String) -> Result<Vec<DirectoryEntry>> {
+		let state = self.state.read().await;
+		let dir_path = state.files.key(&common::normalize_path(&path));
+
+		if !dir_path.is_empty() {
+			match state.files.get(&dir_path) {
+				Some(file) if !file.is_directory => return Err(fs_error("ENOTDIR", "not a directory", &path)),
+				Some(_) => {}
+				None => return Err(fs_error("ENOENT", "no such file or directory", &path)),
+			}
+		}
+
+		Ok(state.files.iter()
+			.filter(|(child_path, _)| common::is_direct_child(&dir_path, child_path))
+			.map(|(child_path, file)| DirectoryEntry {
+				name: child_path.rsplit('/').next().unwrap().to_string(),
+				path: child_path.clone(),
+				is_directory: file.is_directory,
+				size: file.size as i64,
+				mtime: system_time_to_ms(file.mtime),
+			})
+			.collect())
+	}
+
+	/// `*` stops at path separators while `**` spans any number of directories
+	#[napi(ts_return_type = "Promise<Array<string> | Array<DirectoryEntry>>")]
+	pub async fn find(&self, pattern: String, options: Option<FindOptions>) -> Result<Either<Vec<String>, Vec<DirectoryEntry>>> {
+		let options = options.unwrap_or(FindOptions { limit: None, object_type: None, with_metadata: None });
+		let state = self.state.read().await;
+		let matcher = globset::GlobBuilder::new(&common::normalize_path(&pattern))
+			.literal_separator(true)
+			.case_insensitive(state.files.is_case_insensitive())
+			.build()
+			.map_err(|e| Error::new(Status::InvalidArg, e.to_string()))?
+			.compile_matcher();
+		if let Some(object_type) = &options.object_type {
+			if !["file", "directory", "symlink"].contains(&object_type.as_str()) {
+				return Err(Error::new(Status::InvalidArg, format!("unknown object type '{}'", object_type)));
+			}
+		}
+
+		let mut matches: Vec<(&String, &common::VirtualFile)> = state.files.iter()
+			.filter(|(_, file)| options.object_type.as_deref().is_none_or(|object_type| file.get_type().as_str() == object_type))
+			.filter(|(path, _)| matcher.is_match(path.as_str()))
+			.collect();
+		matches.sort_by_key(|(path, _)| *path);
+		matches.truncate(options.limit.map_or(usize::MAX, |limit| limit as usize));
+
+		if !options.with_metadata.unwrap_or(false) {
+			return Ok(Either::A(matches.into_iter().map(|(path, _)| path.clone()).collect()));
+		}
+		Ok(Either::B(matches.into_iter()
+			.map(|(path, file)| DirectoryEntry {
+				name: path.rsplit('/').next().unwrap().to_string(),
+				path: path.clone(),
+				is_directory: file.is_directory,
+				size: file.size as i64,
+				mtime: system_time_to_ms(file.mtime),
+			})
+			.collect()))
+	}
+
+	#[napi]
+	pub async fn rename_path(&self, old_path: String, new_path: String, overwrite: Option<bool>) -> Result<()> {
+		let mut state = self.state.write().await;
+		let old_key = state.files.key(&entry_key(&old_path)?);
+		let requested_key = entry_key(&new_path)?;
+		let new_key = state.files.key(&requested_key);
+
+		if !state.files.contains_key(&old_key) {
+			return Err(fs_error("ENOENT", "no such file or directory", &old_path));
+		}
+		if new_key.starts_with(&format!("{}/", old_key)) {
+			return Err(fs_error("EINVAL", "cannot move a directory into itself", &new_path));
+		}
+		// Replacing an ancestor would remove the source along with it
+		if old_key.starts_with(&format!("{}/", new_key)) {
+			return Err(fs_error("ENOTEMPTY", "directory not empty", &new_path));
+		}
+		ensure_parent(&mut state, &new_key, false)?;
+		if old_key == new_key {
+			// In case-insensitive mode the casing alone may change, the entry is then renamed onto itself
+			if requested_key == old_key {
+				return Ok(());
+			}
+		} else if state.files.contains_key(&new_key) {
+			if !overwrite.unwrap_or(false) {
+				return Err(fs_error("EEXIST", "file already exists", &new_path));
+			}
+			if let Some(replaced) = state.remove_tree(&new_key) {
+				state.emit_event(FSEvent::Deleted { path: new_key.clone(), object_type: replaced.get_type() });
+			}
+		}
+
+		// A single event covers the whole subtree, children are moved silently
+		let Some(object_type) = state.move_entry(&old_key, &requested_key) else {
+			return Err(fs_error("ENOENT", "no such file or directory", &old_path));
+		};
+		let new_key = state.files.key(&requested_key);
+		state.emit_renamed(old_key, new_key, object_type);
+		Ok(())
+	}
+
 	#[napi]
-	pub async fn remove_path(&self, path: String) -> Result<()> {
+	pub async fn copy_path(&self, src: String, dst: String, options: Option<CopyOptions>) -> Result<()> {
 		let mut state = self.state.write().await;
-		if let Some(file) = state.files.remove(&path) {
-			state.emit_event(FSEvent::Deleted { path, object_type: file.get_type() });
+		let src_key = state.files.key(&entry_key(&src)?);
+		let dst_key = state.files.key(&entry_key(&dst)?);
+		let recursive = options.as_ref().and_then(|options| options.recursive).unwrap_or(false);
+		let create_parents = options.and_then(|options| options.create_parents).unwrap_or(true);
+
+		let source = match state.files.get(&src_key) {
+			Some(file) => file,
+			None => return Err(fs_error("ENOENT", "no such file or directory", &src)),
+		};
+		if source.is_directory && !recursive {
+			return Err(fs_error("EISDIR", "recursive option is required to copy a directory", &src));
+		}
+		if dst_key == src_key || dst_key.starts_with(&format!("{}/", src_key)) {
+			return Err(fs_error("EINVAL", "cannot copy a directory into itself", &dst));
+		}
+		if state.files.contains_key(&dst_key) {
+			return Err(fs_error("EEXIST", "file already exists", &dst));
+		}
+
+		// Collect everything up front so the quota can be checked before anything is inserted
+		let mut copies = vec![(dst_key.clone(), source.clone())];
+		if source.is_directory {
+			let prefix = format!("{}/", src_key);
+			let mut children: Vec<_> = state.files.iter()
+				.filter(|(path, _)| path.starts_with(&prefix))
+				.map(|(path, file)| (format!("{}/{}", dst_key, &path[prefix.len()..]), file.clone()))
+				.collect();
+			// Parents sort before their children so Created events arrive top-down
+			children.sort_by(|(a, _), (b, _)| a.cmp(b));
+			copies.extend(children);
+		}
+
+		// The missing parents of `dst` are created too, and count like any other entry
+		let parents = state.new_entries(&dst_key) - 1;
+		let copy_size: u64 = copies.iter().map(|(_, file)| file.used_bytes(state.quota_logical_size)).sum();
+		if !state.has_space(0, copy_size + parents * common::ENTRY_OVERHEAD_BYTES) {
+			return Err(fs_error("ENOSPC", "no space left on device", &dst));
+		}
+		if !state.has_entries_for(0, copies.len() as u64 + parents) {
+			return Err(fs_error("ENOSPC", "file count quota reached", &dst));
+		}
+		ensure_parent(&mut state, &dst_key, create_parents)?;
+
+		let now = std::time::SystemTime::now();
+		for (path, mut file) in copies {
+			file.mtime = now;
+			let object_type = file.get_type();
+			state.insert_entry(&path, file).map_err(|e| insert_error(e, &path))?;
+			state.emit_event(FSEvent::Created { path, object_type });
 		}
 		Ok(())
 	}
 
-	#[napi(js_name = "on")]
-	pub fn on_fs_event(&self, callback: JsFunction) -> Result<()> {
-		let state = self.state.clone();
-		let tsfn: ThreadsafeFunction<_, napi::threadsafe_function::ErrorStrategy::Fatal> =
-			callback.create_threadsafe_function(0, |ctx| {
-				let event = ctx.value;
-				Ok(vec![event])
-			})?;
-
-		std::thread::spawn(move || {
-			let rt = tokio::runtime::Runtime::new().unwrap();
-			rt.block_on(async move {
-				let state = state.read().await;
-				let mut rx = state.subscribe_to_events();
-				drop(state);
+	/// Subtrees larger than 1000 entries only produce a Deleted event for `path` itself
+	#[napi]
+	pub async fn remove_path(&self, path: String, options: Option<RemoveOptions>) -> Result<()> {
+		let key = entry_key(&path)?;
+		let recursive = options.and_then(|options| options.recursive).unwrap_or(true);
+		let mut state = self.state.write().await;
+		let key = state.files.key(&key);
+		if !state.files.contains_key(&key) {
+			return Ok(());
+		}
 
-				while let Ok(event) = rx.recv().await {
-					let (event_type, path, object_type) = match event {
-						FSEvent::Created { path, object_type } => ("created", path, object_type),
-						FSEvent::Modified { path, object_type } => ("modified", path, object_type),
-						FSEvent::Deleted { path, object_type } => ("deleted", path, object_type),
-					};
+		let prefix = format!("{}/", key);
+		let mut descendants: Vec<(String, common::ObjectType)> = state.files.iter()
+			.filter(|(child_path, _)| child_path.starts_with(&prefix))
+			.map(|(child_path, file)| (child_path.clone(), file.get_type()))
+			.collect();
+		if !recursive && !descendants.is_empty() {
+			return Err(fs_error("ENOTEMPTY", "directory not empty", &path));
+		}
 
-					let js_event = FileSystemEvent {
-						event_type: event_type.to_string(),
-						path,
-						object_type: match object_type {
-							common::ObjectType::File => "file".to_string(),
-							common::ObjectType::Directory => "directory".to_string(),
-						},
-					};
+		let file = match state.remove_tree(&key) {
+			Some(file) => file,
+			None => return Ok(()),
+		};
+		let mut removed: Vec<String> = descendants.iter().map(|(child_path, _)| child_path.clone()).collect();
+		if descendants.len() <= MAX_DELETED_EVENTS {
+			// Children before their parents, the order they would be removed in one by one
+			descendants.sort_by(|(a, _), (b, _)| b.cmp(a));
+			for (child_path, object_type) in descendants {
+				state.emit_event(FSEvent::Deleted { path: child_path, object_type });
+			}
+		}
+		state.emit_event(FSEvent::Deleted { path: key.clone(), object_type: file.get_type() });
+		drop(state);
 
-					let _ = tsfn.call(js_event, napi::threadsafe_function::ThreadsafeFunctionCallMode::Blocking);
-				}
-			});
+		removed.push(key);
+		self.inner.lock().await.invalidate_paths(&removed);
+		Ok(())
+	}
+
+	#[napi]
+	pub async fn clear(&self) -> Result<()> {
+		let mut state = self.state.write().await;
+		let paths: Vec<String> = state.files.drain().map(|(path, _)| path).collect();
+		state.emit_event(FSEvent::Cleared);
+		drop(state);
+
+		self.inner.lock().await.invalidate_paths(&paths);
+		Ok(())
+	}
+
+	/// Lazy files are fetched from their provider first so their content is captured
+	#[napi]
+	pub async fn snapshot(&self) -> Result<Buffer> {
+		let lazy_paths: Vec<String> = self.state.read().await.files.iter()
+			.filter(|(_, file)| file.provider.is_some())
+			.map(|(path, _)| path.clone())
+			.collect();
+		for path in lazy_paths {
+			common::materialize(&self.state, &path).await
+				.map_err(|message| fs_error("EIO", &message, &path))?;
+		}
+
+		let state = self.state.read().await;
+		let buffer = snapshot::encode(&state.files).map_err(Error::from_reason)?;
+		Ok(buffer.into())
+	}
+
+	/// Replaces every entry with the ones captured by snapshot()
+	#[napi]
+	pub async fn restore(&self, snapshot: Buffer) -> Result<()> {
+		let files = snapshot::decode(&snapshot).map_err(|message| Error::new(Status::InvalidArg, message))?;
+		// Paths come from the buffer, each has to be a normalized key under a directory of the snapshot
+		for path in files.keys() {
+			let valid = entry_key(path).is_ok_and(|key| &key == path) && match path.rsplit_once('/') {
+				Some((parent, _)) => files.get(parent).is_some_and(|file| file.is_directory),
+				None => true,
+			};
+			if !valid {
+				return Err(Error::new(Status::InvalidArg, format!("corrupt snapshot: invalid path '{}'", path)));
+			}
+		}
+
+		let mut state = self.state.write().await;
+		// Everything in place is replaced, so the restored entries alone have to fit
+		if !state.has_space(state.used_bytes(), files.values().map(|file| file.used_bytes(state.quota_logical_size)).sum()) {
+			return Err(Error::from_reason("ENOSPC: no space left on device"));
+		}
+		if !state.has_entries_for(state.files.len() as u64, files.len() as u64) {
+			return Err(Error::from_reason("ENOSPC: file count quota reached"));
+		}
+
+		// Both the previous and the restored paths may be cached by consumers
+		let mut paths: Vec<String> = state.files.keys().cloned().collect();
+		paths.extend(files.keys().filter(|path| !state.files.contains_key(path)).cloned());
+
+		state.files.replace(files);
+		state.forget_missing();
+		state.emit_event(FSEvent::Cleared);
+		if !state.files.is_empty() {
+			state.emit_event(FSEvent::BulkCreated);
+		}
+		drop(state);
+
+		self.inner.lock().await.invalidate_paths(&paths);
+		Ok(())
+	}
+
+	#[napi(ts_args_type = "resolver: ((path: string) => ResolvedPath | Buffer | null | Promise<ResolvedPath | Buffer | null>) | null")]
+	pub fn on_missing_path(&self, env: Env, resolver: Option<JsFunction>) -> Result<()> {
+		let resolver = match resolver {
+			Some(resolver) => resolver,
+			None => {
+				self.state.blocking_write().resolver = None;
+				return Ok(());
+			}
+		};
+
+		// Same promise normalization as lazy file providers
+		let wrap: JsFunction = env.run_script("(resolver) => (path) => new Promise((resolve) => resolve(resolver(path)))")?;
+		let resolver: JsFunction = wrap.call(None, &[resolver])?.try_into()?;
+
+		let mut tsfn: ThreadsafeFunction<String, ErrorStrategy::Fatal> =
+			resolver.create_threadsafe_function(0, |ctx| Ok(vec![ctx.value]))?;
+		tsfn.unref(&env)?;
+
+		let resolver: common::PathResolver = Arc::new(move |path| {
+			let tsfn = tsfn.clone();
+			Box::pin(async move {
+				let promise = tsfn.call_async::<Promise<Option<Either<Buffer, ResolvedPath>>>>(path).await.map_err(|e| e.reason)?;
+				let (is_directory, content) = match promise.await.map_err(|e| e.reason)? {
+					None => return Ok(None),
+					Some(Either::A(content)) => (false, Some(content)),
+					Some(Either::B(resolved)) => (resolved.is_directory.unwrap_or(false), resolved.content),
+				};
+
+				let content = match content {
+					Some(content) if !is_directory => content.to_vec(),
+					_ => Vec::new(),
+				};
+				Ok(Some(common::VirtualFile {
+					size: content.len() as u64,
+					content: Arc::new(content.into()),
+					is_directory,
+					..Default::default()
+				}))
+			})
 		});
 
+		let mut state = self.state.blocking_write();
+		state.resolver = Some(resolver);
+		// Answers from a previous resolver no longer apply
+		state.forget_missing();
 		Ok(())
 	}
+
+	/// Reads through the mount start producing "accessed" events, one per open handle on Unix
+	/// and one per hydrated file on Windows
+	#[napi]
+	pub fn enable_access_events(&self) {
+		self.state.blocking_read().set_access_events(true);
+	}
+
+	#[napi]
+	pub fn disable_access_events(&self) {
+		self.state.blocking_read().set_access_events(false);
+	}
+
+	/// `filter` is a path, matching it and everything below it, or a glob pattern
+	#[napi(ts_args_type = "filter: string, callback: (event: FileSystemEvent) => void")]
+	pub fn watch(&self, env: Env, filter: String, callback: JsFunction) -> Result<Watcher> {
+		let filter = WatchFilter::new(&filter, &self.state.blocking_read().files)?;
+		let filter = EventFilter { path: Some(filter), types: None };
+		let forwarder = EventForwarder::spawn(&env, &self.state, callback, filter, false)?;
+		Ok(Watcher { forwarder })
+	}
+
+	/// Returns an id to pass to off(). Events not matching `filter` never reach JS
+	#[napi(js_name = "on", ts_args_type = "callback: (event: FileSystemEvent) => void, filter?: EventFilterOptions | undefined | null")]
+	pub fn on_fs_event(&self, env: Env, callback: JsFunction, filter: Option<EventFilterOptions>) -> Result<u32> {
+		let filter = EventFilter::new(filter, &self.state.blocking_read().files)?;
+		let forwarder = EventForwarder::spawn(&env, &self.state, callback, filter, false)?;
+		Ok(self.add_listener(forwarder))
+	}
+
+	/// Called with the "error" events: failures of the mount, lazy file providers, the missing path
+	/// resolver or listeners falling behind. Returns an id to pass to off()
+	#[napi(ts_args_type = "callback: (error: FileSystemEvent) => void")]
+	pub fn on_error(&self, env: Env, callback: JsFunction) -> Result<u32> {
+		let filter = EventFilter { path: None, types: Some(vec!["error".to_string()]) };
+		let forwarder = EventForwarder::spawn(&env, &self.state, callback, filter, false)?;
+		Ok(self.add_listener(forwarder))
+	}
+
+	/// Removed after the first matching event, returns an id to pass to off() before that
+	#[napi(ts_args_type = "callback: (event: FileSystemEvent) => void, filter?: EventFilterOptions | undefined | null")]
+	pub fn once(&self, env: Env, callback: JsFunction, filter: Option<EventFilterOptions>) -> Result<u32> {
+		let filter = EventFilter::new(filter, &self.state.blocking_read().files)?;
+		let forwarder = EventForwarder::spawn(&env, &self.state, callback, filter, true)?;
+		Ok(self.add_listener(forwarder))
+	}
+
+	fn add_listener(&self, forwarder: EventForwarder) -> u32 {
+		let id = self.next_listener_id.fetch_add(1, Ordering::Relaxed);
+		let mut listeners = self.listeners.lock().unwrap();
+		// once() listeners end on their own, drop the ones that already fired
+		listeners.retain(|_, forwarder| !forwarder.is_finished());
+		listeners.insert(id, forwarder);
+		id
+	}
+
+	/// Async iterator over the events sent from now on: `for await (const event of fs.events())`
+	#[napi(ts_args_type = "options?: EventStreamOptions | undefined | null", ts_return_type = "EventStream")]
+	pub fn events(&self, env: Env, options: Option<EventStreamOptions>) -> Result<JsObject> {
+		let options = options.unwrap_or(EventStreamOptions { buffer_size: None, overflow: None, filter: None });
+		let drop_overflow = match options.overflow.as_deref() {
+			None | Some("wait") => false,
+			Some("drop") => true,
+			Some(overflow) => return Err(Error::new(Status::InvalidArg, format!("unknown overflow mode '{}'", overflow))),
+		};
+		let buffer_size = options.buffer_size.unwrap_or(1024).max(1) as usize;
+
+		// Subscribe before returning so events sent right after the call cannot be missed
+		let (filter, mut rx) = {
+			let state = self.state.blocking_read();
+			(EventFilter::new(options.filter, &state.files)?, state.subscribe_to_events())
+		};
+		let (sender, receiver) = tokio::sync::mpsc::channel(buffer_size);
+		let state = self.state.clone();
+		let (close_sender, mut close_receiver) = tokio::sync::oneshot::channel::<()>();
+
+		// Also ends when the stream is garbage collected, as that drops both the close sender and the receiver
+		spawn(async move {
+			loop {
+				let event = tokio::select! {
+					_ = &mut close_receiver => break,
+					event = rx.recv() => event,
+				};
+
+				match event {
+					Ok(record) if filter.matches(&record.event) => {
+						let event = to_js_event(record);
+						let sent = if drop_overflow {
+							!matches!(sender.try_send(event), Err(tokio::sync::mpsc::error::TrySendError::Closed(_)))
+						} else {
+							tokio::select! {
+								_ = &mut close_receiver => false,
+								sent = sender.send(event) => sent.is_ok(),
+							}
+						};
+						if !sent {
+							break;
+						}
+					}
+					Ok(_) => {}
+					Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => report_lag(&state, missed).await,
+					Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+				}
+			}
+		});
+
+		let stream = EventStream {
+			receiver: Arc::new(Mutex::new(Some(receiver))),
+			close_sender: std::sync::Mutex::new(Some(close_sender)),
+		}.into_instance(env)?;
+		// Classes cannot declare symbol keyed methods, make the stream its own async iterable here
+		let iterable: JsFunction = env.run_script("(stream) => { stream[Symbol.asyncIterator] = function () { return this }; return stream }")?;
+		iterable.call(None, &[stream.as_object(env)])?.try_into()
+	}
+
+	/// Resolves with the first matching event sent after the call, every field of `options` is optional
+	#[napi(ts_return_type = "Promise<FileSystemEvent>")]
+	pub fn wait_for_event(&self, env: Env, options: Option<WaitForEventOptions>) -> Result<JsObject> {
+		let options = options.unwrap_or(WaitForEventOptions { event_type: None, path: None, timeout_ms: None });
+		if let Some(event_type) = &options.event_type {
+			check_event_type(event_type)?;
+		}
+
+		// Subscribed synchronously for the same reason as in events()
+		let (path, mut rx) = {
+			let state = self.state.blocking_read();
+			let path = options.path.as_deref().map(|path| state.files.key(&common::normalize_path(path)));
+			(path, state.subscribe_to_events())
+		};
+		let wait = async move {
+			loop {
+				match rx.recv().await {
+					Ok(record) => {
+						let event = to_js_event(record);
+						let type_matches = options.event_type.as_ref().is_none_or(|event_type| *event_type == event.event_type);
+						let path_matches = path.as_ref().is_none_or(|path| *path == event.path);
+						if type_matches && path_matches {
+							return Ok(event);
+						}
+					}
+					Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {}
+					Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+						return Err(Error::from_reason("event channel closed"));
+					}
+				}
+			}
+		};
+
+		env.spawn_future(async move {
+			match options.timeout_ms {
+				Some(timeout) => tokio::time::timeout(std::time::Duration::from_millis(timeout as u64), wait).await
+					.map_err(|_| Error::from_reason("ETIMEDOUT: timed out waiting for event"))?,
+				None => wait.await,
+			}
+		})
+	}
+
+	/// Returns whether a listener was registered under this id
+	#[napi]
+	pub fn off(&self, id: u32) -> bool {
+		match self.listeners.lock().unwrap().remove(&id) {
+			Some(mut forwarder) => {
+				let was_active = !forwarder.is_finished();
+				forwarder.close();
+				was_active
+			}
+			None => false,
+		}
+	}
+
+	#[napi]
+	pub fn remove_all_listeners(&self) {
+		for (_, mut forwarder) in self.listeners.lock().unwrap().drain() {
+			forwarder.close();
+		}
+	}
 }
\ No newline at end of file
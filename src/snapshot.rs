@@ -0,0 +1,210 @@
+use crate::common::VirtualFile;
+use crate::content::Content;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAGIC: &[u8; 4] = b"NPFS";
+
+// Bump when the entry layout changes and keep decoding the older versions,
+// snapshots are meant to outlive the process that took them
+const VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize)]
+struct EntryV1 {
+	path: String,
+	content: Vec<u8>,
+	// Differs from the content length for directories created through the mount
+	size: u64,
+	is_directory: bool,
+	is_symlink: bool,
+	// Since the Unix epoch, earlier times are clamped to it
+	mtime: Duration,
+	atime: Duration,
+	mode: Option<u16>,
+	readonly: bool,
+}
+
+// Same as V1 with the content kept sparse, only its runs are stored, and the protected flag
+#[derive(Serialize, Deserialize)]
+struct EntryV2 {
+	path: String,
+	extents: Vec<(u64, Vec<u8>)>,
+	content_len: u64,
+	size: u64,
+	is_directory: bool,
+	is_symlink: bool,
+	mtime: Duration,
+	atime: Duration,
+	mode: Option<u16>,
+	readonly: bool,
+	protected: bool,
+}
+
+impl From<EntryV1> for EntryV2 {
+	fn from(entry: EntryV1) -> Self {
+		Self {
+			path: entry.path,
+			content_len: entry.content.len() as u64,
+			extents: if entry.content.is_empty() { Vec::new() } else { vec![(0, entry.content)] },
+			size: entry.size,
+			is_directory: entry.is_directory,
+			is_symlink: entry.is_symlink,
+			mtime: entry.mtime,
+			atime: entry.atime,
+			mode: entry.mode,
+			readonly: entry.readonly,
+			protected: false,
+		}
+	}
+}
+
+fn since_epoch(time: SystemTime) -> Duration {
+	time.duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+// Lazy files have to be materialized beforehand, a provider cannot be serialized
+pub fn encode(files: &HashMap<String, VirtualFile>) -> Result<Vec<u8>, String> {
+	let mut entries = Vec::with_capacity(files.len());
+	for (path, file) in files {
+		if file.provider.is_some() {
+			return Err(format!("'{}' has not been fetched from its provider", path));
+		}
+		entries.push(EntryV2 {
+			path: path.clone(),
+			extents: file.content.extents().map(|(start, chunk)| (start, chunk.to_vec())).collect(),
+			content_len: file.content.size(),
+			size: file.size,
+			is_directory: file.is_directory,
+			is_symlink: file.is_symlink,
+			mtime: since_epoch(file.mtime),
+			atime: since_epoch(file.atime),
+			mode: file.mode,
+			readonly: file.readonly,
+			protected: file.protected,
+		});
+	}
+
+	let mut buffer = Vec::new();
+	buffer.extend_from_slice(MAGIC);
+	buffer.extend_from_slice(&VERSION.to_le_bytes());
+	bincode::serialize_into(&mut buffer, &entries).map_err(|e| e.to_string())?;
+	Ok(buffer)
+}
+
+pub fn decode(buffer: &[u8]) -> Result<HashMap<String, VirtualFile>, String> {
+	if buffer.len() < 8 || &buffer[..4] != MAGIC {
+		return Err("not a snapshot".to_string());
+	}
+
+	let version = u32::from_le_bytes([buffer[4], buffer[5], buffer[6], buffer[7]]);
+	let entries: Vec<EntryV2> = match version {
+		1 => bincode::deserialize::<Vec<EntryV1>>(&buffer[8..]).map_err(|e| e.to_string())?
+			.into_iter().map(EntryV2::from).collect(),
+		2 => bincode::deserialize(&buffer[8..]).map_err(|e| e.to_string())?,
+		_ => return Err(format!("unsupported snapshot version {}", version)),
+	};
+
+	let mut files = HashMap::with_capacity(entries.len());
+	for entry in entries {
+		let mut content = Content::default();
+		for (start, chunk) in &entry.extents {
+			// Checked so a crafted extent cannot overflow the content bounds
+			match start.checked_add(chunk.len() as u64) {
+				Some(end) if end <= entry.content_len => content.write(*start, chunk),
+				_ => return Err("corrupt snapshot".to_string()),
+			}
+		}
+		content.set_len(entry.content_len);
+		let (Some(mtime), Some(atime)) = (UNIX_EPOCH.checked_add(entry.mtime), UNIX_EPOCH.checked_add(entry.atime)) else {
+			return Err("corrupt snapshot".to_string());
+		};
+		files.insert(entry.path, VirtualFile {
+			size: entry.size,
+			content: std::sync::Arc::new(content),
+			is_directory: entry.is_directory,
+			is_symlink: entry.is_symlink,
+			mtime,
+			atime,
+			mode: entry.mode,
+			readonly: entry.readonly,
+			protected: entry.protected,
+			..Default::default()
+		});
+	}
+	Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn holes_survive_a_round_trip() {
+		let mut content = Content::default();
+		content.write(1 << 30, b"far");
+		let size = content.size();
+		let files = HashMap::from([("/sparse".to_string(), VirtualFile { size, content: std::sync::Arc::new(content), ..Default::default() })]);
+
+		let buffer = encode(&files).unwrap();
+		assert!(buffer.len() < 1024);
+		let decoded = decode(&buffer).unwrap();
+		let content = &decoded["/sparse"].content;
+		assert_eq!(content.size(), size);
+		assert_eq!(content.stored_bytes(), 3);
+		assert_eq!(content.read(1 << 30, 3), b"far");
+	}
+
+	#[test]
+	fn decodes_version_1() {
+		let entries = vec![EntryV1 {
+			path: "/old".to_string(),
+			content: b"dense".to_vec(),
+			size: 5,
+			is_directory: false,
+			is_symlink: false,
+			mtime: Duration::ZERO,
+			atime: Duration::ZERO,
+			mode: None,
+			readonly: true,
+		}];
+		let mut buffer = MAGIC.to_vec();
+		buffer.extend_from_slice(&1u32.to_le_bytes());
+		bincode::serialize_into(&mut buffer, &entries).unwrap();
+
+		let decoded = decode(&buffer).unwrap();
+		assert_eq!(decoded["/old"].content.to_vec(), b"dense");
+		assert!(decoded["/old"].readonly);
+	}
+
+	fn encode_v2(entry: EntryV2) -> Vec<u8> {
+		let mut buffer = MAGIC.to_vec();
+		buffer.extend_from_slice(&2u32.to_le_bytes());
+		bincode::serialize_into(&mut buffer, &vec![entry]).unwrap();
+		buffer
+	}
+
+	fn entry(extents: Vec<(u64, Vec<u8>)>, content_len: u64, mtime: Duration) -> EntryV2 {
+		EntryV2 {
+			path: "/crafted".to_string(),
+			extents,
+			content_len,
+			size: content_len,
+			is_directory: false,
+			is_symlink: false,
+			mtime,
+			atime: Duration::ZERO,
+			mode: None,
+			readonly: false,
+			protected: false,
+		}
+	}
+
+	#[test]
+	fn rejects_out_of_range_fields() {
+		assert!(decode(&encode_v2(entry(Vec::new(), 0, Duration::from_secs(u64::MAX)))).is_err());
+		assert!(decode(&encode_v2(entry(vec![(u64::MAX, b"ab".to_vec())], u64::MAX, Duration::ZERO))).is_err());
+		assert!(decode(&encode_v2(entry(vec![(4, b"ab".to_vec())], 5, Duration::ZERO))).is_err());
+		assert!(decode(&encode_v2(entry(vec![(3, b"ab".to_vec())], 5, Duration::ZERO))).is_ok());
+	}
+}
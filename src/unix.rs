@@ -1,16 +1,193 @@
-use crate::common::{SharedFSState, FSEvent, ObjectType};
+use crate::common::{SharedFSState, CachePolicy, ErrorKind, FSEvent, FSState, FileLock, FileMap, ObjectType, SessionStats, VirtualFile, compare_names, is_direct_child, materialize, resolve_missing};
 use std::ffi::OsStr;
 use std::path::Path;
-use std::time::{Duration, UNIX_EPOCH, SystemTime};
+use std::time::{Duration, SystemTime};
 use fuser::{
 	FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
 	Request, ReplyWrite, ReplyCreate, TimeOrNow,
 };
 use napi::bindgen_prelude::*;
+use crate::MountOptions;
 use std::collections::HashMap;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicU64, Ordering};
 
-const TTL: Duration = Duration::from_secs(1);
+// Runtime the callbacks of every mount block on, built by the first mount and never torn down
+static CALLBACK_RUNTIME: OnceLock<Option<tokio::runtime::Runtime>> = OnceLock::new();
+
+fn callback_runtime() -> Option<&'static tokio::runtime::Runtime> {
+	CALLBACK_RUNTIME
+		.get_or_init(|| tokio::runtime::Builder::new_multi_thread().worker_threads(2).enable_all().build().ok())
+		.as_ref()
+}
+
+// How long the kernel caches entries and attributes when the mount options leave it unset
+const DEFAULT_TTL: Duration = Duration::from_secs(1);
+// What statfs reports when the mount options leave it unset
+const DEFAULT_BLOCK_SIZE: u32 = 4096;
+const DEFAULT_NAME_MAX: u32 = 255;
+// How old relatime lets an access time get before a read refreshes it anyway
+const RELATIME_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[cfg(target_os = "macos")]
+const ENOATTR: i32 = libc::ENOATTR;
+#[cfg(not(target_os = "macos"))]
+const ENOATTR: i32 = libc::ENODATA;
+
+// renameat2 flags, which macOS names RENAME_EXCL and RENAME_SWAP
+#[cfg(target_os = "macos")]
+const RENAME_NOREPLACE: u32 = libc::RENAME_EXCL;
+#[cfg(target_os = "macos")]
+const RENAME_EXCHANGE: u32 = libc::RENAME_SWAP;
+#[cfg(not(target_os = "macos"))]
+const RENAME_NOREPLACE: u32 = libc::RENAME_NOREPLACE;
+#[cfg(not(target_os = "macos"))]
+const RENAME_EXCHANGE: u32 = libc::RENAME_EXCHANGE;
+
+// When reads through the mount refresh the access time, each refresh taking the write lock
+#[derive(Clone, Copy)]
+enum AtimeUpdate {
+	Never,
+	// Only when the access time is not newer than the modification time or is over a day old
+	Relative,
+	Always,
+}
+
+impl AtimeUpdate {
+	fn needs_update(self, file: &VirtualFile, now: SystemTime) -> bool {
+		match self {
+			AtimeUpdate::Never => false,
+			AtimeUpdate::Relative => file.atime <= file.mtime
+				|| now.duration_since(file.atime).is_ok_and(|age| age >= RELATIME_INTERVAL),
+			AtimeUpdate::Always => true,
+		}
+	}
+}
+
+fn file_kind(file: &VirtualFile) -> FileType {
+	if file.is_directory {
+		FileType::Directory
+	} else if file.is_symlink {
+		FileType::Symlink
+	} else {
+		FileType::RegularFile
+	}
+}
+
+fn root_attr() -> FileAttr {
+	let (uid, gid) = get_user_ids();
+	let now = SystemTime::now();
+	FileAttr {
+		ino: 1,
+		size: 0,
+		blocks: 0,
+		atime: now,
+		mtime: now,
+		ctime: now,
+		crtime: now,
+		kind: FileType::Directory,
+		perm: 0o755,
+		nlink: 2,
+		uid,
+		gid,
+		rdev: 0,
+		flags: 0,
+		blksize: 512,
+	}
+}
+
+// Attributes of the entry at `path`, reported under `ino`
+fn file_attr(files: &FileMap, path: &str, ino: u64) -> Option<FileAttr> {
+	Some(entry_attr(files.get(path)?, ino, files.nlink(path)))
+}
+
+fn entry_attr(file: &VirtualFile, ino: u64, nlink: u32) -> FileAttr {
+	let (uid, gid) = get_user_ids();
+	FileAttr {
+		ino,
+		size: file.size,
+		blocks: 1,
+		atime: file.atime,
+		mtime: file.mtime,
+		ctime: file.mtime,
+		crtime: file.mtime,
+		kind: file_kind(file),
+		perm: file.perm(),
+		nlink,
+		uid: file.uid.unwrap_or(uid),
+		gid: file.gid.unwrap_or(gid),
+		rdev: 0,
+		flags: 0,
+		blksize: 512,
+	}
+}
+
+// Path of the directory behind an inode other than the mount root's
+fn directory_path(files: &FileMap, ino: u64) -> Option<String> {
+	files.path_of(ino)
+		.filter(|path| files.get(path).is_some_and(|file| file.is_directory))
+		.cloned()
+}
+
+// O_TRUNC only applies to handles that can write
+fn truncates(flags: i32) -> bool {
+	flags & libc::O_TRUNC != 0 && flags & libc::O_ACCMODE != libc::O_RDONLY
+}
+
+// Empties a file opened with O_TRUNC, failing with the errno to reply with
+fn truncate_on_open(state: &mut FSState, path: String) -> std::result::Result<(), i32> {
+	let object_type = match state.files.get_mut(&path) {
+		Some(file) if file.readonly => return Err(libc::EACCES),
+		Some(file) => {
+			// A lazy file's content is replaced before it was ever fetched
+			file.provider = None;
+			file.content = Arc::default();
+			file.size = 0;
+			file.mtime = SystemTime::now();
+			file.get_type()
+		}
+		None => return Err(libc::ENOENT),
+	};
+	state.emit_event(FSEvent::Modified { path, object_type });
+	Ok(())
+}
+
+// Why `file` cannot be unlinked. Directories are removed with rmdir, which also refuses to orphan their children
+fn unlink_error(file: &VirtualFile) -> Option<i32> {
+	if file.is_directory {
+		Some(libc::EISDIR)
+	} else if file.readonly || file.protected {
+		Some(libc::EPERM)
+	} else {
+		None
+	}
+}
+
+// Why renaming `source` over `target`, at `target_path`, fails. Replacing another entry requires one of
+// the same kind, and an empty one for directories. It deletes the target, which read-only entries refuse
+// like unlink does
+fn replace_error(files: &FileMap, source: &VirtualFile, target: &VirtualFile, target_path: &str) -> Option<i32> {
+	match (source.is_directory, target.is_directory) {
+		(false, true) => Some(libc::EISDIR),
+		(true, false) => Some(libc::ENOTDIR),
+		(true, true) if has_children(files, target_path) => Some(libc::ENOTEMPTY),
+		_ if target.readonly || target.protected => Some(libc::EPERM),
+		_ => None,
+	}
+}
+
+// Where a write lands. O_APPEND writes go at the end of file as of the state lock, whatever offset the
+// kernel passed, so concurrent appenders never overwrite each other
+fn write_offset(file: &VirtualFile, offset: i64, append: bool) -> u64 {
+	if append { file.content.size() } else { offset as u64 }
+}
+
+// Whether any entry lives below the directory at `path`
+fn has_children(files: &FileMap, path: &str) -> bool {
+	let prefix = format!("{}/", path);
+	files.keys().any(|child_path| child_path.starts_with(&prefix))
+}
 
 // Get current user's UID and GID
 fn get_user_ids() -> (u32, u32) {
@@ -24,213 +201,570 @@ fn get_user_ids() -> (u32, u32) {
     }
 }
 
+// Options handed to the kernel for a mount
+fn mount_options(options: &MountOptions) -> Vec<MountOption> {
+	let mut mount_options = vec![MountOption::FSName(options.fs_name.clone().unwrap_or_else(|| "virtual".to_string()))];
+	if let Some(subtype) = &options.subtype {
+		mount_options.push(MountOption::Subtype(subtype.clone()));
+	}
+	if options.default_permissions.unwrap_or(true) {
+		mount_options.push(MountOption::DefaultPermissions);
+	}
+	if options.auto_unmount.unwrap_or(true) {
+		mount_options.push(MountOption::AutoUnmount);
+	}
+	if options.allow_other.unwrap_or(false) {
+		mount_options.push(MountOption::AllowOther);
+	}
+	if options.allow_root.unwrap_or(false) {
+		mount_options.push(MountOption::AllowRoot);
+	}
+	if let AtimeUpdate::Never = atime_update(options) {
+		mount_options.push(MountOption::NoAtime);
+	}
+	#[cfg(target_os = "macos")]
+	{
+		let volume_name = options.volume_name.clone().or_else(|| options.fs_name.clone());
+		mount_options.push(MountOption::CUSTOM(format!("volname={}", volume_name.as_deref().unwrap_or("virtual"))));
+		if !options.apple_double.unwrap_or(false) {
+			mount_options.push(MountOption::CUSTOM("noappledouble".to_string()));
+		}
+		if !options.apple_xattr.unwrap_or(true) {
+			mount_options.push(MountOption::CUSTOM("noapplexattr".to_string()));
+		}
+		if options.local.unwrap_or(true) {
+			mount_options.push(MountOption::CUSTOM("local".to_string()));
+		}
+	}
+	// Checked against the managed options when parsed, the kernel rejecting one fails the mount
+	for option in options.raw_options.iter().flatten() {
+		mount_options.push(MountOption::CUSTOM(option.clone()));
+	}
+	mount_options
+}
+
+// Cache duration for an entryTtlMs or attrTtlMs option
+fn ttl(ms: Option<u32>) -> Duration {
+	ms.map_or(DEFAULT_TTL, |ms| Duration::from_millis(ms.into()))
+}
+
+// Validated when the options were parsed
+fn atime_update(mount_options: &MountOptions) -> AtimeUpdate {
+	match mount_options.atime.as_deref() {
+		Some("noatime") => AtimeUpdate::Never,
+		Some("strictatime") => AtimeUpdate::Always,
+		_ => AtimeUpdate::Relative,
+	}
+}
+
+struct Session {
+	// Dropping the background session unmounts it
+	handle: fuser::BackgroundSession,
+	stats: Arc<SessionStats>,
+}
+
 pub struct FSImpl {
-	sessions: HashMap<PathBuf, fuser::BackgroundSession>,
+	sessions: HashMap<PathBuf, Session>,
 	state: SharedFSState,
-	pub total_space_bytes: u64,
-	pub max_files: u64,
 }
 
 impl FSImpl {
 	pub fn new(state: SharedFSState) -> Self {
-		// Default to 4GB total space and 1M files
-		Self::with_size(state, 4 * 1024 * 1024 * 1024, 1024 * 1024)
-	}
-
-	pub fn with_size(state: SharedFSState, total_space_bytes: u64, max_files: u64) -> Self {
 		Self {
 			sessions: HashMap::new(),
 			state,
-			total_space_bytes,
-			max_files,
 		}
 	}
 
-	pub async fn mount(&mut self, mount_path: &Path) -> Result<()> {
-		let options = vec![
-			MountOption::FSName("virtual".to_string()),
-			MountOption::DefaultPermissions,
-			MountOption::AutoUnmount,
-		];
+	pub async fn mount(&mut self, mount_path: &Path, mount_options: &MountOptions) -> Result<()> {
+		// A session unmounted from outside unmounts the path again when dropped, which has to happen
+		// before the new session is mounted there
+		if self.sessions.get(mount_path).is_some_and(|session| session.stats.has_ended()) {
+			self.sessions.remove(mount_path);
+		}
+		// Checked here so the callbacks can count on it
+		let Some(runtime) = callback_runtime() else {
+			return Err(crate::fs_error("EIO", "could not start the runtime for the filesystem callbacks", &mount_path.to_string_lossy()));
+		};
+		let options = self::mount_options(mount_options);
+		let atime = atime_update(mount_options);
+
+		let cache = mount_options.cache.as_deref().and_then(CachePolicy::from_name).unwrap_or(CachePolicy::Auto);
 
+		let stats = Arc::new(SessionStats::default());
 		let fs = VirtualFS {
+			runtime,
 			state: self.state.clone(),
-			total_space_bytes: self.total_space_bytes,
-			max_files: self.max_files,
+			mount_path: mount_path.to_string_lossy().into_owned(),
+			root: mount_options.root.clone().unwrap_or_default(),
+			atime,
+			cache,
+			entry_ttl: ttl(mount_options.entry_ttl_ms),
+			attr_ttl: ttl(mount_options.attr_ttl_ms),
+			block_size: mount_options.block_size.unwrap_or(DEFAULT_BLOCK_SIZE),
+			name_max: mount_options.name_max.unwrap_or(DEFAULT_NAME_MAX),
+			stats: stats.clone(),
+			handles: HashMap::new(),
+			directories: HashMap::new(),
+			orphans: HashMap::new(),
+			next_directory_handle: 1,
+			in_flight: Arc::default(),
 		};
 
 		match fuser::spawn_mount2(fs, mount_path, &options) {
-			Ok(session) => {
-				self.sessions.insert(mount_path.to_path_buf(), session);
+			Ok(handle) => {
+				self.sessions.insert(mount_path.to_path_buf(), Session { handle, stats });
 				Ok(())
 			},
 			Err(e) => {
-				eprintln!("FUSE mount error details: {:?}", e);
-				Err(Error::from_reason(format!("Mount failed: {:?}", e)))
+				self.state.read().await.emit_error(ErrorKind::Mount, "mount", &mount_path.to_string_lossy(), e.to_string(), e.raw_os_error());
+				Err(mount_error(&e, &mount_path.to_string_lossy()))
 			}
 		}
 	}
 
-	pub async fn unmount(&mut self, mount_path: &Path) -> Result<()> {
-		if let Some(_) = self.sessions.remove(mount_path) {
-			// Session is dropped here, which automatically unmounts
-			Ok(())
+	// Another filesystem mounted on the directory or entries the mount would hide are refused
+	pub fn check_mount_point(mount_path: &Path) -> Result<()> {
+		use std::os::unix::fs::MetadataExt;
+
+		let display = mount_path.to_string_lossy();
+		let io_error = |e: std::io::Error| crate::fs_error("EIO", &e.to_string(), &display);
+		let mount_path = mount_path.canonicalize().map_err(io_error)?;
+		// A mount point lives on another device than its parent, the filesystem root has no parent
+		let is_mount_point = match mount_path.parent() {
+			Some(parent) => std::fs::metadata(&mount_path).map_err(io_error)?.dev() != std::fs::metadata(parent).map_err(io_error)?.dev(),
+			None => true,
+		};
+		if is_mount_point {
+			return Err(crate::fs_error("EBUSY", "already a mount point", &display));
+		}
+		if std::fs::read_dir(&mount_path).map_err(io_error)?.next().is_some() {
+			return Err(crate::fs_error("ENOTEMPTY", "mount point is not empty", &display));
+		}
+		Ok(())
+	}
+
+	// Paths still open through the mount, which would keep it from being torn down
+	pub fn busy_paths(&self, mount_path: &Path) -> Vec<String> {
+		self.sessions.get(mount_path).map_or_else(Vec::new, |session| session.stats.busy_paths())
+	}
+
+	// With `force` the open handles start failing with EBADF and the session thread is not waited
+	// for, it ends on its own once the kernel has released them
+	pub async fn unmount(&mut self, mount_path: &Path, force: bool) -> Result<()> {
+		if let Some(session) = self.sessions.remove(mount_path) {
+			if force {
+				session.stats.invalidate_handles();
+				return Ok(());
+			}
+			// Dropping the rest of the background session at the end of this block unmounts,
+			// after which the session loop sees the device go away and its thread ends
+			let guard = {
+				let handle = session.handle;
+				handle.guard
+			};
+			guard.join()
+				.map_err(|_| Error::from_reason("FUSE session thread panicked"))?
+				.map_err(|e| Error::from_reason(format!("FUSE session ended with an error: {}", e)))
 		} else {
 			Err(Error::from_reason("Mount point not found"))
 		}
 	}
 
+	// Sessions unmounted from outside stay in the map until their thread drops them
+	pub fn is_mounted(&self, mount_path: &Path) -> bool {
+		self.sessions.get(mount_path).is_some_and(|session| !session.stats.has_ended())
+	}
+
+	pub fn mounts(&self) -> Vec<(PathBuf, Arc<SessionStats>)> {
+		self.sessions.iter()
+			.filter(|(_, session)| !session.stats.has_ended())
+			.map(|(path, session)| (path.clone(), session.stats.clone()))
+			.collect()
+	}
+
+	// fuser 0.12 has no kernel notification channel, so there is nothing to push here:
+	// every callback resolves against the live state and cached dentries/attrs for
+	// removed or changed paths expire on their own after the mount's TTLs
+	pub fn invalidate_paths(&self, _paths: &[String]) {}
+
+	#[allow(dead_code)]
 	pub async fn unmount_all(&mut self) -> Result<()> {
 		self.sessions.clear();  // Drop all sessions
 		Ok(())
 	}
 }
 
+// Gives a failed mount a code JS can branch on. Errors from fusermount only carry its stderr, so those are
+// told apart by their message
+fn mount_error(e: &std::io::Error, mount_path: &str) -> Error {
+	let detail = e.to_string();
+	let detail = detail.trim();
+	let lower = detail.to_lowercase();
+	let code = match e.raw_os_error() {
+		// Neither /dev/fuse nor the fusermount helper exist
+		Some(libc::ENOENT) | Some(libc::ENODEV) => "ENODEV",
+		Some(libc::EACCES) | Some(libc::EPERM) => "EACCES",
+		Some(libc::EBUSY) => "EBUSY",
+		Some(libc::EINVAL) => "EINVAL",
+		Some(_) => "EIO",
+		None if lower.contains("device not found") || lower.contains("no such device") => "ENODEV",
+		None if lower.contains("permission denied") || lower.contains("not permitted") || lower.contains("user_allow_other") => "EACCES",
+		None if lower.contains("busy") => "EBUSY",
+		None if lower.contains("invalid argument") || lower.contains("unknown option") || lower.contains("bad mount point") => "EINVAL",
+		None => "EIO",
+	};
+	let message = match code {
+		"ENODEV" => format!("FUSE is not available, check for /dev/fuse and fusermount3 ({})", detail),
+		_ => format!("mount failed: {}", detail),
+	};
+	crate::fs_error(code, &message, mount_path)
+}
+
 struct VirtualFS {
+	runtime: &'static tokio::runtime::Runtime,
 	state: SharedFSState,
-	total_space_bytes: u64,
-	max_files: u64,
+	// Where the session is mounted on the host, for the "unmounted" event
+	mount_path: String,
+	// State key of the directory shown as the mount root, empty for the whole tree
+	root: String,
+	atime: AtimeUpdate,
+	cache: CachePolicy,
+	// How long the kernel may cache name lookups and attributes. Entry replies carry a single TTL, so the
+	// attributes returned by lookup, create and readdirplus follow the entry TTL
+	entry_ttl: Duration,
+	attr_ttl: Duration,
+	// What statfs reports, validated when the options were parsed
+	block_size: u32,
+	name_max: u32,
+	stats: Arc<SessionStats>,
+	handles: HashMap<u64, FileHandle>,
+	// Listing taken by opendir for each open directory handle
+	directories: HashMap<u64, Vec<DirEntry>>,
+	next_directory_handle: u64,
+	// Files removed while handles were still open on them, by inode. Those handles keep working on the
+	// content until the last of them is released
+	orphans: HashMap<u64, VirtualFile>,
+	// Reads waiting on a provider off the session thread, by request id, aborted when the session ends
+	in_flight: Arc<Mutex<HashMap<u64, tokio::task::AbortHandle>>>,
+}
+
+// Fetches a lazy file, giving up with EINTR once a forced unmount cancels the session. fuser 0.12 answers
+// FUSE_INTERRUPT with ENOSYS itself without calling the filesystem, so a single request cannot be interrupted
+async fn fetch(state: &SharedFSState, stats: &SessionStats, path: &str) -> std::result::Result<(), i32> {
+	tokio::select! {
+		fetched = materialize(state, path) => fetched.map_err(|_| libc::EIO),
+		_ = stats.cancelled() => Err(libc::EINTR),
+	}
+}
+
+// A read answered outside of the session's borrow of VirtualFS
+struct ContentRead {
+	state: SharedFSState,
+	stats: Arc<SessionStats>,
+	atime: AtimeUpdate,
+	// Byte count of the handle when access events are enabled
+	accessed: Option<Arc<AtomicU64>>,
+	path: String,
+	offset: u64,
+	size: usize,
+}
+
+impl ContentRead {
+	async fn reply(self, reply: ReplyData) {
+		if let Err(errno) = fetch(&self.state, &self.stats, &self.path).await {
+			reply.error(errno);
+			return;
+		}
+
+		let now = SystemTime::now();
+		// The content is replied with once the state lock is released
+		let found = self.state.read().await.files.get(&self.path)
+			.map(|file| (file.content.clone(), self.atime.needs_update(file, now)));
+		let Some((content, stale_atime)) = found else {
+			reply.error(libc::ENOENT);
+			return;
+		};
+		let data = content.read(self.offset, self.size);
+		self.stats.record_read(self.offset, data.len());
+		if let Some(bytes) = &self.accessed {
+			bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+		}
+		reply.data(&data);
+
+		if stale_atime {
+			if let Some(file) = self.state.write().await.files.get_mut(&self.path) {
+				file.atime = now;
+			}
+		}
+	}
+}
+
+// Inode, type and name of a directory entry as readdir reports it
+type DirEntry = (u64, FileType, String);
+
+fn reply_directory(entries: &[DirEntry], offset: i64, mut reply: ReplyDirectory) {
+	for (i, (ino, kind, name)) in entries.iter().enumerate().skip(offset as usize) {
+		if reply.add(*ino, (i + 1) as i64, *kind, name) {
+			break;
+		}
+	}
+	reply.ok();
+}
+
+// Per-open state of a handle given out by open or create
+struct FileHandle {
+	ino: u64,
+	flags: i32,
+	// Path and bytes read so far when access events were enabled at open, reported on release. Shared with the
+	// reads answered off the session thread
+	access: Option<(String, Arc<AtomicU64>)>,
+}
+
+impl FileHandle {
+	fn readable(&self) -> bool {
+		self.flags & libc::O_ACCMODE != libc::O_WRONLY
+	}
+
+	fn writable(&self) -> bool {
+		self.flags & libc::O_ACCMODE != libc::O_RDONLY
+	}
+
+	fn appends(&self) -> bool {
+		self.flags & libc::O_APPEND != 0
+	}
+}
+
+impl VirtualFS {
+	// ".", ".." and the children sorted by name, None when `ino` is not a directory
+	fn directory_entries(&self, files: &FileMap, ino: u64) -> Option<Vec<DirEntry>> {
+		let dir_path = if ino == 1 {
+			self.root.clone()
+		} else {
+			directory_path(files, ino)?
+		};
+
+		// ".." is the parent directory, or the mount root for its direct children and the root itself
+		let parent_path = dir_path.rsplit_once('/').map_or("", |(parent, _)| parent);
+		let parent_ino = if ino == 1 || parent_path == self.root {
+			1
+		} else {
+			files.ino(parent_path).unwrap_or(1)
+		};
+
+		let mut children: Vec<DirEntry> = files.iter()
+			.filter(|(path, _)| **path != dir_path && is_direct_child(&dir_path, path))
+			.map(|(path, file)| (
+				files.ino(path).unwrap_or_default(),
+				file_kind(file),
+				path.rsplit('/').next().unwrap().to_string(),
+			))
+			.collect();
+		// Sorted so offsets stay stable between calls, in the order Windows enumerates in when case-insensitive
+		let fold_case = files.is_case_insensitive();
+		children.sort_by(|(_, _, a), (_, _, b)| compare_names(a, b, fold_case));
+
+		let mut entries = vec![
+			(ino, FileType::Directory, ".".to_string()),
+			(parent_ino, FileType::Directory, "..".to_string()),
+		];
+		entries.extend(children);
+		Some(entries)
+	}
+
+	async fn fetch(&self, path: &str) -> std::result::Result<(), i32> {
+		fetch(&self.state, &self.stats, path).await
+	}
+
+	fn has_open_handles(&self, ino: u64) -> bool {
+		self.handles.iter().any(|(fh, handle)| handle.ino == ino && self.stats.is_handle_open(*fh))
+	}
+
+	// Inode of a removed file that lost its last name while handles are still open on it
+	fn orphaned(&self, files: &FileMap, ino: Option<u64>) -> Option<u64> {
+		ino.filter(|ino| files.path_of(*ino).is_none() && self.has_open_handles(*ino))
+	}
+
+	// None once the handle was released or invalidated by a forced unmount
+	fn handle(&self, fh: u64) -> Option<&FileHandle> {
+		self.handles.get(&fh).filter(|_| self.stats.is_handle_open(fh))
+	}
+
+	// Entry holding the extended attributes of an inode. The mount root only has one when it shows a sub-directory
+	fn xattr_path(&self, files: &FileMap, ino: u64) -> std::result::Result<String, i32> {
+		if ino == 1 {
+			if self.root.is_empty() {
+				return Err(libc::ENOTSUP);
+			}
+			return Ok(self.root.clone());
+		}
+		files.path_of(ino).cloned().ok_or(libc::ENOENT)
+	}
+
+	// FOPEN_* flags telling the kernel how to cache the file for a new handle
+	fn open_flags(&self, files: &FileMap, path: &str) -> u32 {
+		match files.get(path).and_then(|file| file.cache).unwrap_or(self.cache) {
+			CachePolicy::Auto => 0,
+			CachePolicy::Keep => fuser::consts::FOPEN_KEEP_CACHE,
+			CachePolicy::Direct => fuser::consts::FOPEN_DIRECT_IO,
+		}
+	}
+}
+
+// Answers a sized xattr query: the length when asked for 0 bytes, ERANGE when the buffer is too small
+fn reply_xattr(data: &[u8], size: u32, reply: fuser::ReplyXattr) {
+	if size == 0 {
+		reply.size(data.len() as u32);
+	} else if (size as usize) < data.len() {
+		reply.error(libc::ERANGE);
+	} else {
+		reply.data(data);
+	}
 }
 
 impl Filesystem for VirtualFS {
-	fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-		tokio::runtime::Runtime::new().unwrap().block_on(async {
-			let state = self.state.read().await;
-			let (uid, gid) = get_user_ids();
+	fn init(&mut self, _req: &Request, config: &mut fuser::KernelConfig) -> std::result::Result<(), libc::c_int> {
+		// Kernels without readdirplus keep using readdir and lookup
+		let _ = config.add_capabilities(fuser::consts::FUSE_DO_READDIRPLUS | fuser::consts::FUSE_READDIRPLUS_AUTO);
+		// Has POSIX locks go through getlk/setlk so the host can see them, the kernel keeps them locally otherwise
+		let _ = config.add_capabilities(fuser::consts::FUSE_POSIX_LOCKS);
+		// Has O_TRUNC reach open and create, the kernel truncates through setattr beforehand otherwise
+		let _ = config.add_capabilities(fuser::consts::FUSE_ATOMIC_O_TRUNC);
+		Ok(())
+	}
 
-			let parent_path = if parent == 1 {
-				String::new()
-			} else {
-				let parent_path = state.files.iter()
-					.find(|(path, file)| file.is_directory && hash_path(path) == parent)
-					.map(|(path, _)| path.clone());
+	// Called once the session loop ended, both after unmount() and when the mount went away from outside
+	fn destroy(&mut self) {
+		self.stats.end();
+		for (_, task) in self.in_flight.lock().unwrap().drain() {
+			task.abort();
+		}
+		self.runtime.block_on(async {
+			self.state.read().await.emit_event(FSEvent::Unmounted { path: self.mount_path.clone() });
+		});
+	}
 
-				match parent_path {
-					Some(path) => path,
-					None => {
-						reply.error(libc::ENOENT);
-						return;
+	fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+		self.runtime.block_on(async {
+			let path = {
+				let state = self.state.read().await;
+				let parent_path = if parent == 1 {
+					self.root.clone()
+				} else {
+					match directory_path(&state.files, parent) {
+						Some(path) => path,
+						None => {
+							reply.error(libc::ENOENT);
+							return;
+						}
 					}
+				};
+
+				if parent_path.is_empty() {
+					name.to_string_lossy().into_owned()
+				} else {
+					format!("{}/{}", parent_path, name.to_string_lossy())
 				}
 			};
 
-			let path = if parent_path.is_empty() {
-				name.to_string_lossy().into_owned()
-			} else {
-				format!("{}/{}", parent_path, name.to_string_lossy())
-			};
+			// Paths the state doesn't know about may still be provided by the resolver
+			resolve_missing(&self.state, &path).await;
 
-			if let Some(file) = state.files.get(&path) {
-				let attr = FileAttr {
-					ino: hash_path(&path),
-					size: file.size,
-					blocks: 1,
-					atime: UNIX_EPOCH,
-					mtime: UNIX_EPOCH,
-					ctime: UNIX_EPOCH,
-					crtime: UNIX_EPOCH,
-					kind: if file.is_directory { FileType::Directory } else { FileType::RegularFile },
-					perm: if file.is_directory { 0o755 } else { 0o644 },
-					nlink: if file.is_directory { 2 } else { 1 },
-					uid,
-					gid,
-					rdev: 0,
-					flags: 0,
-					blksize: 512,
-				};
-				reply.entry(&TTL, &attr, 0);
-			} else {
-				reply.error(libc::ENOENT);
+			let state = self.state.read().await;
+			// The kernel asks with whatever casing it was given, inodes follow the stored key
+			let path = state.files.key(&path);
+			match state.files.ino(&path).and_then(|ino| file_attr(&state.files, &path, ino)) {
+				Some(attr) => reply.entry(&self.entry_ttl, &attr, 0),
+				None => reply.error(libc::ENOENT),
 			}
 		});
 	}
 
-	fn write(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, data: &[u8], _write_flags: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyWrite) {
-		tokio::runtime::Runtime::new().unwrap().block_on(async {
+	fn write(&mut self, _req: &Request, ino: u64, fh: u64, offset: i64, data: &[u8], _write_flags: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyWrite) {
+		let Some(append) = self.handle(fh).filter(|handle| handle.writable()).map(FileHandle::appends) else {
+			reply.error(libc::EBADF);
+			return;
+		};
+		self.runtime.block_on(async {
+			// Lazy files have to be fetched before they can be partially overwritten
+			let lazy_path = {
+				let state = self.state.read().await;
+				state.files.path_of(ino)
+					.filter(|path| state.files.get(path).is_some_and(|file| file.provider.is_some()))
+					.cloned()
+			};
+			if let Some(path) = lazy_path {
+				if let Err(errno) = self.fetch(&path).await {
+					reply.error(errno);
+					return;
+				}
+			}
+
 			let mut state = self.state.write().await;
 			let now = SystemTime::now();
 
-			let mut found_path = None;
-			let mut is_dir = false;
-
-			// Calculate current total size
-			let total_size: u64 = state.files.values()
-				.map(|file| file.size)
-				.sum();
+			let mut object_type = ObjectType::File;
 
-			for (path, _) in state.files.iter() {
-				if hash_path(path) == ino {
-					found_path = Some(path.clone());
-					break;
-				}
-			}
+			let found_path = state.files.path_of(ino).cloned();
 
 			if let Some(path) = found_path {
-				if let Some(file) = state.files.get_mut(&path) {
-					let start = offset as usize;
-					let end = start + data.len();
-
-					// Calculate the size change
-					let size_increase = if end > file.content.len() {
-						(end - file.content.len()) as u64
-					} else {
-						0
-					};
-
-					// Check if this write would exceed the total space limit
-					if total_size + size_increase > self.total_space_bytes {
-						reply.error(libc::ENOSPC);
+				let (offset, content_bytes) = match state.files.get(&path) {
+					Some(file) if file.readonly => {
+						reply.error(libc::EACCES);
 						return;
 					}
-
-					// Ensure the file is large enough
-					if end > file.content.len() {
-						file.content.resize(end, 0);
+					Some(file) => {
+						let offset = write_offset(file, offset, append);
+						(offset, file.content_bytes_after_write(offset, data.len() as u64, state.quota_logical_size))
+					}
+					None => {
+						reply.error(libc::ENOENT);
+						return;
 					}
+				};
+
+				// Check if this write would exceed the total space limit
+				if !state.has_space_for(&path, content_bytes) {
+					reply.error(libc::ENOSPC);
+					return;
+				}
 
+				if let Some(file) = state.files.get_mut(&path) {
 					// Write the data
-					file.content[start..end].copy_from_slice(data);
-					file.size = file.content.len() as u64;
+					file.write_at(offset, data);
 					file.mtime = now;
-					is_dir = file.is_directory;
+					object_type = file.get_type();
 				}
 
 				// Emit modification event outside the mutable borrow scope
-				state.emit_event(FSEvent::Modified {
-					path,
-					object_type: if is_dir { ObjectType::Directory } else { ObjectType::File }
-				});
+				state.emit_event(FSEvent::Modified { path, object_type });
 
 				reply.written(data.len() as u32);
 				return;
 			}
+			// Removed files only live on for their handles, so no quota or events apply
+			if let Some(file) = self.orphans.get_mut(&ino) {
+				let offset = if append { file.content.size() } else { offset as u64 };
+				file.write_at(offset, data);
+				file.mtime = now;
+				reply.written(data.len() as u32);
+				return;
+			}
 			reply.error(libc::ENOENT);
 		});
 	}
 
-	fn create(&mut self, _req: &Request, parent: u64, name: &OsStr, _mode: u32, _umask: u32, _flags: i32, reply: ReplyCreate) {
-		tokio::runtime::Runtime::new().unwrap().block_on(async {
+	fn create(&mut self, _req: &Request, parent: u64, name: &OsStr, mode: u32, umask: u32, flags: i32, reply: ReplyCreate) {
+		self.runtime.block_on(async {
 			let mut state = self.state.write().await;
 			let (uid, gid) = get_user_ids();
 			let now = SystemTime::now();
 
-			// Calculate current total size
-			let total_size: u64 = state.files.values()
-				.map(|file| file.size)
-				.sum();
-
-			// Account for metadata size (path and basic struct size)
-			let metadata_size = std::mem::size_of::<crate::common::VirtualFile>() as u64 + name.len() as u64;
-			if total_size + metadata_size > self.total_space_bytes {
-				reply.error(libc::ENOSPC);
-				return;
-			}
-
 			let parent_path = if parent == 1 {
-				String::new()
+				self.root.clone()
 			} else {
-				match state.files.iter().find(|(path, file)| file.is_directory && hash_path(path) == parent) {
-					Some((path, _)) => path.clone(),
+				match directory_path(&state.files, parent) {
+					Some(path) => path,
 					None => {
 						reply.error(libc::ENOENT);
 						return;
@@ -243,16 +777,57 @@ impl Filesystem for VirtualFS {
 			} else {
 				format!("{}/{}", parent_path, name.to_string_lossy())
 			};
+			let path = state.files.key(&path);
 
+			// A directory is never replaced by a file, an existing file only fails with O_EXCL
+			if state.files.get(&path).is_some_and(|file| file.is_directory || flags & libc::O_EXCL != 0) {
+				reply.error(libc::EEXIST);
+				return;
+			}
+			// Otherwise the existing file is opened as it is, like open() would
+			if state.files.contains_key(&path) {
+				if truncates(flags) {
+					if let Err(errno) = truncate_on_open(&mut state, path.clone()) {
+						reply.error(errno);
+						return;
+					}
+				}
+				let Some(attr) = state.files.ino(&path).and_then(|ino| file_attr(&state.files, &path, ino)) else {
+					reply.error(libc::ENOENT);
+					return;
+				};
+				let open_flags = self.open_flags(&state.files, &path);
+				let fh = self.stats.open_handle(path.clone());
+				let access = state.access_events_enabled().then(|| (path, Arc::default()));
+				self.handles.insert(fh, FileHandle { ino: attr.ino, flags, access });
+				reply.created(&self.entry_ttl, &attr, 0, fh, open_flags);
+				return;
+			}
+			if state.exceeds_max_files(&path) {
+				reply.error(libc::ENOSPC);
+				return;
+			}
+			// Check if adding this file would exceed the limit
+			if !state.has_space_for(&path, 0) {
+				reply.error(libc::ENOSPC);
+				return;
+			}
+
+			let perm = (mode & !umask & 0o7777) as u16;
 			let file = crate::common::VirtualFile {
-				content: Vec::new(),
+				content: Arc::default(),
 				size: 0,
 				is_directory: false,
 				mtime: now,
+				mode: Some(perm),
+				..Default::default()
 			};
 
+			state.files.insert(path.clone(), file);
+			let ino = state.files.ino(&path).unwrap_or_default();
+
 			let attr = FileAttr {
-				ino: hash_path(&path),
+				ino,
 				size: 0,
 				blocks: 1,
 				atime: now,
@@ -260,7 +835,7 @@ impl Filesystem for VirtualFS {
 				ctime: now,
 				crtime: now,
 				kind: FileType::RegularFile,
-				perm: 0o644,
+				perm,
 				nlink: 1,
 				uid,
 				gid,
@@ -268,23 +843,24 @@ impl Filesystem for VirtualFS {
 				flags: 0,
 				blksize: 512,
 			};
-
-			state.files.insert(path.clone(), file);
+			let fh = self.stats.open_handle(path.clone());
+			self.handles.insert(fh, FileHandle { ino, flags, access: None });
+			let open_flags = self.open_flags(&state.files, &path);
 			state.emit_event(FSEvent::Created { path, object_type: ObjectType::File });
 
-			reply.created(&TTL, &attr, 0, 0, 0);
+			reply.created(&self.entry_ttl, &attr, 0, fh, open_flags);
 		});
 	}
 
 	fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
-		tokio::runtime::Runtime::new().unwrap().block_on(async {
+		self.runtime.block_on(async {
 			let mut state = self.state.write().await;
 
 			let parent_path = if parent == 1 {
-				String::new()
+				self.root.clone()
 			} else {
-				match state.files.iter().find(|(path, file)| file.is_directory && hash_path(path) == parent) {
-					Some((path, _)) => path.clone(),
+				match directory_path(&state.files, parent) {
+					Some(path) => path,
 					None => {
 						reply.error(libc::ENOENT);
 						return;
@@ -297,12 +873,22 @@ impl Filesystem for VirtualFS {
 			} else {
 				format!("{}/{}", parent_path, name.to_string_lossy())
 			};
+			let path = state.files.key(&path);
+
+			if let Some(errno) = state.files.get(&path).and_then(unlink_error) {
+				reply.error(errno);
+				return;
+			}
 
+			let ino = state.files.ino(&path);
 			if let Some(file) = state.files.remove(&path) {
 				state.emit_event(FSEvent::Deleted {
 					path,
 					object_type: file.get_type()
 				});
+				if let Some(ino) = self.orphaned(&state.files, ino) {
+					self.orphans.insert(ino, file);
+				}
 				reply.ok();
 			} else {
 				reply.error(libc::ENOENT);
@@ -311,81 +897,93 @@ impl Filesystem for VirtualFS {
 	}
 
 	fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-		let (uid, gid) = get_user_ids();
-		let now = SystemTime::now();
-
 		if ino == 1 {
-			let attr = FileAttr {
-				ino: 1,
-				size: 0,
-				blocks: 0,
-				atime: now,
-				mtime: now,
-				ctime: now,
-				crtime: now,
-				kind: FileType::Directory,
-				perm: 0o755,
-				nlink: 2,
-				uid,
-				gid,
-				rdev: 0,
-				flags: 0,
-				blksize: 512,
-			};
-			reply.attr(&TTL, &attr);
+			reply.attr(&self.attr_ttl, &root_attr());
 			return;
 		}
 
-		tokio::runtime::Runtime::new().unwrap().block_on(async {
+		self.runtime.block_on(async {
 			let state = self.state.read().await;
-			for (path, file) in state.files.iter() {
-				if hash_path(path) == ino {
-					let attr = FileAttr {
-						ino,
-						size: file.size,
-						blocks: 1,
-						atime: file.mtime,
-						mtime: file.mtime,
-						ctime: file.mtime,
-						crtime: file.mtime,
-						kind: if file.is_directory { FileType::Directory } else { FileType::RegularFile },
-						perm: if file.is_directory { 0o755 } else { 0o644 },
-						nlink: if file.is_directory { 2 } else { 1 },
-						uid,
-						gid,
-						rdev: 0,
-						flags: 0,
-						blksize: 512,
-					};
-					reply.attr(&TTL, &attr);
-					return;
-				}
+			match state.files.path_of(ino).and_then(|path| file_attr(&state.files, path, ino)) {
+				Some(attr) => reply.attr(&self.attr_ttl, &attr),
+				None => match self.orphans.get(&ino) {
+					Some(file) => reply.attr(&self.attr_ttl, &entry_attr(file, ino, 0)),
+					None => reply.error(libc::ENOENT),
+				},
 			}
-			reply.error(libc::ENOENT);
 		});
 	}
 
 	fn read(
 		&mut self,
-		_req: &Request,
+		req: &Request,
 		ino: u64,
-		_fh: u64,
+		fh: u64,
 		offset: i64,
 		size: u32,
 		_flags: i32,
 		_lock: Option<u64>,
 		reply: ReplyData,
 	) {
-		tokio::runtime::Runtime::new().unwrap().block_on(async {
+		if !self.handle(fh).is_some_and(FileHandle::readable) {
+			reply.error(libc::EBADF);
+			return;
+		}
+		if offset < 0 {
+			reply.error(libc::EINVAL);
+			return;
+		}
+		let found = self.runtime.block_on(async {
 			let state = self.state.read().await;
-			for (path, file) in state.files.iter() {
-				if hash_path(path) == ino {
-					let data = &file.content[offset as usize..std::cmp::min(file.content.len(), (offset + size as i64) as usize)];
-					reply.data(data);
-					return;
+			state.files.path_of(ino).map(|path| (path.clone(), state.files.get(path).is_some_and(|file| file.provider.is_some())))
+		});
+		let Some((path, lazy)) = found else {
+			match self.orphans.get(&ino) {
+				Some(file) => reply.data(&file.content.read(offset as u64, size as usize)),
+				None => reply.error(libc::ENOENT),
+			}
+			return;
+		};
+
+		let read = ContentRead {
+			state: self.state.clone(),
+			stats: self.stats.clone(),
+			atime: self.atime,
+			accessed: self.handles.get(&fh).and_then(|handle| handle.access.as_ref()).map(|(_, bytes)| bytes.clone()),
+			path,
+			offset: offset as u64,
+			size: size as usize,
+		};
+		if !lazy {
+			self.runtime.block_on(read.reply(reply));
+			return;
+		}
+
+		// Lazy files are fetched from their provider on first read, which may take arbitrarily long. The reply
+		// is sent from the runtime so the session thread keeps serving other requests meanwhile
+		let unique = req.unique();
+		let in_flight = self.in_flight.clone();
+		// Held until the task is registered, which it otherwise could try to remove itself from first
+		let mut tasks = self.in_flight.lock().unwrap();
+		let task = self.runtime.spawn(async move {
+			read.reply(reply).await;
+			in_flight.lock().unwrap().remove(&unique);
+		});
+		tasks.insert(unique, task.abort_handle());
+	}
+
+	fn opendir(&mut self, _req: &Request, ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
+		self.runtime.block_on(async {
+			let state = self.state.read().await;
+			match self.directory_entries(&state.files, ino) {
+				Some(entries) => {
+					let fh = self.next_directory_handle;
+					self.next_directory_handle += 1;
+					self.directories.insert(fh, entries);
+					reply.opened(fh, 0);
 				}
+				None => reply.error(libc::ENOTDIR),
 			}
-			reply.error(libc::ENOENT);
 		});
 	}
 
@@ -393,56 +991,56 @@ impl Filesystem for VirtualFS {
 		&mut self,
 		_req: &Request,
 		ino: u64,
-		_fh: u64,
+		fh: u64,
 		offset: i64,
-		mut reply: ReplyDirectory,
+		reply: ReplyDirectory,
 	) {
-		tokio::runtime::Runtime::new().unwrap().block_on(async {
+		// Pages are served from the listing taken by opendir, so entries added or removed
+		// in between cannot shift the offsets
+		if let Some(entries) = self.directories.get(&fh) {
+			reply_directory(entries, offset, reply);
+			return;
+		}
+
+		self.runtime.block_on(async {
 			let state = self.state.read().await;
+			match self.directory_entries(&state.files, ino) {
+				Some(entries) => reply_directory(&entries, offset, reply),
+				None => reply.error(libc::ENOTDIR),
+			}
+		});
+	}
 
-			// Find the directory path for this inode
-			let dir_path = if ino == 1 {
-				String::new()
-			} else {
-				match state.files.iter().find(|(path, file)| file.is_directory && hash_path(path) == ino) {
-					Some((path, _)) => path.clone(),
+	// Same listing as readdir with every entry's attributes, sparing the kernel a lookup per entry
+	fn readdirplus(&mut self, _req: &Request, ino: u64, fh: u64, offset: i64, mut reply: fuser::ReplyDirectoryPlus) {
+		self.runtime.block_on(async {
+			let state = self.state.read().await;
+			let live;
+			let entries = match self.directories.get(&fh) {
+				Some(entries) => entries,
+				None => match self.directory_entries(&state.files, ino) {
+					Some(entries) => {
+						live = entries;
+						&live
+					}
 					None => {
 						reply.error(libc::ENOTDIR);
 						return;
 					}
-				}
+				},
 			};
 
-			let mut entries = vec![
-				(ino, FileType::Directory, "."),
-				(if ino == 1 { 1 } else { hash_path(dir_path.rsplit('/').next().unwrap_or("")) }, FileType::Directory, ".."),
-			];
-
-			// Add entries in this directory
-			for (path, file) in state.files.iter() {
-				if path == &dir_path {
-					continue;
-				}
-
-				let is_direct_child = if dir_path.is_empty() {
-					!path.contains('/')
+			for (i, (entry_ino, _, name)) in entries.iter().enumerate().skip(offset as usize) {
+				let attr = if *entry_ino == 1 {
+					root_attr()
 				} else {
-					path.starts_with(&format!("{}/", dir_path)) &&
-					path[dir_path.len()+1..].split('/').count() == 1
+					// Entries removed since opendir are left out, offsets still follow the listing
+					match state.files.path_of(*entry_ino).and_then(|path| file_attr(&state.files, path, *entry_ino)) {
+						Some(attr) => attr,
+						None => continue,
+					}
 				};
-
-				if is_direct_child {
-					let name = path.split('/').last().unwrap();
-					entries.push((
-						hash_path(path),
-						if file.is_directory { FileType::Directory } else { FileType::RegularFile },
-						name,
-					));
-				}
-			}
-
-			for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
-				if reply.add(entry.0, (i + 1) as i64, entry.1, entry.2) {
+				if reply.add(*entry_ino, (i + 1) as i64, name, &self.entry_ttl, &attr, 0) {
 					break;
 				}
 			}
@@ -450,6 +1048,11 @@ impl Filesystem for VirtualFS {
 		});
 	}
 
+	fn releasedir(&mut self, _req: &Request, _ino: u64, fh: u64, _flags: i32, reply: fuser::ReplyEmpty) {
+		self.directories.remove(&fh);
+		reply.ok();
+	}
+
 	fn setattr(
 		&mut self,
 		_req: &Request,
@@ -468,95 +1071,121 @@ impl Filesystem for VirtualFS {
 		_flags: Option<u32>,
 		reply: ReplyAttr,
 	) {
-		tokio::runtime::Runtime::new().unwrap().block_on(async {
+		self.runtime.block_on(async {
+			// Truncating a lazy file keeps the start of what its provider returns, which has to be fetched first.
+			// Truncating it to nothing needs none of it
+			if size.is_some_and(|size| size > 0) {
+				let path = self.state.read().await.files.path_of(ino).cloned();
+				if let Some(path) = path {
+					if let Err(errno) = self.fetch(&path).await {
+						reply.error(errno);
+						return;
+					}
+				}
+			}
+
 			let mut state = self.state.write().await;
 			let (current_uid, current_gid) = get_user_ids();
 			let now = SystemTime::now();
 
-			let mut found_path = None;
 			let mut found_attr = None;
 			let mut should_emit_event = false;
 
-			// Calculate current total size
-			let total_size: u64 = state.files.values()
-				.map(|file| file.size)
-				.sum();
-
-			for (path, _) in state.files.iter() {
-				if hash_path(path) == ino {
-					found_path = Some(path.clone());
-					break;
-				}
-			}
+			let found_path = state.files.path_of(ino).cloned();
 
 			if let Some(path) = found_path {
-				let mut is_dir = false;
+				let mut object_type = ObjectType::File;
+				let nlink = state.files.nlink(&path);
+				// Checked before the entry is borrowed for the update
+				let size_fits = size.is_none_or(|new_size| {
+					state.files.get(&path).is_some_and(|file| state.has_space_for(&path, file.content_bytes_after_resize(new_size, state.quota_logical_size)))
+				});
 				if let Some(file) = state.files.get_mut(&path) {
+					if file.readonly {
+						reply.error(libc::EPERM);
+						return;
+					}
+
 					// Handle file size changes (truncation)
 					if let Some(new_size) = size {
 						// Check if this size change would exceed the limit
-						let size_change = if new_size > file.size {
-							new_size - file.size
-						} else {
-							0
-						};
-
-						if total_size + size_change > self.total_space_bytes {
+						if !size_fits {
 							reply.error(libc::ENOSPC);
 							return;
 						}
 
-						file.content.resize(new_size as usize, 0);
-						file.size = new_size;
+						// Otherwise the provider would bring the old content back on the next read
+						file.provider = None;
+						file.resize(new_size);
 						file.mtime = now;
 						should_emit_event = true;
 					}
 
-					// Handle mtime updates
+					// Handle chmod, reported as a modification so the host sees permission changes
+					if let Some(mode) = mode {
+						let mode = (mode & 0o7777) as u16;
+						if file.perm() != mode {
+							should_emit_event = true;
+						}
+						file.mode = Some(mode);
+					}
+
+					// Handle chown
+					if let Some(uid) = uid {
+						if file.uid.unwrap_or(current_uid) != uid {
+							should_emit_event = true;
+						}
+						file.uid = Some(uid);
+					}
+					if let Some(gid) = gid {
+						if file.gid.unwrap_or(current_gid) != gid {
+							should_emit_event = true;
+						}
+						file.gid = Some(gid);
+					}
+
+					// Handle mtime/atime updates
 					if let Some(mtime) = mtime {
 						match mtime {
 							TimeOrNow::Now => file.mtime = now,
 							TimeOrNow::SpecificTime(time) => file.mtime = time,
 						}
 					}
+					if let Some(atime) = atime {
+						match atime {
+							TimeOrNow::Now => file.atime = now,
+							TimeOrNow::SpecificTime(time) => file.atime = time,
+						}
+					}
 
 					found_attr = Some(FileAttr {
 						ino,
 						size: file.size,
 						blocks: 1,
-						atime: match atime {
-							Some(TimeOrNow::Now) => now,
-							Some(TimeOrNow::SpecificTime(time)) => time,
-							None => file.mtime,
-						},
+						atime: file.atime,
 						mtime: file.mtime,
 						ctime: file.mtime,
 						crtime: file.mtime,
-						kind: if file.is_directory { FileType::Directory } else { FileType::RegularFile },
-						perm: mode.unwrap_or(if file.is_directory { 0o755 } else { 0o644 }) as u16,
-						nlink: if file.is_directory { 2 } else { 1 },
-						uid: uid.unwrap_or(current_uid),
-						gid: gid.unwrap_or(current_gid),
+						kind: file_kind(file),
+						perm: file.perm(),
+						nlink,
+						uid: file.uid.unwrap_or(current_uid),
+						gid: file.gid.unwrap_or(current_gid),
 						rdev: 0,
 						flags: 0,
 						blksize: 512,
 					});
 
-					if should_emit_event {
-						is_dir = file.is_directory;
-					}
+					object_type = file.get_type();
 				}
 
 				if should_emit_event {
-					state.emit_event(FSEvent::Modified {
-						path,
-						object_type: if is_dir { ObjectType::Directory } else { ObjectType::File }
-					});
+					state.emit_event(FSEvent::Modified { path, object_type });
 				}
 			}
 
 			if let Some(attr) = found_attr {
-				reply.attr(&TTL, &attr);
+				reply.attr(&self.attr_ttl, &attr);
 			} else {
 				reply.error(libc::ENOENT);
 			}
@@ -564,80 +1193,220 @@ impl Filesystem for VirtualFS {
 	}
 
 	fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
-		tokio::runtime::Runtime::new().unwrap().block_on(async {
+		self.runtime.block_on(async {
+			if truncates(flags) {
+				let mut state = self.state.write().await;
+				let Some(path) = state.files.path_of(ino).cloned() else {
+					reply.error(libc::ENOENT);
+					return;
+				};
+				if let Err(errno) = truncate_on_open(&mut state, path) {
+					reply.error(errno);
+					return;
+				}
+			}
+
 			let state = self.state.read().await;
-			for (path, _) in state.files.iter() {
-				if hash_path(path) == ino {
-					reply.opened(0, flags as u32);
+			let path = match state.files.path_of(ino) {
+				Some(path) => path.clone(),
+				None => {
+					reply.error(libc::ENOENT);
 					return;
 				}
+			};
+
+			let open_flags = self.open_flags(&state.files, &path);
+			let fh = self.stats.open_handle(path.clone());
+			// Reads through the handle are tallied and reported once it is released
+			let access = state.access_events_enabled().then(|| (path, Arc::default()));
+			self.handles.insert(fh, FileHandle { ino, flags, access });
+			// The reply carries FOPEN_* flags for the kernel, not the open flags
+			reply.opened(fh, open_flags);
+		});
+	}
+
+	fn flush(&mut self, _req: &Request, ino: u64, _fh: u64, lock_owner: u64, reply: fuser::ReplyEmpty) {
+		self.runtime.block_on(async {
+			let state = self.state.read().await;
+			// Closing any descriptor of a file drops the POSIX locks its owner holds on it
+			state.locks.release_owner(ino, lock_owner);
+			if state.files.path_of(ino).is_some() || self.orphans.contains_key(&ino) {
+				reply.ok();
+				return;
 			}
 			reply.error(libc::ENOENT);
 		});
 	}
 
-	fn flush(&mut self, _req: &Request, ino: u64, _fh: u64, _lock_owner: u64, reply: fuser::ReplyEmpty) {
-		tokio::runtime::Runtime::new().unwrap().block_on(async {
+	fn fsync(&mut self, _req: &Request, ino: u64, _fh: u64, _datasync: bool, reply: fuser::ReplyEmpty) {
+		self.runtime.block_on(async {
 			let state = self.state.read().await;
-			for (path, _) in state.files.iter() {
-				if hash_path(path) == ino {
-					reply.ok();
-					return;
-				}
+			if state.files.path_of(ino).is_some() || self.orphans.contains_key(&ino) {
+				reply.ok();
+				return;
 			}
 			reply.error(libc::ENOENT);
 		});
 	}
 
-	fn fsync(&mut self, _req: &Request, ino: u64, _fh: u64, _datasync: bool, reply: fuser::ReplyEmpty) {
-		tokio::runtime::Runtime::new().unwrap().block_on(async {
+	fn release(&mut self, _req: &Request, ino: u64, fh: u64, _flags: i32, lock_owner: Option<u64>, _flush: bool, reply: fuser::ReplyEmpty) {
+		self.runtime.block_on(async {
+			self.stats.close_handle(fh);
 			let state = self.state.read().await;
-			for (path, _) in state.files.iter() {
-				if hash_path(path) == ino {
-					reply.ok();
-					return;
-				}
+			if let Some(lock_owner) = lock_owner {
+				state.locks.release_owner(ino, lock_owner);
+			}
+			if let Some((path, bytes)) = self.handles.remove(&fh).and_then(|handle| handle.access) {
+				state.emit_event(FSEvent::Accessed { path, bytes: bytes.load(Ordering::Relaxed) });
+			}
+			// The content of a removed file goes with its last handle
+			if !self.has_open_handles(ino) && self.orphans.remove(&ino).is_some() {
+				reply.ok();
+				return;
+			}
+			if state.files.path_of(ino).is_some() {
+				reply.ok();
+				return;
 			}
 			reply.error(libc::ENOENT);
 		});
 	}
 
-	fn release(&mut self, _req: &Request, ino: u64, _fh: u64, _flags: i32, _lock_owner: Option<u64>, _flush: bool, reply: fuser::ReplyEmpty) {
-		tokio::runtime::Runtime::new().unwrap().block_on(async {
-			let state = self.state.read().await;
-			for (path, _) in state.files.iter() {
-				if hash_path(path) == ino {
-					reply.ok();
+	// The kernel resolves SEEK_SET/CUR/END itself and only asks for SEEK_DATA and SEEK_HOLE.
+	// Holes are the gaps between the runs of bytes the content holds, with one more at EOF
+	fn lseek(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, whence: i32, reply: fuser::ReplyLseek) {
+		self.runtime.block_on(async {
+			let state = self.state.read().await;
+			let Some(file) = state.files.path_of(ino).and_then(|path| state.files.get(path)).or_else(|| self.orphans.get(&ino)) else {
+				reply.error(libc::ENOENT);
+				return;
+			};
+
+			if offset < 0 {
+				reply.error(libc::EINVAL);
+			} else if offset as u64 >= file.size {
+				reply.error(libc::ENXIO);
+			} else if file.provider.is_some() {
+				// Not fetched yet, the whole announced size counts as data
+				match whence {
+					libc::SEEK_DATA => reply.offset(offset),
+					libc::SEEK_HOLE => reply.offset(file.size as i64),
+					_ => reply.error(libc::EINVAL),
+				}
+			} else {
+				match whence {
+					libc::SEEK_DATA => match file.content.next_data(offset as u64) {
+						Some(data) => reply.offset(data as i64),
+						None => reply.error(libc::ENXIO),
+					},
+					libc::SEEK_HOLE => reply.offset(file.content.next_hole(offset as u64) as i64),
+					_ => reply.error(libc::EINVAL),
+				}
+			}
+		});
+	}
+
+	// Copies between two entries under a single write lock instead of a read/write round trip through the caller
+	fn copy_file_range(
+		&mut self,
+		_req: &Request,
+		ino_in: u64,
+		fh_in: u64,
+		offset_in: i64,
+		ino_out: u64,
+		fh_out: u64,
+		offset_out: i64,
+		len: u64,
+		flags: u32,
+		reply: ReplyWrite,
+	) {
+		if !self.handle(fh_in).is_some_and(FileHandle::readable) || !self.handle(fh_out).is_some_and(FileHandle::writable) {
+			reply.error(libc::EBADF);
+			return;
+		}
+		if flags != 0 || offset_in < 0 || offset_out < 0 {
+			reply.error(libc::EINVAL);
+			return;
+		}
+		self.runtime.block_on(async {
+			let paths = {
+				let state = self.state.read().await;
+				state.files.path_of(ino_in).cloned().zip(state.files.path_of(ino_out).cloned())
+			};
+			let Some((src_path, dst_path)) = paths else {
+				reply.error(libc::ENOENT);
+				return;
+			};
+
+			// Lazy files are fetched before being copied from or partially overwritten
+			for path in [&src_path, &dst_path] {
+				if let Err(errno) = self.fetch(path).await {
+					reply.error(errno);
+					return;
+				}
+			}
+
+			let mut state = self.state.write().await;
+			let data = match state.files.get(&src_path) {
+				Some(file) if file.is_directory => {
+					reply.error(libc::EISDIR);
+					return;
+				}
+				// The reply carries a 32-bit count, the caller loops for the rest
+				Some(file) => file.content.read(offset_in as u64, std::cmp::min(len, u32::MAX as u64) as usize),
+				None => {
+					reply.error(libc::ENOENT);
+					return;
+				}
+			};
+
+			let content_bytes = state.files.get(&dst_path)
+				.map_or(0, |file| file.content_bytes_after_write(offset_out as u64, data.len() as u64, state.quota_logical_size));
+			let size_fits = state.has_space_for(&dst_path, content_bytes);
+			let object_type = match state.files.get_mut(&dst_path) {
+				Some(file) if file.is_directory => {
+					reply.error(libc::EISDIR);
+					return;
+				}
+				Some(file) => {
+					if file.readonly {
+						reply.error(libc::EACCES);
+						return;
+					}
+					if !size_fits {
+						reply.error(libc::ENOSPC);
+						return;
+					}
+					if !data.is_empty() {
+						file.write_at(offset_out as u64, &data);
+						file.mtime = SystemTime::now();
+					}
+					file.get_type()
+				}
+				None => {
+					reply.error(libc::ENOENT);
 					return;
 				}
+			};
+
+			if !data.is_empty() {
+				state.emit_event(FSEvent::Modified { path: dst_path, object_type });
 			}
-			reply.error(libc::ENOENT);
+			reply.written(data.len() as u32);
 		});
 	}
 
-	fn mkdir(&mut self, _req: &Request, parent: u64, name: &OsStr, _mode: u32, _umask: u32, reply: ReplyEntry) {
-		tokio::runtime::Runtime::new().unwrap().block_on(async {
+	fn mkdir(&mut self, _req: &Request, parent: u64, name: &OsStr, mode: u32, umask: u32, reply: ReplyEntry) {
+		self.runtime.block_on(async {
 			let mut state = self.state.write().await;
 			let (uid, gid) = get_user_ids();
 			let now = SystemTime::now();
 
-			// Calculate current total size
-			let total_size: u64 = state.files.values()
-				.map(|file| file.size)
-				.sum();
-
-			// Account for directory metadata size (path and basic struct size)
-			let metadata_size = std::mem::size_of::<crate::common::VirtualFile>() as u64 + name.len() as u64;
-			if total_size + metadata_size > self.total_space_bytes {
-				reply.error(libc::ENOSPC);
-				return;
-			}
-
 			let parent_path = if parent == 1 {
-				String::new()
+				self.root.clone()
 			} else {
-				match state.files.iter().find(|(path, file)| file.is_directory && hash_path(path) == parent) {
-					Some((path, _)) => path.clone(),
+				match directory_path(&state.files, parent) {
+					Some(path) => path,
 					None => {
 						reply.error(libc::ENOENT);
 						return;
@@ -650,16 +1419,37 @@ impl Filesystem for VirtualFS {
 			} else {
 				format!("{}/{}", parent_path, name.to_string_lossy())
 			};
+			let path = state.files.key(&path);
+
+			if state.files.contains_key(&path) {
+				reply.error(libc::EEXIST);
+				return;
+			}
+			if state.exceeds_max_files(&path) {
+				reply.error(libc::ENOSPC);
+				return;
+			}
+			// Check if adding this directory would exceed the limit
+			if !state.has_space_for(&path, 0) {
+				reply.error(libc::ENOSPC);
+				return;
+			}
 
+			let perm = (mode & !umask & 0o7777) as u16;
 			let dir = crate::common::VirtualFile {
-				content: Vec::new(),
-				size: metadata_size, // Store the metadata size for directories
+				content: Arc::default(),
+				size: 0,
 				is_directory: true,
 				mtime: now,
+				mode: Some(perm),
+				..Default::default()
 			};
 
+			state.files.insert(path.clone(), dir);
+			let ino = state.files.ino(&path).unwrap_or_default();
+
 			let attr = FileAttr {
-				ino: hash_path(&path),
+				ino,
 				size: 0,
 				blocks: 1,
 				atime: now,
@@ -667,7 +1457,7 @@ impl Filesystem for VirtualFS {
 				ctime: now,
 				crtime: now,
 				kind: FileType::Directory,
-				perm: 0o755,
+				perm,
 				nlink: 2,
 				uid,
 				gid,
@@ -675,24 +1465,27 @@ impl Filesystem for VirtualFS {
 				flags: 0,
 				blksize: 512,
 			};
-
-			state.files.insert(path.clone(), dir);
 			state.emit_event(FSEvent::Created { path, object_type: ObjectType::Directory });
 
-			reply.entry(&TTL, &attr, 0);
+			reply.entry(&self.entry_ttl, &attr, 0);
 		});
 	}
 
-	fn rename(&mut self, _req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, _flags: u32, reply: fuser::ReplyEmpty) {
-		tokio::runtime::Runtime::new().unwrap().block_on(async {
+	fn rename(&mut self, _req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, flags: u32, reply: fuser::ReplyEmpty) {
+		// RENAME_WHITEOUT is for overlay filesystems, and a swap cannot also refuse to replace
+		if flags & !(RENAME_NOREPLACE | RENAME_EXCHANGE) != 0 || flags == RENAME_NOREPLACE | RENAME_EXCHANGE {
+			reply.error(libc::EINVAL);
+			return;
+		}
+		self.runtime.block_on(async {
 			let mut state = self.state.write().await;
 
 			// Get parent paths
 			let parent_path = if parent == 1 {
-				String::new()
+				self.root.clone()
 			} else {
-				match state.files.iter().find(|(path, file)| file.is_directory && hash_path(path) == parent) {
-					Some((path, _)) => path.clone(),
+				match directory_path(&state.files, parent) {
+					Some(path) => path,
 					None => {
 						reply.error(libc::ENOENT);
 						return;
@@ -701,10 +1494,10 @@ impl Filesystem for VirtualFS {
 			};
 
 			let new_parent_path = if newparent == 1 {
-				String::new()
+				self.root.clone()
 			} else {
-				match state.files.iter().find(|(path, file)| file.is_directory && hash_path(path) == newparent) {
-					Some((path, _)) => path.clone(),
+				match directory_path(&state.files, newparent) {
+					Some(path) => path,
 					None => {
 						reply.error(libc::ENOENT);
 						return;
@@ -725,39 +1518,65 @@ impl Filesystem for VirtualFS {
 				format!("{}/{}", new_parent_path, newname.to_string_lossy())
 			};
 
-			// Get the file/directory being renamed
-			if let Some(file) = state.files.remove(&old_path) {
-				let is_dir = file.is_directory;
-
-				// If it's a directory, we need to update all child paths
-				if is_dir {
-					let mut paths_to_rename = Vec::new();
-					for (path, _) in state.files.iter() {
-						if path.starts_with(&format!("{}/", old_path)) {
-							paths_to_rename.push(path.clone());
-						}
-					}
+			let old_path = state.files.key(&old_path);
+			let target_path = state.files.key(&new_path);
+			let source = match state.files.get(&old_path) {
+				Some(file) => file,
+				None => {
+					reply.error(libc::ENOENT);
+					return;
+				}
+			};
+			if source.is_directory && target_path.starts_with(&format!("{}/", old_path)) {
+				reply.error(libc::EINVAL);
+				return;
+			}
 
-					for old_child_path in paths_to_rename {
-						if let Some(file) = state.files.remove(&old_child_path) {
-							let new_child_path = old_child_path.replacen(&old_path, &new_path, 1);
-							state.files.insert(new_child_path, file);
+			if flags & RENAME_EXCHANGE != 0 {
+				match state.files.get(&target_path) {
+					None => reply.error(libc::ENOENT),
+					Some(target) if target.is_directory && old_path.starts_with(&format!("{}/", target_path)) => reply.error(libc::EINVAL),
+					// Two names of the same entry have nothing to swap
+					Some(_) if state.files.ino(&target_path) == state.files.ino(&old_path) => reply.ok(),
+					Some(_) => match state.exchange_entries(&old_path, &target_path) {
+						Some((source_type, target_type)) => {
+							state.emit_renamed(old_path.clone(), target_path.clone(), source_type);
+							state.emit_renamed(target_path, old_path, target_type);
+							reply.ok();
 						}
+						None => reply.error(libc::ENOENT),
+					},
+				}
+				return;
+			}
+			if flags & RENAME_NOREPLACE != 0 && state.files.contains_key(&target_path) {
+				reply.error(libc::EEXIST);
+				return;
+			}
+			// Two names of the same entry are left to the rename below
+			let mut replaced = None;
+			if let Some(target) = state.files.get(&target_path).filter(|_| state.files.ino(&target_path) != state.files.ino(&old_path)) {
+				if let Some(errno) = replace_error(&state.files, source, target, &target_path) {
+					reply.error(errno);
+					return;
+				}
+				replaced = Some(target.get_type());
+			}
+			// The replaced entry is reported gone before the rename that takes its place
+			if let Some(object_type) = replaced {
+				let ino = state.files.ino(&target_path);
+				if let Some(file) = state.files.remove(&target_path) {
+					if let Some(ino) = self.orphaned(&state.files, ino) {
+						self.orphans.insert(ino, file);
 					}
 				}
+				state.emit_event(FSEvent::Deleted { path: target_path, object_type });
+			}
 
-				// Insert the renamed file/directory
-				state.files.insert(new_path.clone(), file);
-
-				// Emit events
-				state.emit_event(FSEvent::Deleted {
-					path: old_path,
-					object_type: if is_dir { ObjectType::Directory } else { ObjectType::File }
-				});
-				state.emit_event(FSEvent::Created {
-					path: new_path,
-					object_type: if is_dir { ObjectType::Directory } else { ObjectType::File }
-				});
+			// Move the file/directory, along with all child paths for directories
+			if let Some(object_type) = state.move_entry(&old_path, &new_path) {
+				let new_path = state.files.key(&new_path);
+				state.emit_renamed(old_path, new_path, object_type);
 
 				reply.ok();
 			} else {
@@ -767,14 +1586,14 @@ impl Filesystem for VirtualFS {
 	}
 
 	fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
-		tokio::runtime::Runtime::new().unwrap().block_on(async {
+		self.runtime.block_on(async {
 			let mut state = self.state.write().await;
 
 			let parent_path = if parent == 1 {
-				String::new()
+				self.root.clone()
 			} else {
-				match state.files.iter().find(|(path, file)| file.is_directory && hash_path(path) == parent) {
-					Some((path, _)) => path.clone(),
+				match directory_path(&state.files, parent) {
+					Some(path) => path,
 					None => {
 						reply.error(libc::ENOENT);
 						return;
@@ -787,6 +1606,7 @@ impl Filesystem for VirtualFS {
 			} else {
 				format!("{}/{}", parent_path, name.to_string_lossy())
 			};
+			let path = state.files.key(&path);
 
 			// Check if directory exists and is actually a directory
 			match state.files.get(&path) {
@@ -794,6 +1614,10 @@ impl Filesystem for VirtualFS {
 					reply.error(libc::ENOTDIR);
 					return;
 				}
+				Some(file) if file.protected => {
+					reply.error(libc::EPERM);
+					return;
+				}
 				None => {
 					reply.error(libc::ENOENT);
 					return;
@@ -802,11 +1626,7 @@ impl Filesystem for VirtualFS {
 			}
 
 			// Check if directory is empty
-			let has_children = state.files.iter().any(|(child_path, _)| {
-				child_path != &path && child_path.starts_with(&format!("{}/", path))
-			});
-
-			if has_children {
+			if has_children(&state.files, &path) {
 				reply.error(libc::ENOTEMPTY);
 				return;
 			}
@@ -825,28 +1645,16 @@ impl Filesystem for VirtualFS {
 	}
 
 	fn symlink(&mut self, _req: &Request, parent: u64, name: &OsStr, link: &Path, reply: ReplyEntry) {
-		tokio::runtime::Runtime::new().unwrap().block_on(async {
+		self.runtime.block_on(async {
 			let mut state = self.state.write().await;
 			let (uid, gid) = get_user_ids();
 			let now = SystemTime::now();
 
-			// Calculate current total size
-			let total_size: u64 = state.files.values()
-				.map(|file| file.size)
-				.sum();
-
-			// Check if adding this symlink would exceed the limit
-			let link_size = link.to_string_lossy().len() as u64;
-			if total_size + link_size > self.total_space_bytes {
-				reply.error(libc::ENOSPC);
-				return;
-			}
-
 			let parent_path = if parent == 1 {
-				String::new()
+				self.root.clone()
 			} else {
-				match state.files.iter().find(|(path, file)| file.is_directory && hash_path(path) == parent) {
-					Some((path, _)) => path.clone(),
+				match directory_path(&state.files, parent) {
+					Some(path) => path,
 					None => {
 						reply.error(libc::ENOENT);
 						return;
@@ -859,18 +1667,36 @@ impl Filesystem for VirtualFS {
 			} else {
 				format!("{}/{}", parent_path, name.to_string_lossy())
 			};
+			let path = state.files.key(&path);
+
+			if state.exceeds_max_files(&path) {
+				reply.error(libc::ENOSPC);
+				return;
+			}
+			let link_size = link.to_string_lossy().len() as u64;
+			// Check if adding this symlink would exceed the limit
+			if !state.has_space_for(&path, link_size) {
+				reply.error(libc::ENOSPC);
+				return;
+			}
 
 			// Create symlink content (store the target path)
 			let symlink = crate::common::VirtualFile {
-				content: link.to_string_lossy().as_bytes().to_vec(),
+				content: Arc::new(link.to_string_lossy().as_bytes().to_vec().into()),
 				size: link_size,
 				is_directory: false,
+				is_symlink: true,
 				mtime: now,
+				..Default::default()
 			};
 
+			let size = symlink.size;
+			state.files.insert(path.clone(), symlink);
+			let ino = state.files.ino(&path).unwrap_or_default();
+
 			let attr = FileAttr {
-				ino: hash_path(&path),
-				size: symlink.size,
+				ino,
+				size,
 				blocks: 1,
 				atime: now,
 				mtime: now,
@@ -885,67 +1711,447 @@ impl Filesystem for VirtualFS {
 				flags: 0,
 				blksize: 512,
 			};
+			state.emit_event(FSEvent::Created { path, object_type: ObjectType::Symlink });
 
-			state.files.insert(path.clone(), symlink);
-			state.emit_event(FSEvent::Created {
-				path,
-				object_type: ObjectType::File // Symlinks are treated as special files
-			});
+			reply.entry(&self.entry_ttl, &attr, 0);
+		});
+	}
+
+	fn link(&mut self, _req: &Request, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry) {
+		self.runtime.block_on(async {
+			let mut state = self.state.write().await;
+			let (uid, gid) = get_user_ids();
+
+			let target = match state.files.path_of(ino) {
+				Some(path) => path.clone(),
+				None => {
+					reply.error(libc::ENOENT);
+					return;
+				}
+			};
+			// Directories cannot be hard linked
+			if state.files.get(&target).is_some_and(|file| file.is_directory) {
+				reply.error(libc::EPERM);
+				return;
+			}
+
+			let parent_path = if newparent == 1 {
+				self.root.clone()
+			} else {
+				match directory_path(&state.files, newparent) {
+					Some(path) => path,
+					None => {
+						reply.error(libc::ENOENT);
+						return;
+					}
+				}
+			};
+
+			let path = if parent_path.is_empty() {
+				newname.to_string_lossy().into_owned()
+			} else {
+				format!("{}/{}", parent_path, newname.to_string_lossy())
+			};
+			let path = state.files.key(&path);
+
+			if state.files.contains_key(&path) {
+				reply.error(libc::EEXIST);
+				return;
+			}
+
+			state.files.link(&target, &path);
+			let nlink = state.files.nlink(&path);
+			let Some(file) = state.files.get(&path) else {
+				reply.error(libc::ENOENT);
+				return;
+			};
+			let attr = FileAttr {
+				ino,
+				size: file.size,
+				blocks: 1,
+				atime: file.atime,
+				mtime: file.mtime,
+				ctime: file.mtime,
+				crtime: file.mtime,
+				kind: file_kind(file),
+				perm: file.perm(),
+				nlink,
+				uid: file.uid.unwrap_or(uid),
+				gid: file.gid.unwrap_or(gid),
+				rdev: 0,
+				flags: 0,
+				blksize: 512,
+			};
+			let object_type = file.get_type();
+			state.emit_event(FSEvent::Created { path, object_type });
 
-			reply.entry(&TTL, &attr, 0);
+			reply.entry(&self.entry_ttl, &attr, 0);
 		});
 	}
 
 	fn readlink(&mut self, _req: &Request, ino: u64, reply: fuser::ReplyData) {
-		tokio::runtime::Runtime::new().unwrap().block_on(async {
+		self.runtime.block_on(async {
 			let state = self.state.read().await;
 
-			for (path, file) in state.files.iter() {
-				if hash_path(path) == ino {
-					reply.data(&file.content);
-					return;
+			if let Some(file) = state.files.path_of(ino).and_then(|path| state.files.get(path)) {
+				if file.is_symlink {
+					reply.data(&file.content.to_vec());
+				} else {
+					reply.error(libc::EINVAL);
 				}
+				return;
 			}
 
 			reply.error(libc::ENOENT);
 		});
 	}
 
-	fn statfs(&mut self, _req: &Request, _ino: u64, reply: fuser::ReplyStatfs) {
-		tokio::runtime::Runtime::new().unwrap().block_on(async {
+	// Checked against the stored mode and owner, supplementary groups of the caller are not considered
+	fn access(&mut self, req: &Request, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
+		self.runtime.block_on(async {
+			let state = self.state.read().await;
+			let (attr, readonly) = if ino == 1 {
+				(root_attr(), false)
+			} else {
+				let path = state.files.path_of(ino);
+				match path.and_then(|path| file_attr(&state.files, path, ino).zip(state.files.get(path))) {
+					Some((attr, file)) => (attr, file.readonly),
+					None => {
+						reply.error(libc::ENOENT);
+						return;
+					}
+				}
+			};
+
+			if mask == libc::F_OK {
+				reply.ok();
+				return;
+			}
+			if mask & libc::W_OK != 0 && readonly {
+				reply.error(libc::EACCES);
+				return;
+			}
+
+			let perm = attr.perm as i32;
+			let granted = if req.uid() == 0 {
+				// Root may read and write anything, and execute what anyone may execute
+				let any_execute = attr.kind == FileType::Directory || perm & 0o111 != 0;
+				libc::R_OK | libc::W_OK | if any_execute { libc::X_OK } else { 0 }
+			} else if req.uid() == attr.uid {
+				(perm >> 6) & 0o7
+			} else if req.gid() == attr.gid {
+				(perm >> 3) & 0o7
+			} else {
+				perm & 0o7
+			};
+
+			if mask & !granted == 0 {
+				reply.ok();
+			} else {
+				reply.error(libc::EACCES);
+			}
+		});
+	}
+
+	fn getlk(&mut self, _req: &Request, ino: u64, _fh: u64, lock_owner: u64, start: u64, end: u64, typ: i32, pid: u32, reply: fuser::ReplyLock) {
+		self.runtime.block_on(async {
+			let lock = FileLock { owner: lock_owner, pid, start, end, exclusive: typ == libc::F_WRLCK };
+			match self.state.read().await.locks.conflict(ino, &lock) {
+				Some(held) => reply.locked(held.start, held.end, if held.exclusive { libc::F_WRLCK } else { libc::F_RDLCK }, held.pid),
+				None => reply.locked(start, end, libc::F_UNLCK, 0),
+			}
+		});
+	}
+
+	fn setlk(&mut self, _req: &Request, ino: u64, _fh: u64, lock_owner: u64, start: u64, end: u64, typ: i32, pid: u32, sleep: bool, reply: fuser::ReplyEmpty) {
+		let locks = self.runtime.block_on(async {
+			self.state.read().await.locks.clone()
+		});
+
+		let exclusive = match typ {
+			libc::F_UNLCK => {
+				locks.unlock(ino, lock_owner, start, end);
+				reply.ok();
+				return;
+			}
+			libc::F_RDLCK => false,
+			libc::F_WRLCK => true,
+			_ => {
+				reply.error(libc::EINVAL);
+				return;
+			}
+		};
+
+		let lock = FileLock { owner: lock_owner, pid, start, end, exclusive };
+		match locks.try_lock(ino, lock.clone()) {
+			Ok(()) => reply.ok(),
+			Err(_) if !sleep => reply.error(libc::EAGAIN),
+			// Waiting here would stall every other request of the session, including the unlock being waited for
+			Err(_) => {
+				std::thread::spawn(move || {
+					locks.lock_wait(ino, lock);
+					reply.ok();
+				});
+			}
+		}
+	}
+
+	fn setxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, value: &[u8], flags: i32, _position: u32, reply: fuser::ReplyEmpty) {
+		self.runtime.block_on(async {
+			let mut state = self.state.write().await;
+			let path = match self.xattr_path(&state.files, ino) {
+				Ok(path) => path,
+				Err(code) => {
+					reply.error(code);
+					return;
+				}
+			};
+			let Some(file) = state.files.get_mut(&path) else {
+				reply.error(libc::ENOENT);
+				return;
+			};
+			if file.readonly {
+				reply.error(libc::EPERM);
+				return;
+			}
+
+			let name = name.to_string_lossy().into_owned();
+			let exists = file.xattrs.contains_key(&name);
+			if flags & libc::XATTR_CREATE != 0 && exists {
+				reply.error(libc::EEXIST);
+				return;
+			}
+			if flags & libc::XATTR_REPLACE != 0 && !exists {
+				reply.error(ENOATTR);
+				return;
+			}
+
+			file.xattrs.insert(name, value.to_vec());
+			let object_type = file.get_type();
+			state.emit_event(FSEvent::Modified { path, object_type });
+			reply.ok();
+		});
+	}
+
+	fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: fuser::ReplyXattr) {
+		self.runtime.block_on(async {
+			let state = self.state.read().await;
+			let path = match self.xattr_path(&state.files, ino) {
+				Ok(path) => path,
+				Err(code) => {
+					reply.error(code);
+					return;
+				}
+			};
+			match state.files.get(&path).map(|file| file.xattrs.get(name.to_string_lossy().as_ref())) {
+				Some(Some(value)) => reply_xattr(value, size, reply),
+				Some(None) => reply.error(ENOATTR),
+				None => reply.error(libc::ENOENT),
+			}
+		});
+	}
+
+	fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: fuser::ReplyXattr) {
+		self.runtime.block_on(async {
 			let state = self.state.read().await;
+			let path = match self.xattr_path(&state.files, ino) {
+				Ok(path) => path,
+				Err(code) => {
+					reply.error(code);
+					return;
+				}
+			};
+			let Some(file) = state.files.get(&path) else {
+				reply.error(libc::ENOENT);
+				return;
+			};
+
+			// Names are listed back to back, each terminated by a NUL byte
+			let mut names: Vec<&String> = file.xattrs.keys().collect();
+			names.sort();
+			let mut list = Vec::new();
+			for name in names {
+				list.extend_from_slice(name.as_bytes());
+				list.push(0);
+			}
+			reply_xattr(&list, size, reply);
+		});
+	}
+
+	fn removexattr(&mut self, _req: &Request, ino: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+		self.runtime.block_on(async {
+			let mut state = self.state.write().await;
+			let path = match self.xattr_path(&state.files, ino) {
+				Ok(path) => path,
+				Err(code) => {
+					reply.error(code);
+					return;
+				}
+			};
+			let Some(file) = state.files.get_mut(&path) else {
+				reply.error(libc::ENOENT);
+				return;
+			};
+			if file.readonly {
+				reply.error(libc::EPERM);
+				return;
+			}
+			if file.xattrs.remove(name.to_string_lossy().as_ref()).is_none() {
+				reply.error(ENOATTR);
+				return;
+			}
 
-			// Calculate total size of all files
-			let total_size: u64 = state.files.values()
-				.map(|file| file.size)
-				.sum();
+			let object_type = file.get_type();
+			state.emit_event(FSEvent::Modified { path, object_type });
+			reply.ok();
+		});
+	}
+
+	fn statfs(&mut self, _req: &Request, _ino: u64, reply: fuser::ReplyStatfs) {
+		self.runtime.block_on(async {
+			let state = self.state.read().await;
 
-			// Calculate number of files/directories
-			let total_files = state.files.len() as u64;
+			// Same accounting as getUsage() on the JS side
+			let (used_bytes, used_files) = state.usage_under("");
 
-			let block_size: u64 = 4096; // 4KB blocks
-			let total_blocks = self.total_space_bytes / block_size;
-			let used_blocks = (total_size + block_size - 1) / block_size; // Round up
-			let free_blocks = total_blocks.saturating_sub(used_blocks);
+			let used_blocks = used_bytes.div_ceil(self.block_size.into());
+			// With a quota lowered below the current usage, the filesystem shows as full rather than
+			// using more than it has
+			let total_blocks = (state.total_space_bytes / u64::from(self.block_size)).max(used_blocks);
+			let total_files = state.max_files.max(used_files);
 
 			reply.statfs(
 				total_blocks,
-				free_blocks,
-				free_blocks, // Available blocks (same as free for this virtual fs)
-				self.max_files, // Total files/inodes
-				self.max_files.saturating_sub(total_files), // Free inodes
-				block_size as u32,
-				255, // Maximum name length
-				0,   // Fragment size (unused)
+				total_blocks - used_blocks,
+				total_blocks - used_blocks, // Available blocks, the same as free ones without reserved blocks
+				total_files,
+				total_files - used_files,
+				self.block_size,
+				self.name_max,
+				// Some statvfs wrappers divide by the fragment size, which has to match the block size
+				self.block_size,
 			);
 		});
 	}
 }
 
-fn hash_path(path: &str) -> u64 {
-	use std::collections::hash_map::DefaultHasher;
-	use std::hash::{Hash, Hasher};
-	let mut hasher = DefaultHasher::new();
-	path.hash(&mut hasher);
-	hasher.finish()
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::content::Content;
+
+	fn custom(option: &str) -> MountOption {
+		MountOption::CUSTOM(option.to_string())
+	}
+
+	#[test]
+	fn appending_writes_go_at_the_end_of_file() {
+		let file = VirtualFile { content: Arc::new(Content::from(b"existing".to_vec())), size: 8, ..Default::default() };
+		assert_eq!(write_offset(&file, 0, true), 8);
+		assert_eq!(write_offset(&file, 3, true), 8);
+		assert_eq!(write_offset(&file, 3, false), 3);
+		assert_eq!(write_offset(&file, 20, false), 20);
+	}
+
+	#[test]
+	fn ttl_options_are_milliseconds() {
+		assert_eq!(ttl(None), DEFAULT_TTL);
+		assert_eq!(ttl(Some(0)), Duration::ZERO);
+		assert_eq!(ttl(Some(250)), Duration::from_millis(250));
+		assert_eq!(ttl(Some(u32::MAX)), Duration::from_millis(u32::MAX.into()));
+	}
+
+	fn directory() -> VirtualFile {
+		VirtualFile { is_directory: true, ..Default::default() }
+	}
+
+	#[test]
+	fn unlink_refuses_directories_and_readonly_files() {
+		assert_eq!(unlink_error(&directory()), Some(libc::EISDIR));
+		assert_eq!(unlink_error(&VirtualFile { readonly: true, ..Default::default() }), Some(libc::EPERM));
+		assert_eq!(unlink_error(&VirtualFile { protected: true, ..Default::default() }), Some(libc::EPERM));
+		assert_eq!(unlink_error(&VirtualFile::default()), None);
+	}
+
+	#[test]
+	fn rename_replaces_only_entries_of_the_same_kind() {
+		let mut files = FileMap::new(false);
+		files.insert("empty".to_string(), directory());
+		files.insert("full".to_string(), directory());
+		files.insert("full/child".to_string(), VirtualFile::default());
+		let file = VirtualFile::default();
+		let dir = directory();
+
+		assert_eq!(replace_error(&files, &file, &dir, "empty"), Some(libc::EISDIR));
+		assert_eq!(replace_error(&files, &dir, &file, "full/child"), Some(libc::ENOTDIR));
+		assert_eq!(replace_error(&files, &dir, &dir, "full"), Some(libc::ENOTEMPTY));
+		assert_eq!(replace_error(&files, &dir, &dir, "empty"), None);
+		assert_eq!(replace_error(&files, &file, &file, "full/child"), None);
+	}
+
+	#[test]
+	fn rename_refuses_to_replace_readonly_entries() {
+		let files = FileMap::new(false);
+		let readonly = VirtualFile { readonly: true, ..Default::default() };
+
+		assert_eq!(replace_error(&files, &VirtualFile::default(), &readonly, "readonly"), Some(libc::EPERM));
+		assert_eq!(replace_error(&files, &readonly, &VirtualFile::default(), "file"), None);
+		assert_eq!(replace_error(&files, &directory(), &VirtualFile { readonly: true, ..directory() }, "dir"), Some(libc::EPERM));
+		assert_eq!(replace_error(&files, &VirtualFile::default(), &VirtualFile { protected: true, ..Default::default() }, "protected"), Some(libc::EPERM));
+	}
+
+	#[test]
+	fn mount_options_defaults() {
+		let options = mount_options(&MountOptions::default());
+		assert_eq!(options[0], MountOption::FSName("virtual".to_string()));
+		assert!(options.contains(&MountOption::DefaultPermissions));
+		assert!(options.contains(&MountOption::AutoUnmount));
+		assert!(!options.contains(&MountOption::AllowOther));
+		assert!(!options.contains(&MountOption::NoAtime));
+	}
+
+	#[test]
+	fn mount_options_map_the_flags() {
+		let options = mount_options(&MountOptions {
+			subtype: Some("demo".to_string()),
+			default_permissions: Some(false),
+			auto_unmount: Some(false),
+			allow_other: Some(true),
+			atime: Some("noatime".to_string()),
+			raw_options: Some(vec!["max_read=4096".to_string()]),
+			..Default::default()
+		});
+		assert!(options.contains(&MountOption::Subtype("demo".to_string())));
+		assert!(!options.contains(&MountOption::DefaultPermissions));
+		assert!(!options.contains(&MountOption::AutoUnmount));
+		assert!(options.contains(&MountOption::AllowOther));
+		assert!(options.contains(&MountOption::NoAtime));
+		assert_eq!(options.last(), Some(&custom("max_read=4096")));
+	}
+
+	#[cfg(target_os = "macos")]
+	#[test]
+	fn mount_options_macos_defaults() {
+		let options = mount_options(&MountOptions { fs_name: Some("data".to_string()), ..Default::default() });
+		assert!(options.contains(&custom("volname=data")));
+		assert!(options.contains(&custom("noappledouble")));
+		assert!(!options.contains(&custom("noapplexattr")));
+		assert!(options.contains(&custom("local")));
+	}
+
+	#[cfg(target_os = "macos")]
+	#[test]
+	fn mount_options_macos_overrides() {
+		let options = mount_options(&MountOptions {
+			volume_name: Some("Volume".to_string()),
+			apple_double: Some(true),
+			apple_xattr: Some(false),
+			local: Some(false),
+			..Default::default()
+		});
+		assert!(options.contains(&custom("volname=Volume")));
+		assert!(!options.contains(&custom("noappledouble")));
+		assert!(options.contains(&custom("noapplexattr")));
+		assert!(!options.contains(&custom("local")));
+	}
+}
@@ -1,70 +1,167 @@
-use crate::common::{SharedFSState, FSEvent, ObjectType};
+use crate::common::{SharedFSState, ErrorKind, FSEvent, ObjectType, SessionStats, VirtualFile, is_direct_child, join_root, materialize, resolve_missing, strip_root};
 use std::path::Path;
 use napi::bindgen_prelude::*;
+use crate::MountOptions;
+use crate::content::Content;
 use windows::Win32::Storage::ProjectedFileSystem::*;
 use windows::Win32::Foundation::*;
 use windows::core::{PCWSTR, HRESULT, GUID};
 use std::ffi::OsString;
 use std::os::windows::ffi::OsStringExt;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex, OnceLock};
 use std::collections::HashMap;
 use once_cell::sync::Lazy;
 use std::time::SystemTime;
 use uuid::Uuid;
 use std::path::PathBuf;
 
+const FILE_ATTRIBUTE_READONLY: u32 = 0x1;
 const FILE_ATTRIBUTE_DIRECTORY: u32 = 0x10;
 const FILE_ATTRIBUTE_NORMAL: u32 = 0x80;
 
+fn file_attributes(file: &VirtualFile) -> u32 {
+	let attributes = if file.is_directory { FILE_ATTRIBUTE_DIRECTORY } else { 0 };
+	let attributes = if file.readonly { attributes | FILE_ATTRIBUTE_READONLY } else { attributes };
+	// FILE_ATTRIBUTE_NORMAL is only valid on its own
+	if attributes == 0 { FILE_ATTRIBUTE_NORMAL } else { attributes }
+}
+
+// Runtime the callbacks of every instance block on, built by the first mount and never torn down
+static CALLBACK_RUNTIME: OnceLock<Option<tokio::runtime::Runtime>> = OnceLock::new();
+
+fn callback_runtime() -> Option<&'static tokio::runtime::Runtime> {
+	CALLBACK_RUNTIME
+		.get_or_init(|| tokio::runtime::Builder::new_multi_thread().worker_threads(2).enable_all().build().ok())
+		.as_ref()
+}
+
+// Symlinks have no ProjFS placeholder equivalent: they are projected as regular files
+// whose content is the link target, unlike on Unix where they resolve as real links
+
 // Global state mapping using the raw pointer value as the key
 static INSTANCE_STATES: Lazy<Mutex<HashMap<usize, SharedFSState>>> =
 	Lazy::new(|| Mutex::new(HashMap::new()));
 
-// Add this near the top with other statics
-static ENUM_STATES: Lazy<Mutex<HashMap<String, usize>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+// State key of the directory each instance shows as its root, keyed like INSTANCE_STATES
+static INSTANCE_ROOTS: Lazy<Mutex<HashMap<usize, String>>> =
+	Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Directory each instance virtualizes on the host, keyed like INSTANCE_STATES
+static INSTANCE_PATHS: Lazy<Mutex<HashMap<usize, PathBuf>>> =
+	Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Session counters, keyed like INSTANCE_STATES
+static INSTANCE_STATS: Lazy<Mutex<HashMap<usize, Arc<SessionStats>>>> =
+	Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Runtime the callbacks of each instance run on, keyed like INSTANCE_STATES
+static INSTANCE_RUNTIMES: Lazy<Mutex<HashMap<usize, &'static tokio::runtime::Runtime>>> =
+	Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Directory enumerations of each instance, keyed like INSTANCE_STATES. Enumeration ids are only
+// unique within an instance, and each instance owns its table so stopping it drops them all
+static INSTANCE_ENUMERATIONS: Lazy<Mutex<HashMap<usize, Enumerations>>> =
+	Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Progress of an instance's directory enumerations, by enumeration id
+type Enumerations = Arc<Mutex<HashMap<GUID, Enumeration>>>;
+
+#[derive(Default)]
+struct Enumeration {
+	// Index of the next child to return
+	index: usize,
+	// Search expression of the first request, null-terminated. ProjFS only passes it again on a restart
+	search: Option<Vec<u16>>,
+	// Whether `search` holds the expression of this scan, which may have been none
+	search_captured: bool,
+	// Matching children in ProjFS order, taken by the first call of a scan and paged through by the next ones
+	entries: Option<Vec<DirEntry>>,
+}
+
+// Null-terminated name and attributes of a child as the directory entry buffer takes them
+type DirEntry = (Vec<u16>, PRJ_FILE_BASIC_INFO);
+
+// What filling one directory entry buffer came to
+enum Page<E> {
+	// Every entry was already returned
+	End,
+	// Index of the first entry left for the next call
+	Filled(usize),
+	// Not even the first entry fit, ProjFS calls again with a larger buffer when given the error
+	TooSmall(E),
+}
+
+// Hands entries from `start` on to `fill` until it refuses one, the buffer being full then
+fn fill_page<T, E>(entries: &[T], start: usize, mut fill: impl FnMut(&T) -> std::result::Result<(), E>) -> Page<E> {
+	if start >= entries.len() {
+		return Page::End;
+	}
+	let mut next = start;
+	for entry in &entries[start..] {
+		if let Err(e) = fill(entry) {
+			if next == start {
+				return Page::TooSmall(e);
+			}
+			break;
+		}
+		next += 1;
+	}
+	Page::Filled(next)
+}
+
+impl Enumeration {
+	// Positions the enumeration for a get_dir_enum call and returns the index to resume from. A restart
+	// rewinds to the first child and takes the search expression of the call again
+	fn resume(&mut self, restart: bool, search: Option<Vec<u16>>) -> usize {
+		if restart {
+			self.index = 0;
+			self.search_captured = false;
+			self.entries = None;
+		}
+		if !self.search_captured {
+			self.search = search;
+			self.search_captured = true;
+		}
+		self.index
+	}
+}
 
 pub struct FSImpl {
 	sessions: HashMap<PathBuf, VirtualFS>,
 	state: SharedFSState,
-	pub total_space_bytes: u64,
-	pub max_files: u64,
 	provider_guid: GUID,
 }
 
 impl FSImpl {
 	pub fn new(state: SharedFSState) -> Self {
-		Self::with_size(state, 4 * 1024 * 1024 * 1024, 1024 * 1024)
-	}
-
-	pub fn with_size(
-		state: SharedFSState,
-		total_space_bytes: u64,
-		max_files: u64,
-	) -> Self {
 		// Generate a random UUID and convert it to Windows GUID
 		let uuid = Uuid::new_v4();
 		let provider_guid = GUID::from_values(
 			uuid.as_fields().0,
 			uuid.as_fields().1,
 			uuid.as_fields().2,
-			uuid.as_fields().3.clone(),
+			*uuid.as_fields().3,
 		);
 
 		Self {
 			sessions: HashMap::new(),
 			state,
-			total_space_bytes,
-			max_files,
 			provider_guid,
 		}
 	}
 
-	pub async fn mount(&mut self, mount_path: &Path) -> Result<()> {
+	// Only `root` and `notifications` apply here, the quota is handled by the caller and the rest is FUSE specific
+	pub async fn mount(&mut self, mount_path: &Path, options: &MountOptions) -> Result<()> {
+		// Checked here so the callbacks can count on it
+		let Some(runtime) = callback_runtime() else {
+			return Err(crate::fs_error("EIO", "could not start the runtime for the filesystem callbacks", &mount_path.to_string_lossy()));
+		};
 		let mut fs = VirtualFS::new(
+			runtime,
 			self.state.clone(),
-			self.total_space_bytes,
-			self.max_files,
 			self.provider_guid,
+			options.root.clone().unwrap_or_default(),
+			notification_mask(options.notifications.as_deref()),
 		);
 
 		match fs.start(mount_path) {
@@ -72,46 +169,131 @@ impl FSImpl {
 				self.sessions.insert(mount_path.to_path_buf(), fs);
 				Ok(())
 			},
-			Err(e) => Err(Error::from_reason(format!("Mount failed: {:?}", e)))
+			Err(e) => {
+				self.state.read().await.emit_error(ErrorKind::Mount, "mount", &mount_path.to_string_lossy(), e.message().to_string(), Some(e.code().0));
+				Err(Error::from_reason(format!("Mount failed: {:?}", e)))
+			}
 		}
 	}
 
-	pub async fn unmount(&mut self, mount_path: &Path) -> Result<()> {
+	// ProjFS can only turn an empty directory into a virtualization root. One left by an earlier
+	// mount keeps its reparse point and whatever was hydrated, it can be started again as is
+	pub fn check_mount_point(mount_path: &Path) -> Result<()> {
+		use std::os::windows::fs::MetadataExt;
+		const FILE_ATTRIBUTE_REPARSE_POINT: u32 = 0x400;
+
+		let display = mount_path.to_string_lossy();
+		let io_error = |e: std::io::Error| crate::fs_error("EIO", &e.to_string(), &display);
+		let is_virtualization_root = std::fs::metadata(mount_path).map_err(io_error)?.file_attributes() & FILE_ATTRIBUTE_REPARSE_POINT != 0;
+		if !is_virtualization_root && std::fs::read_dir(mount_path).map_err(io_error)?.next().is_some() {
+			return Err(crate::fs_error("ENOTEMPTY", "mount point is not empty", &display));
+		}
+		Ok(())
+	}
+
+	// Paths being hydrated right now, which would keep the virtualization from stopping cleanly
+	pub fn busy_paths(&self, mount_path: &Path) -> Vec<String> {
+		self.sessions.get(mount_path).map_or_else(Vec::new, |fs| fs.stats.busy_paths())
+	}
+
+	// ProjFS cancels the hydrations still in progress when virtualization stops
+	pub async fn unmount(&mut self, mount_path: &Path, force: bool) -> Result<()> {
 		if let Some(mut fs) = self.sessions.remove(mount_path) {
+			if force {
+				fs.stats.invalidate_handles();
+			}
 			fs.stop();
+			fs.stats.end();
+			self.state.read().await.emit_event(FSEvent::Unmounted { path: mount_path.to_string_lossy().into_owned() });
 		}
 		Ok(())
 	}
+
+	// A session only exists once PrjStartVirtualizing handed back an instance handle
+	pub fn is_mounted(&self, mount_path: &Path) -> bool {
+		self.sessions.contains_key(mount_path)
+	}
+
+	pub fn mounts(&self) -> Vec<(PathBuf, Arc<SessionStats>)> {
+		self.sessions.iter()
+			.map(|(path, fs)| (path.clone(), fs.stats.clone()))
+			.collect()
+	}
+
+	// Drops the on-disk placeholders ProjFS created for these paths so that consumers
+	// re-query the provider instead of serving stale hydrated content
+	pub fn invalidate_paths(&self, paths: &[String]) {
+		// Children have to go before their parent directory can be deleted
+		let mut paths: Vec<&String> = paths.iter().collect();
+		paths.sort_by_key(|path| std::cmp::Reverse(path.matches('/').count()));
+
+		for fs in self.sessions.values() {
+			for path in &paths {
+				fs.delete_placeholder(path);
+			}
+		}
+	}
+}
+
+// Notifications subscribed for each event type MountOptions.notifications can name
+fn notification_mask(event_types: Option<&[String]>) -> PRJ_NOTIFY_TYPES {
+	// Always needed, the only ones able to veto changes to read-only entries
+	let mut mask = PRJ_NOTIFY_PRE_DELETE | PRJ_NOTIFY_PRE_RENAME | PRJ_NOTIFY_FILE_PRE_CONVERT_TO_FULL;
+	let event_types: Vec<&str> = match event_types {
+		Some(event_types) => event_types.iter().map(String::as_str).collect(),
+		None => crate::NOTIFICATION_TYPES.to_vec(),
+	};
+	for event_type in event_types {
+		mask |= match event_type {
+			"created" => PRJ_NOTIFY_NEW_FILE_CREATED,
+			"modified" => PRJ_NOTIFY_FILE_OVERWRITTEN | PRJ_NOTIFY_FILE_HANDLE_CLOSED_FILE_MODIFIED,
+			"deleted" => PRJ_NOTIFY_FILE_HANDLE_CLOSED_FILE_DELETED,
+			"renamed" => PRJ_NOTIFY_FILE_RENAMED,
+			// Validated when the options were parsed
+			_ => PRJ_NOTIFY_NONE,
+		};
+	}
+	mask
 }
 
 struct VirtualFS {
+	runtime: &'static tokio::runtime::Runtime,
 	state: SharedFSState,
-	total_space_bytes: u64,
-	max_files: u64,
+	// State key of the directory shown as the virtualization root, empty for the whole tree
+	root: String,
+	notifications: PRJ_NOTIFY_TYPES,
 	instance_handle: Option<PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT>,
+	// Key of this instance in the INSTANCE_ maps, the context pointer given to ProjFS
+	instance_key: Option<usize>,
 	provider_guid: GUID,
+	stats: Arc<SessionStats>,
+	enumerations: Enumerations,
 }
 
 impl VirtualFS {
 	fn new(
+		runtime: &'static tokio::runtime::Runtime,
 		state: SharedFSState,
-		total_space_bytes: u64,
-		max_files: u64,
 		provider_guid: GUID,
+		root: String,
+		notifications: PRJ_NOTIFY_TYPES,
 	) -> Self {
 		Self {
+			runtime,
 			state,
-			total_space_bytes,
-			max_files,
+			root,
+			notifications,
 			instance_handle: None,
+			instance_key: None,
 			provider_guid,
+			stats: Arc::new(SessionStats::default()),
+			enumerations: Enumerations::default(),
 		}
 	}
 
 	fn start(&mut self, mount_path: &Path) -> windows::core::Result<()> {
 		unsafe {
 			let root_path = mount_path.to_str().unwrap();
-			let instance_handle = PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT::default();
 
 			// Convert path to wide string and ensure it stays alive
 			let root_path_wide: Vec<u16> = root_path.encode_utf16().chain(std::iter::once(0)).collect();
@@ -129,9 +311,7 @@ impl VirtualFS {
 				&self.provider_guid,
 			);
 
-			if let Err(e) = result {
-				return Err(e);
-			}
+			result?;
 
 			let callbacks = PRJ_CALLBACKS {
 				StartDirectoryEnumerationCallback: Some(Self::start_dir_enum),
@@ -143,12 +323,20 @@ impl VirtualFS {
 				..Default::default()
 			};
 
+			// Subscribe the whole root to the notifications handled in notification_callback. The root
+			// string has to outlive PrjStartVirtualizing, which copies the mappings
+			let notification_root: Vec<u16> = vec![0];
+			let mut notification_mappings = [PRJ_NOTIFICATION_MAPPING {
+				NotificationBitMask: self.notifications,
+				NotificationRoot: PCWSTR(notification_root.as_ptr()),
+			}];
+
 			let options = PRJ_STARTVIRTUALIZING_OPTIONS {
 				Flags: PRJ_STARTVIRTUALIZING_FLAGS(0),
 				PoolThreadCount: 0,
 				ConcurrentThreadCount: 0,
-				NotificationMappings: std::ptr::null_mut(),
-				NotificationMappingsCount: 0,
+				NotificationMappings: notification_mappings.as_mut_ptr(),
+				NotificationMappingsCount: notification_mappings.len() as u32,
 			};
 
 			// Store state in global map before starting virtualization
@@ -157,6 +345,21 @@ impl VirtualFS {
 				let key = state_ptr as usize;
 				states.insert(key, self.state.clone());
 			}
+			if let Ok(mut stats) = INSTANCE_STATS.lock() {
+				stats.insert(state_ptr as usize, self.stats.clone());
+			}
+			if let Ok(mut runtimes) = INSTANCE_RUNTIMES.lock() {
+				runtimes.insert(state_ptr as usize, self.runtime);
+			}
+			if let Ok(mut roots) = INSTANCE_ROOTS.lock() {
+				roots.insert(state_ptr as usize, self.root.clone());
+			}
+			if let Ok(mut paths) = INSTANCE_PATHS.lock() {
+				paths.insert(state_ptr as usize, mount_path.to_path_buf());
+			}
+			if let Ok(mut enumerations) = INSTANCE_ENUMERATIONS.lock() {
+				enumerations.insert(state_ptr as usize, self.enumerations.clone());
+			}
 
 			let result = PrjStartVirtualizing(
 				PCWSTR(root_path_wide.as_ptr()),
@@ -165,16 +368,37 @@ impl VirtualFS {
 				Some(&options),
 			);
 
-			if let Err(_) = &result {
-				// Clean up on error
-				if let Ok(mut states) = INSTANCE_STATES.lock() {
-					states.remove(&(state_ptr as usize));
+			match result {
+				Ok(instance_handle) => {
+					self.instance_handle = Some(instance_handle);
+					self.instance_key = Some(state_ptr as usize);
+					Ok(())
+				}
+				Err(e) => {
+					// Clean up on error
+					Self::forget_instance(state_ptr as usize);
+					Err(e)
 				}
-			} else {
-				self.instance_handle = Some(instance_handle);
 			}
+		}
+	}
 
-			result.map(|_| ())
+	fn delete_placeholder(&self, path: &str) {
+		// Entries outside the root never had a placeholder in this instance
+		let Some(path) = strip_root(&self.root, path) else {
+			return;
+		};
+		if let Some(handle) = self.instance_handle {
+			let path_wide: Vec<u16> = path.replace('/', "\\").encode_utf16().chain(std::iter::once(0)).collect();
+			unsafe {
+				// Paths that were never hydrated have no placeholder, so failures are expected
+				let _ = PrjDeleteFile(
+					handle,
+					PCWSTR(path_wide.as_ptr()),
+					PRJ_UPDATE_ALLOW_DIRTY_METADATA | PRJ_UPDATE_ALLOW_DIRTY_DATA | PRJ_UPDATE_ALLOW_READ_ONLY | PRJ_UPDATE_ALLOW_TOMBSTONE,
+					None,
+				);
+			}
 		}
 	}
 
@@ -184,6 +408,39 @@ impl VirtualFS {
 				PrjStopVirtualizing(handle);
 			}
 		}
+		// No callback runs once PrjStopVirtualizing returns, including end_dir_enum for enumerations
+		// still open, so whatever the instance left behind goes here
+		if let Some(key) = self.instance_key.take() {
+			Self::forget_instance(key);
+		}
+		if let Ok(mut enumerations) = self.enumerations.lock() {
+			enumerations.clear();
+		}
+	}
+
+	// Drops an instance from the INSTANCE_ maps and frees the context handed to ProjFS
+	fn forget_instance(key: usize) {
+		if let Ok(mut states) = INSTANCE_STATES.lock() {
+			states.remove(&key);
+		}
+		if let Ok(mut stats) = INSTANCE_STATS.lock() {
+			stats.remove(&key);
+		}
+		if let Ok(mut runtimes) = INSTANCE_RUNTIMES.lock() {
+			runtimes.remove(&key);
+		}
+		if let Ok(mut roots) = INSTANCE_ROOTS.lock() {
+			roots.remove(&key);
+		}
+		if let Ok(mut paths) = INSTANCE_PATHS.lock() {
+			paths.remove(&key);
+		}
+		if let Ok(mut enumerations) = INSTANCE_ENUMERATIONS.lock() {
+			enumerations.remove(&key);
+		}
+		unsafe {
+			drop(Box::from_raw(key as *mut SharedFSState));
+		}
 	}
 
 	unsafe extern "system" fn notification_callback(
@@ -193,47 +450,146 @@ impl VirtualFS {
 		_destination_file_name: PCWSTR,
 		_parameters: *mut PRJ_NOTIFICATION_PARAMETERS,
 	) -> HRESULT {
-		// Get the tokio runtime
-		if let Ok(rt) = tokio::runtime::Runtime::new() {
-			rt.block_on(async move {
+		if let Some(rt) = Self::get_runtime_from_context(_callback_data) {
+			return rt.block_on(async move {
 				let state = Self::get_state_from_context(_callback_data);
 				if let Some(state) = state {
-					let state = state.write().await;
+					let mut state = state.write().await;
 					let object_type = if _is_directory.as_bool() { ObjectType::Directory } else { ObjectType::File };
-					let file_path = Self::get_string_from_pcwstr(_destination_file_name);
+					let target_path = Self::get_state_path(_callback_data, (*_callback_data).FilePathName);
+					// Only renames come with a destination, it is null otherwise
+					let file_path = match _notification {
+						PRJ_NOTIFICATION_FILE_RENAMED => Self::get_state_path(_callback_data, _destination_file_name),
+						_ => target_path.clone(),
+					};
+
+					// Read-only entries refuse deletion and modification from the mount, protected ones only deletion,
+					// only pre-operation notifications can actually veto the operation
+					if let Some(file) = state.files.get(&target_path).filter(|file| file.readonly || file.protected) {
+						match _notification {
+							// Explorer and del then report the file as one that cannot be deleted
+							PRJ_NOTIFICATION_PRE_DELETE => return HRESULT(-805306079), // HRESULT_FROM_NT(STATUS_CANNOT_DELETE)
+							PRJ_NOTIFICATION_FILE_PRE_CONVERT_TO_FULL
+							| PRJ_NOTIFICATION_FILE_OVERWRITTEN if file.readonly => return HRESULT(-2147024891), // E_ACCESSDENIED
+							_ => {}
+						}
+					}
+					// Renaming over a read-only or protected entry would delete it, moving one away is fine
+					if _notification == PRJ_NOTIFICATION_PRE_RENAME && !Self::get_string_from_pcwstr(_destination_file_name).is_empty() {
+						let destination = state.files.key(&Self::get_state_path(_callback_data, _destination_file_name));
+						if destination != state.files.key(&target_path) && state.files.get(&destination).is_some_and(|file| file.readonly || file.protected) {
+							return HRESULT(-2147024891); // E_ACCESSDENIED
+						}
+					}
 
 					// Only emit deletion events for explicit file deletions
 					// Ignore notifications that might be from internal ProjFS operations
 					match _notification {
 						PRJ_NOTIFICATION_NEW_FILE_CREATED => {
-							state.emit_event(FSEvent::Created { path: file_path, object_type });
+							// ProjFS has no pre-creation notification to veto, so the entry already
+							// exists on disk. It is reported as an error instead of being announced
+							if state.exceeds_max_files(&file_path) {
+								state.emit_error(ErrorKind::Quota, "create", &file_path, "ENOSPC: file count quota reached", None);
+							} else if !state.has_space_for(&file_path, 0) {
+								state.emit_error(ErrorKind::Quota, "create", &file_path, "ENOSPC: no space left on device", None);
+							} else {
+								state.emit_event(FSEvent::Created { path: file_path, object_type });
+							}
+						}
+						// The file is full on disk from now on and ProjFS no longer asks for its content, so
+						// the state takes it from there. Reading it can take a while, which the notification
+						// thread does not wait for, Modified goes out once the state holds the new content
+						PRJ_NOTIFICATION_FILE_OVERWRITTEN | PRJ_NOTIFICATION_FILE_HANDLE_CLOSED_FILE_MODIFIED
+							if state.files.get(&file_path).is_some_and(|file| !file.is_directory && !file.is_symlink) =>
+						{
+							let disk_path = Self::get_disk_path(_callback_data);
+							rt.spawn(Self::sync_modified(Self::get_state_from_context(_callback_data), disk_path, file_path));
 						}
 						PRJ_NOTIFICATION_FILE_OVERWRITTEN | PRJ_NOTIFICATION_FILE_HANDLE_CLOSED_FILE_MODIFIED => {
 							state.emit_event(FSEvent::Modified { path: file_path, object_type });
 						}
-						PRJ_NOTIFICATION_PRE_DELETE => {
-							// Only emit deletion if the file was actually in our state
-							let lookup_path = file_path.replace('\\', "/");
-							if state.files.contains_key(&lookup_path) {
-								state.emit_event(FSEvent::Deleted { path: file_path, object_type });
+						// FilePathName is where the entry was, the destination where it is now. Either is empty
+						// when the entry moved across the virtualization root
+						PRJ_NOTIFICATION_FILE_RENAMED if Self::get_string_from_pcwstr(_destination_file_name).is_empty() => {
+							if let Some(removed) = state.remove_tree(&target_path) {
+								state.emit_event(FSEvent::Deleted { path: target_path, object_type: removed.get_type() });
+							}
+						}
+						PRJ_NOTIFICATION_FILE_RENAMED if Self::get_string_from_pcwstr((*_callback_data).FilePathName).is_empty() => {
+							state.emit_event(FSEvent::Created { path: file_path, object_type });
+						}
+						PRJ_NOTIFICATION_FILE_RENAMED => {
+							// The rename already happened on disk, so the state follows it, replacing whatever
+							// was at the destination the way the host's own rename does
+							let source = state.files.key(&target_path);
+							let destination = state.files.key(&file_path);
+							if source != destination && state.files.contains_key(&source) {
+								if let Some(replaced) = state.remove_tree(&destination) {
+									state.emit_event(FSEvent::Deleted { path: destination, object_type: replaced.get_type() });
+								}
 							}
-						},
+							// A single event covers the whole subtree, children are moved silently
+							let object_type = state.move_entry(&source, &file_path).unwrap_or(object_type);
+							let destination = state.files.key(&file_path);
+							state.emit_renamed(source, destination, object_type);
+						}
+						// Once the entry is actually gone, PRE_DELETE may still be followed by a failure. Only
+						// entries the state holds are reported, they leave it along with any descendants
+						PRJ_NOTIFICATION_FILE_HANDLE_CLOSED_FILE_DELETED => {
+							if let Some(removed) = state.remove_tree(&file_path) {
+								state.emit_event(FSEvent::Deleted { path: file_path, object_type: removed.get_type() });
+							}
+						}
 						_ => {}
 					}
 				}
+				HRESULT(0)
 			});
 		}
 		HRESULT(0)
 	}
 
+	// Replaces the content of a file with what a consumer wrote to it through the mount
+	async fn sync_modified(state: Option<SharedFSState>, disk_path: Option<PathBuf>, path: String) {
+		let (Some(state), Some(disk_path)) = (state, disk_path) else {
+			return;
+		};
+		let content = match tokio::fs::read(&disk_path).await {
+			Ok(data) => Arc::new(Content::from(data)),
+			Err(e) => {
+				state.read().await.emit_error(ErrorKind::Platform, "read", &path, e.to_string(), e.raw_os_error());
+				return;
+			}
+		};
+		let mtime = tokio::fs::metadata(&disk_path).await
+			.and_then(|metadata| metadata.modified())
+			.unwrap_or_else(|_| SystemTime::now());
+
+		let mut state = state.write().await;
+		if !state.has_space_for(&path, content.size()) {
+			state.emit_error(ErrorKind::Quota, "write", &path, "ENOSPC: no space left on device", None);
+			return;
+		}
+		// The entry may have gone or been replaced by a directory meanwhile
+		let Some(file) = state.files.get_mut(&path).filter(|file| !file.is_directory) else {
+			return;
+		};
+		file.size = content.size();
+		file.content = content;
+		file.mtime = mtime;
+		file.provider = None;
+		state.emit_event(FSEvent::Modified { path, object_type: ObjectType::File });
+	}
+
 	unsafe extern "system" fn start_dir_enum(
 		_callback_data: *const PRJ_CALLBACK_DATA,
 		_enumeration_id: *const GUID,
 	) -> HRESULT {
 		// Initialize enumeration state
-		let guid_str = format!("{:?}", unsafe { *_enumeration_id });
-		if let Ok(mut states) = ENUM_STATES.lock() {
-			states.insert(guid_str, 0);
+		if let Some(enumerations) = Self::get_enumerations_from_context(_callback_data) {
+			if let Ok(mut enumerations) = enumerations.lock() {
+				enumerations.insert(unsafe { *_enumeration_id }, Enumeration::default());
+			}
 		}
 		HRESULT(0)
 	}
@@ -243,9 +599,10 @@ impl VirtualFS {
 		_enumeration_id: *const GUID,
 	) -> HRESULT {
 		// Clean up enumeration state
-		let guid_str = format!("{:?}", unsafe { *_enumeration_id });
-		if let Ok(mut states) = ENUM_STATES.lock() {
-			states.remove(&guid_str);
+		if let Some(enumerations) = Self::get_enumerations_from_context(_callback_data) {
+			if let Ok(mut enumerations) = enumerations.lock() {
+				enumerations.remove(unsafe { &*_enumeration_id });
+			}
 		}
 		HRESULT(0)
 	}
@@ -256,113 +613,114 @@ impl VirtualFS {
 		_search_expression: PCWSTR,
 		dir_entry_buffer_handle: PRJ_DIR_ENTRY_BUFFER_HANDLE,
 	) -> HRESULT {
-		let guid_str = format!("{:?}", unsafe { *_enumeration_id });
-
-		if let Ok(rt) = tokio::runtime::Runtime::new() {
-			return rt.block_on(async move {
-				let state = Self::get_state_from_context(_callback_data);
-				if let Some(state) = state {
-					let state = state.read().await;
-					let parent_path = Self::get_string_from_pcwstr((*_callback_data).FilePathName).replace('\\', "/");
-
-					// Get current index for this enumeration
-					let mut current_index = 0;
-					if let Ok(states) = ENUM_STATES.lock() {
-						current_index = *states.get(&guid_str).unwrap_or(&0);
-					}
-
-					// First collect all direct children
-					let mut children = Vec::new();
-					for (path, file) in state.files.iter() {
-						let is_direct_child = if parent_path.is_empty() {
-							!path.contains('/')
-						} else {
-							path.starts_with(&format!("{}/", parent_path)) &&
-							path[parent_path.len()+1..].split('/').count() == 1
-						};
-
-						if is_direct_child {
-							let name = path.split('/').last().unwrap();
-							children.push((name.to_string(), file));
-						}
-					}
-
-					// If we've sent all entries, clean up and return STATUS_END_OF_FILE
-					if current_index >= children.len() {
-						if let Ok(mut states) = ENUM_STATES.lock() {
-							states.remove(&guid_str);
+		let enumeration_id = unsafe { *_enumeration_id };
+		let Some(enumerations) = Self::get_enumerations_from_context(_callback_data) else {
+			return HRESULT(-2147483633); // STATUS_END_OF_FILE
+		};
+
+		// Where to resume, along with the expression children are matched against, "*.dll" and the like
+		let restart = (*_callback_data).Flags.0 & PRJ_CB_DATA_FLAG_ENUM_RESTART_SCAN.0 != 0;
+		let search_expression = Some(_search_expression)
+			.filter(|search| !search.is_null() && *search.0 != 0)
+			.map(|search| Self::get_string_from_pcwstr(search).encode_utf16().chain(std::iter::once(0)).collect());
+		let (search, listed) = match enumerations.lock() {
+			Ok(mut enumerations) => {
+				let enumeration = enumerations.entry(enumeration_id).or_default();
+				enumeration.resume(restart, search_expression);
+				(enumeration.search.clone(), enumeration.entries.is_some())
+			}
+			Err(_) => return HRESULT(-2147483633), // STATUS_END_OF_FILE
+		};
+
+		// The children are listed once per scan, later calls page through that listing so the state is
+		// neither walked nor sorted again for every buffer
+		if !listed {
+			let Some(state) = Self::get_state_from_context(_callback_data) else {
+				return HRESULT(-2147483633); // STATUS_END_OF_FILE
+			};
+			let Some(rt) = Self::get_runtime_from_context(_callback_data) else {
+				return HRESULT(0);
+			};
+			let entries = rt.block_on(async move {
+				let state = state.read().await;
+				// Children are matched by prefix, which has to use the stored casing
+				let parent_path = state.files.key(&Self::get_state_path(_callback_data, (*_callback_data).FilePathName));
+
+				let mut entries: Vec<DirEntry> = Vec::new();
+				for (path, file) in state.files.iter() {
+					if is_direct_child(&parent_path, path) {
+						let name = path.rsplit('/').next().unwrap();
+						let name_wide: Vec<u16> = name.encode_utf16().chain(std::iter::once(0)).collect();
+						if let Some(search) = &search {
+							if !PrjFileNameMatch(PCWSTR(name_wide.as_ptr()), PCWSTR(search.as_ptr())).as_bool() {
+								continue;
+							}
 						}
-						return HRESULT(-2147483633); // STATUS_END_OF_FILE
-					}
-
-					// Add the next child to the buffer
-					let (name, file) = &children[current_index];
-					let name_wide: Vec<u16> = name.encode_utf16().chain(std::iter::once(0)).collect();
-
-					let file_info = PRJ_FILE_BASIC_INFO {
-						IsDirectory: BOOLEAN::from(file.is_directory),
-						FileSize: file.size as i64,
-						CreationTime: Self::system_time_to_file_time(file.mtime),
-						LastAccessTime: Self::system_time_to_file_time(file.mtime),
-						LastWriteTime: Self::system_time_to_file_time(file.mtime),
-						ChangeTime: Self::system_time_to_file_time(file.mtime),
-						FileAttributes: if file.is_directory {
-							FILE_ATTRIBUTE_DIRECTORY
-						} else {
-							FILE_ATTRIBUTE_NORMAL
-						},
-						..Default::default()
-					};
-
-					let result = PrjFillDirEntryBuffer(
-						PCWSTR(name_wide.as_ptr()),
-						Some(&file_info),
-						dir_entry_buffer_handle,
-					);
-
-					if result.is_err() {
-						return HRESULT(-2147024896); // E_FAIL
-					}
-
-					// Update the index for next time
-					if let Ok(mut states) = ENUM_STATES.lock() {
-						states.insert(guid_str, current_index + 1);
+						let file_info = PRJ_FILE_BASIC_INFO {
+							IsDirectory: BOOLEAN::from(file.is_directory),
+							FileSize: file.size as i64,
+							CreationTime: Self::system_time_to_file_time(file.mtime),
+							LastAccessTime: Self::system_time_to_file_time(file.atime),
+							LastWriteTime: Self::system_time_to_file_time(file.mtime),
+							ChangeTime: Self::system_time_to_file_time(file.mtime),
+							FileAttributes: file_attributes(file),
+						};
+						entries.push((name_wide, file_info));
 					}
-
-					HRESULT(0)
-				} else {
-					HRESULT(-2147483633) // STATUS_END_OF_FILE
 				}
+				// ProjFS expects entries in its own file name order, which differs from a plain case-insensitive
+				// comparison for some characters
+				entries.sort_by(|(a, _), (b, _)| PrjFileNameCompare(PCWSTR(a.as_ptr()), PCWSTR(b.as_ptr())).cmp(&0));
+				entries
 			});
+			if let Ok(mut enumerations) = enumerations.lock() {
+				enumerations.entry(enumeration_id).or_default().entries = Some(entries);
+			}
+		}
+
+		let Ok(mut enumerations) = enumerations.lock() else {
+			return HRESULT(-2147483633); // STATUS_END_OF_FILE
+		};
+		let enumeration = enumerations.entry(enumeration_id).or_default();
+		let entries = enumeration.entries.as_deref().unwrap_or_default();
+		// Add as many children as the buffer holds, the rest goes to the next call
+		let page = fill_page(entries, enumeration.index, |(name_wide, file_info)| {
+			PrjFillDirEntryBuffer(PCWSTR(name_wide.as_ptr()), Some(file_info), dir_entry_buffer_handle)
+		});
+		match page {
+			// The listing stays until end_dir_enum so a further call without a restart ends again rather than starting over
+			Page::End => HRESULT(-2147483633), // STATUS_END_OF_FILE
+			Page::Filled(next_index) => {
+				enumeration.index = next_index;
+				HRESULT(0)
+			}
+			Page::TooSmall(e) => e.code(),
 		}
-		HRESULT(0)
 	}
 
 	unsafe extern "system" fn get_placeholder_info(
 		_callback_data: *const PRJ_CALLBACK_DATA,
 	) -> HRESULT {
-		if let Ok(rt) = tokio::runtime::Runtime::new() {
+		if let Some(rt) = Self::get_runtime_from_context(_callback_data) {
 			return rt.block_on(async move {
 				let state = Self::get_state_from_context(_callback_data);
 				if let Some(state) = state {
-					let state = state.read().await;
-					let path = Self::get_string_from_pcwstr((*_callback_data).FilePathName).replace('\\', "/");
+					let path = Self::get_state_path(_callback_data, (*_callback_data).FilePathName);
 
+					// Paths the state doesn't know about may still be provided by the resolver
+					resolve_missing(&state, &path).await;
+
+					let state = state.read().await;
 					if let Some(file) = state.files.get(&path) {
 						let placeholder_info = PRJ_PLACEHOLDER_INFO {
 							FileBasicInfo: PRJ_FILE_BASIC_INFO {
 								IsDirectory: BOOLEAN::from(file.is_directory),
 								FileSize: file.size as i64,
 								CreationTime: Self::system_time_to_file_time(file.mtime),
-								LastAccessTime: Self::system_time_to_file_time(file.mtime),
+								LastAccessTime: Self::system_time_to_file_time(file.atime),
 								LastWriteTime: Self::system_time_to_file_time(file.mtime),
 								ChangeTime: Self::system_time_to_file_time(file.mtime),
-								FileAttributes: if file.is_directory {
-									FILE_ATTRIBUTE_DIRECTORY
-								} else {
-									FILE_ATTRIBUTE_NORMAL
-								},
-								..Default::default()
+								FileAttributes: file_attributes(file),
 							},
 							VariableData: [0; 1],
 							EaInformation: Default::default(),
@@ -374,14 +732,18 @@ impl VirtualFS {
 							},
 						};
 
-						if PrjWritePlaceholderInfo(
+						if let Err(e) = PrjWritePlaceholderInfo(
 							(*_callback_data).NamespaceVirtualizationContext,
 							(*_callback_data).FilePathName,
 							&placeholder_info,
 							std::mem::size_of::<PRJ_PLACEHOLDER_INFO>() as u32,
-						).is_err() {
+						) {
+							state.emit_error(ErrorKind::Platform, "writePlaceholderInfo", &path, e.message().to_string(), Some(e.code().0));
 							return HRESULT(-2147024896); // E_FAIL
 						}
+						if state.access_events_enabled() {
+							state.emit_event(FSEvent::Accessed { path, bytes: 0 });
+						}
 						return HRESULT(0);
 					}
 					return HRESULT(-2147024894); // E_FILE_NOT_FOUND
@@ -397,19 +759,40 @@ impl VirtualFS {
 		_byte_offset: u64,
 		_length: u32,
 	) -> HRESULT {
-		if let Ok(rt) = tokio::runtime::Runtime::new() {
+		// The session counts as busy until the content has been handed over
+		let hydration = Self::get_stats_from_context(_callback_data).map(|stats| {
+			let path = Self::get_state_path(_callback_data, (*_callback_data).FilePathName);
+			let handle = stats.open_handle(path);
+			(stats, handle)
+		});
+		let result = Self::write_file_data(_callback_data, _byte_offset, _length);
+		if let Some((stats, handle)) = hydration {
+			stats.close_handle(handle);
+		}
+		result
+	}
+
+	unsafe fn write_file_data(
+		_callback_data: *const PRJ_CALLBACK_DATA,
+		_byte_offset: u64,
+		_length: u32,
+	) -> HRESULT {
+		if let Some(rt) = Self::get_runtime_from_context(_callback_data) {
 			return rt.block_on(async move {
 				let state = Self::get_state_from_context(_callback_data);
 				if let Some(state) = state {
-					let state = state.read().await;
-					let path = Self::get_string_from_pcwstr((*_callback_data).FilePathName).replace('\\', "/");
+					let path = Self::get_state_path(_callback_data, (*_callback_data).FilePathName);
 
-					if let Some(file) = state.files.get(&path) {
-						let start = _byte_offset as usize;
-						let end = std::cmp::min(start + _length as usize, file.content.len());
+					// Lazy files are fetched from their provider on first read
+					if materialize(&state, &path).await.is_err() {
+						return HRESULT(-2147023779); // HRESULT_FROM_WIN32(ERROR_IO_DEVICE)
+					}
 
-						if start < file.content.len() {
-							let data = &file.content[start..end];
+					// Handed to ProjFS once the state lock is released
+					let content = state.read().await.files.get(&path).map(|file| file.content.clone());
+					if let Some(content) = content {
+						if _byte_offset < content.size() {
+							let data = content.read(_byte_offset, _length as usize);
 							let result = PrjWriteFileData(
 								(*_callback_data).NamespaceVirtualizationContext,
 								&(*_callback_data).DataStreamId,
@@ -417,9 +800,19 @@ impl VirtualFS {
 								_byte_offset,
 								data.len() as u32,
 							);
-							if result.is_err() {
+							let state = state.read().await;
+							if let Err(e) = result {
+								state.emit_error(ErrorKind::Platform, "writeFileData", &path, e.message().to_string(), Some(e.code().0));
 								return HRESULT(-2147024896); // E_FAIL
 							}
+							if let Some(stats) = Self::get_stats_from_context(_callback_data) {
+								stats.record_read(_byte_offset, data.len());
+							}
+							// ProjFS only asks for the content once, when the file is hydrated,
+							// so the first chunk stands for the whole request
+							if _byte_offset == 0 && state.access_events_enabled() {
+								state.emit_event(FSEvent::Accessed { path: path.clone(), bytes: _length as u64 });
+							}
 						}
 					}
 				}
@@ -431,6 +824,9 @@ impl VirtualFS {
 
 	// Helper function to convert Windows wide string to Rust String
 	fn get_string_from_pcwstr(pcwstr: PCWSTR) -> String {
+		if pcwstr.is_null() {
+			return String::new();
+		}
 		unsafe {
 			let len = (0..).take_while(|&i| *pcwstr.0.add(i) != 0).count();
 			let slice = std::slice::from_raw_parts(pcwstr.0, len);
@@ -438,6 +834,25 @@ impl VirtualFS {
 		}
 	}
 
+	// Converts a path ProjFS gives relative to the virtualization root into a state key
+	fn get_state_path(callback_data: *const PRJ_CALLBACK_DATA, name: PCWSTR) -> String {
+		let path = Self::get_string_from_pcwstr(name).replace('\\', "/");
+		let root = unsafe {
+			let key = (*callback_data).InstanceContext as usize;
+			INSTANCE_ROOTS.lock().ok().and_then(|roots| roots.get(&key).cloned()).unwrap_or_default()
+		};
+		join_root(&root, &path)
+	}
+
+	// Where the entry a callback is about lives on the host, below the virtualized directory
+	fn get_disk_path(callback_data: *const PRJ_CALLBACK_DATA) -> Option<PathBuf> {
+		unsafe {
+			let key = (*callback_data).InstanceContext as usize;
+			let mount_path = INSTANCE_PATHS.lock().ok()?.get(&key).cloned()?;
+			Some(mount_path.join(Self::get_string_from_pcwstr((*callback_data).FilePathName)))
+		}
+	}
+
 	// Helper function to get state from callback context
 	fn get_state_from_context(callback_data: *const PRJ_CALLBACK_DATA) -> Option<SharedFSState> {
 		unsafe {
@@ -456,6 +871,36 @@ impl VirtualFS {
 		}
 	}
 
+	fn get_enumerations_from_context(callback_data: *const PRJ_CALLBACK_DATA) -> Option<Enumerations> {
+		unsafe {
+			let context_ptr = (*callback_data).InstanceContext;
+			if context_ptr.is_null() {
+				return None;
+			}
+			INSTANCE_ENUMERATIONS.lock().ok()?.get(&(context_ptr as usize)).cloned()
+		}
+	}
+
+	fn get_runtime_from_context(callback_data: *const PRJ_CALLBACK_DATA) -> Option<&'static tokio::runtime::Runtime> {
+		unsafe {
+			let context_ptr = (*callback_data).InstanceContext;
+			if context_ptr.is_null() {
+				return None;
+			}
+			INSTANCE_RUNTIMES.lock().ok()?.get(&(context_ptr as usize)).copied()
+		}
+	}
+
+	fn get_stats_from_context(callback_data: *const PRJ_CALLBACK_DATA) -> Option<Arc<SessionStats>> {
+		unsafe {
+			let context_ptr = (*callback_data).InstanceContext;
+			if context_ptr.is_null() {
+				return None;
+			}
+			INSTANCE_STATS.lock().ok()?.get(&(context_ptr as usize)).cloned()
+		}
+	}
+
 	fn system_time_to_file_time(time: SystemTime) -> i64 {
 		// Windows FILETIME is in 100-nanosecond intervals since January 1, 1601 UTC
 		// First convert to duration since Unix epoch
@@ -466,4 +911,79 @@ impl VirtualFS {
 		const WINDOWS_UNIX_EPOCH_DIFF: i64 = 116444736000000000;
 		(duration.as_nanos() as i64 / 100) + WINDOWS_UNIX_EPOCH_DIFF
 	}
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn wide(text: &str) -> Option<Vec<u16>> {
+		Some(text.encode_utf16().chain(std::iter::once(0)).collect())
+	}
+
+	#[test]
+	fn search_is_taken_from_the_first_call_only() {
+		let mut enumeration = Enumeration::default();
+		assert_eq!(enumeration.resume(false, wide("*.txt")), 0);
+		enumeration.index = 3;
+		assert_eq!(enumeration.resume(false, None), 3);
+		assert_eq!(enumeration.search, wide("*.txt"));
+	}
+
+	#[test]
+	fn missing_search_stays_missing_while_nothing_was_returned() {
+		let mut enumeration = Enumeration::default();
+		enumeration.resume(false, None);
+		// Still at the first child, e.g. after a buffer too small for it, which must not pick up a new expression
+		assert_eq!(enumeration.resume(false, wide("*.txt")), 0);
+		assert_eq!(enumeration.search, None);
+	}
+
+	#[test]
+	fn restart_drops_the_listing() {
+		let mut enumeration = Enumeration { entries: Some(Vec::new()), ..Default::default() };
+		enumeration.resume(false, None);
+		assert!(enumeration.entries.is_some());
+		enumeration.resume(true, None);
+		assert!(enumeration.entries.is_none());
+	}
+
+	// Stands for a directory entry buffer with room for `room` entries
+	fn buffer(room: usize) -> impl FnMut(&u32) -> std::result::Result<(), &'static str> {
+		let mut used = 0;
+		move |_| {
+			if used == room {
+				return Err("insufficient buffer");
+			}
+			used += 1;
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn a_listing_larger_than_a_buffer_is_paged_through() {
+		let entries: Vec<u32> = (0..5).collect();
+		assert!(matches!(fill_page(&entries, 0, buffer(2)), Page::Filled(2)));
+		assert!(matches!(fill_page(&entries, 2, buffer(2)), Page::Filled(4)));
+		assert!(matches!(fill_page(&entries, 4, buffer(2)), Page::Filled(5)));
+		assert!(matches!(fill_page(&entries, 5, buffer(2)), Page::End));
+	}
+
+	#[test]
+	fn a_buffer_without_room_for_one_entry_is_too_small() {
+		let entries: Vec<u32> = (0..5).collect();
+		assert!(matches!(fill_page(&entries, 1, buffer(0)), Page::TooSmall("insufficient buffer")));
+		assert!(matches!(fill_page(&Vec::<u32>::new(), 0, buffer(0)), Page::End));
+	}
+
+	#[test]
+	fn restart_rewinds_and_takes_the_new_search() {
+		let mut enumeration = Enumeration::default();
+		enumeration.resume(false, wide("*.txt"));
+		enumeration.index = 5;
+		assert_eq!(enumeration.resume(true, wide("*.dll")), 0);
+		assert_eq!(enumeration.search, wide("*.dll"));
+		assert_eq!(enumeration.resume(true, None), 0);
+		assert_eq!(enumeration.search, None);
+	}
+}
@@ -0,0 +1,14 @@
+const { test } = require('node:test')
+const assert = require('node:assert')
+const { FuseFS, mountFS, shell } = require('./helpers')
+
+test('appending through the mount writes at the end of file', async (t) => {
+  const vfs = new FuseFS()
+  await vfs.addFile('log', Buffer.from('first\n'))
+  const mountPath = await mountFS(t, vfs)
+  if (!mountPath) return
+
+  await shell('echo second >> log', mountPath)
+  await shell('echo third >> log', mountPath)
+  assert.strictEqual((await vfs.readFile('log')).toString(), 'first\nsecond\nthird\n')
+})
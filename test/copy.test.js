@@ -0,0 +1,43 @@
+const { test } = require('node:test')
+const assert = require('node:assert')
+const { FuseFS } = require('./helpers')
+
+test('copyPath creates the missing parents of the destination', async () => {
+  const vfs = new FuseFS()
+  await vfs.addFile('src/file', Buffer.from('data'))
+
+  await vfs.copyPath('src', 'a/b/copy', { recursive: true })
+  assert.strictEqual((await vfs.readFile('a/b/copy/file')).toString(), 'data')
+  assert.strictEqual(await vfs.exists('a/b'), 'directory')
+})
+
+test('copyPath without createParents rejects a missing parent', async () => {
+  const vfs = new FuseFS()
+  await vfs.addFile('file', Buffer.from('data'))
+
+  await assert.rejects(vfs.copyPath('file', 'missing/copy', { createParents: false }), (e) => e.message.startsWith('ENOENT'))
+  assert.strictEqual(await vfs.exists('missing'), null)
+})
+
+test('copyPath counts the created parents against maxFiles', async () => {
+  const vfs = new FuseFS()
+  await vfs.addFile('file', Buffer.from('data'))
+  await vfs.setQuota({ maxFiles: 3 })
+
+  await assert.rejects(vfs.copyPath('file', 'a/b/copy'), (e) => e.message.startsWith('ENOSPC'))
+  assert.strictEqual(await vfs.exists('a'), null)
+  await vfs.copyPath('file', 'a/copy')
+})
+
+test('created parents count against totalSpaceBytes', async () => {
+  const vfs = new FuseFS()
+  await vfs.addFile('file', Buffer.from(''))
+  await vfs.setQuota({ totalSpaceBytes: 800 })
+
+  await assert.rejects(vfs.addFile('a/b/c/d/e/f/g/h', Buffer.from('')), (e) => e.message.startsWith('ENOSPC'))
+  await assert.rejects(vfs.copyPath('file', 'a/b/copy'), (e) => e.message.startsWith('ENOSPC'))
+  assert.strictEqual(await vfs.exists('a'), null)
+  await vfs.copyPath('file', 'a/copy')
+  const usage = await vfs.getUsage()
+  assert.ok(usage.usedBytes <= usage.totalBytes)
+})
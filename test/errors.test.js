@@ -0,0 +1,88 @@
+const { test } = require('node:test')
+const assert = require('node:assert')
+const { FuseFS, mountFS, shell } = require('./helpers')
+
+// Runs `call` in Python and prints the name of the errno it fails with
+function errnoOf(call) {
+  return `python3 -c 'import errno, os
+try:
+    ${call}
+    print("ok")
+except OSError as e:
+    print(errno.errorcode[e.errno])'`
+}
+
+test('type mismatches fail with the POSIX errors', async (t) => {
+  const vfs = new FuseFS()
+  await vfs.addFile('file', Buffer.from('data'))
+  await vfs.addFile('other', Buffer.from('data'))
+  await vfs.addFile('full/child', Buffer.from('data'))
+  await vfs.addDirectory('empty')
+  const mountPath = await mountFS(t, vfs)
+  if (!mountPath) return
+
+  const errno = async (call) => (await shell(errnoOf(call), mountPath)).trim()
+  assert.strictEqual(await errno('os.unlink("empty")'), 'EISDIR')
+  assert.strictEqual(await errno('os.mkdir("file")'), 'EEXIST')
+  assert.strictEqual(await errno('os.rename("file", "empty")'), 'EISDIR')
+  assert.strictEqual(await errno('os.rename("empty", "file")'), 'ENOTDIR')
+  assert.strictEqual(await errno('os.rename("empty", "full")'), 'ENOTEMPTY')
+  assert.strictEqual(await errno('os.rmdir("file")'), 'ENOTDIR')
+  assert.strictEqual(await errno('os.rename("file", "other")'), 'ok')
+})
+
+test('renaming over a read-only file fails like unlinking it', async (t) => {
+  const vfs = new FuseFS()
+  await vfs.addFile('file', Buffer.from('data'))
+  await vfs.addFile('readonly', Buffer.from('kept'))
+  await vfs.setReadonly('readonly', true)
+  const mountPath = await mountFS(t, vfs)
+  if (!mountPath) return
+
+  const errno = async (call) => (await shell(errnoOf(call), mountPath)).trim()
+  assert.strictEqual(await errno('os.unlink("readonly")'), 'EPERM')
+  assert.strictEqual(await errno('os.rename("file", "readonly")'), 'EPERM')
+  assert.strictEqual((await vfs.readFile('readonly')).toString(), 'kept')
+  // Moving the read-only file itself away deletes nothing
+  assert.strictEqual(await errno('os.rename("readonly", "moved")'), 'ok')
+})
+
+test('protected entries refuse deletion but stay writable', async (t) => {
+  const vfs = new FuseFS()
+  await vfs.addFile('file', Buffer.from('data'))
+  await vfs.addFile('protected', Buffer.from('kept'))
+  await vfs.addDirectory('dir')
+  await vfs.setProtected('protected', true)
+  await vfs.setProtected('dir', true)
+  assert.strictEqual((await vfs.stat('protected')).protected, true)
+  const mountPath = await mountFS(t, vfs)
+  if (!mountPath) return
+
+  const errno = async (call) => (await shell(errnoOf(call), mountPath)).trim()
+  assert.strictEqual(await errno('os.unlink("protected")'), 'EPERM')
+  assert.strictEqual(await errno('os.rmdir("dir")'), 'EPERM')
+  assert.strictEqual(await errno('os.rename("file", "protected")'), 'EPERM')
+  assert.strictEqual(await errno('open("protected", "a").write("!")'), 'ok')
+  assert.strictEqual((await vfs.readFile('protected')).toString(), 'kept!')
+})
+
+test('addFile refuses to replace a directory', async () => {
+  const vfs = new FuseFS()
+  await vfs.addFile('dir/child', Buffer.from('data'))
+
+  await assert.rejects(vfs.addFile('dir', Buffer.from('file')), (e) => e.message.startsWith('EISDIR'))
+  assert.strictEqual(await vfs.exists('dir'), 'directory')
+  assert.strictEqual((await vfs.readFile('dir/child')).toString(), 'data')
+})
+
+test('writeFileAt rejects a negative offset before fetching a lazy file', async () => {
+  const vfs = new FuseFS()
+  let fetched = false
+  await vfs.addLazyFile('lazy', 4, () => {
+    fetched = true
+    return Buffer.from('data')
+  })
+
+  await assert.rejects(vfs.writeFileAt('lazy', -1, Buffer.from('x')), (e) => e.message.startsWith('EINVAL'))
+  assert.strictEqual(fetched, false)
+})
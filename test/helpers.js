@@ -0,0 +1,46 @@
+const fs = require('node:fs')
+const os = require('node:os')
+const path = require('node:path')
+const { setTimeout } = require('node:timers/promises')
+const { promisify } = require('node:util')
+const execFile = promisify(require('node:child_process').execFile)
+const { FuseFS } = require('../index.js')
+
+// Mounts `vfs` on a fresh temporary directory, unmounted and removed once the test ends. Resolves to
+// null after skipping the test where FUSE is not available. Auto unmount is off unless asked for, it
+// needs fusermount3 which root mounts can do without
+async function mountFS(t, vfs, options) {
+  const mountPath = fs.mkdtempSync(path.join(os.tmpdir(), 'projected-fs-'))
+  try {
+    await vfs.mount(mountPath, { autoUnmount: false, ...options })
+  } catch (e) {
+    fs.rmdirSync(mountPath)
+    if (e.message.startsWith('ENODEV')) {
+      t.skip('FUSE is not available')
+      return null
+    }
+    throw e
+  }
+  t.after(async () => {
+    // The kernel releases the handles commands had open after they exit, the mount stays busy until then
+    for (let attempt = 1; vfs.isMounted(); attempt++) {
+      try {
+        await vfs.unmount()
+      } catch (e) {
+        if (!e.message.startsWith('EBUSY') || attempt === 100) throw e
+        await setTimeout(10)
+      }
+    }
+    fs.rmdirSync(mountPath)
+  })
+  return mountPath
+}
+
+// Runs a shell command in `cwd`, resolving to its output. Never blocks the JS thread, which providers
+// and event listeners called by the mount need
+async function shell(command, cwd) {
+  const { stdout } = await execFile('sh', ['-c', command], { cwd })
+  return stdout
+}
+
+module.exports = { FuseFS, mountFS, shell }
@@ -0,0 +1,27 @@
+const { test } = require('node:test')
+const assert = require('node:assert')
+const { FuseFS, mountFS, shell } = require('./helpers')
+
+test('a provider that never settles stalls neither the mount nor a forced unmount', async (t) => {
+  const vfs = new FuseFS()
+  let called
+  const providerCalled = new Promise((resolve) => { called = resolve })
+  await vfs.addLazyFile('stuck', 5, () => {
+    called()
+    return new Promise(() => {})
+  })
+  await vfs.addFile('other', Buffer.from('ok'))
+  const mountPath = await mountFS(t, vfs)
+  if (!mountPath) return
+
+  // cat retries reads failing with EINTR, which then find the handle invalidated
+  const stuck = assert.rejects(shell('cat stuck', mountPath), /Interrupted system call|Bad file descriptor/)
+  await providerCalled
+  assert.strictEqual(await shell('cat other', mountPath), 'ok')
+
+  await vfs.unmount({ force: true })
+  await stuck
+  // Without autoUnmount nothing detaches a mount the forced unmount left busy, fusermount would have.
+  // It is already gone when the kernel released the handle before the session was dropped
+  await shell(`! mountpoint -q ${mountPath} || umount -l ${mountPath}`, '/')
+})
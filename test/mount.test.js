@@ -0,0 +1,27 @@
+const { test } = require('node:test')
+const assert = require('node:assert')
+const fs = require('node:fs')
+const os = require('node:os')
+const path = require('node:path')
+const { FuseFS, mountFS } = require('./helpers')
+
+test('mount resolves once the path is served', async (t) => {
+  const vfs = new FuseFS()
+  await vfs.addFile('file', Buffer.from('served'))
+  const mountPath = await mountFS(t, vfs)
+  if (!mountPath) return
+
+  // No waiting in between, the mount has to be there as soon as the promise resolved
+  const mounts = fs.readFileSync('/proc/self/mounts', 'utf8').split('\n').map((line) => line.split(' ')[1])
+  assert.ok(mounts.includes(mountPath))
+  assert.ok(vfs.isMounted())
+  assert.strictEqual((await fs.promises.readFile(path.join(mountPath, 'file'))).toString(), 'served')
+})
+
+test('mount rejects when the path cannot be served', async () => {
+  const vfs = new FuseFS()
+  const missing = path.join(os.tmpdir(), 'projected-fs-missing', 'mount')
+
+  await assert.rejects(vfs.mount(missing, { autoUnmount: false }))
+  assert.ok(!vfs.isMounted())
+})
@@ -0,0 +1,17 @@
+const { test } = require('node:test')
+const assert = require('node:assert')
+const { FuseFS, mountFS, shell } = require('./helpers')
+
+test('reads starting at or past the end of file come back empty', async (t) => {
+  const vfs = new FuseFS()
+  await vfs.addFile('file', Buffer.from('data'))
+  const mountPath = await mountFS(t, vfs)
+  if (!mountPath) return
+
+  const script = [
+    'import os',
+    'fd = os.open("file", os.O_RDONLY)',
+    'print(os.pread(fd, 10, 2), os.pread(fd, 10, 4), os.pread(fd, 10, 1 << 40))'
+  ].join('\n')
+  assert.strictEqual(await shell(`python3 -c '${script}'`, mountPath), "b'ta' b'' b''\n")
+})
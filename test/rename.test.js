@@ -0,0 +1,22 @@
+const { test } = require('node:test')
+const assert = require('node:assert')
+const { FuseFS } = require('./helpers')
+
+test('renamePath refuses to replace an ancestor of the source', async () => {
+  const vfs = new FuseFS()
+  await vfs.addFile('a/b', Buffer.from('data'))
+
+  await assert.rejects(vfs.renamePath('a/b', 'a', true), (e) => e.message.startsWith('ENOTEMPTY'))
+  assert.strictEqual((await vfs.readFile('a/b')).toString(), 'data')
+})
+
+test('renamePath requires the parent of the destination', async () => {
+  const vfs = new FuseFS()
+  await vfs.addFile('x', Buffer.from('data'))
+  await vfs.addFile('file', Buffer.from('data'))
+
+  await assert.rejects(vfs.renamePath('x', 'no/such/parent/x'), (e) => e.message.startsWith('ENOENT'))
+  await assert.rejects(vfs.renamePath('x', 'file/x'), (e) => e.message.startsWith('ENOTDIR'))
+  assert.strictEqual(await vfs.exists('x'), 'file')
+  assert.strictEqual(await vfs.exists('no/such/parent/x'), null)
+})
@@ -0,0 +1,39 @@
+const { test } = require('node:test')
+const assert = require('node:assert')
+const fs = require('node:fs')
+const { FuseFS, mountFS, shell } = require('./helpers')
+
+// Threads of this process started by a tokio runtime, mount and session threads spawned from one included
+function runtimeThreads() {
+  return fs.readdirSync('/proc/self/task')
+    .filter((task) => fs.readFileSync(`/proc/self/task/${task}/comm`, 'utf8').startsWith('tokio-runtime'))
+    .length
+}
+
+test('callbacks of every mount run on one shared runtime', async (t) => {
+  const first = new FuseFS()
+  let duringProvider
+  await first.addLazyFile('lazy', 4, () => {
+    duringProvider = runtimeThreads()
+    return Buffer.from('lazy')
+  })
+  await first.addFile('file', Buffer.from('data'))
+  const second = new FuseFS()
+  await second.addFile('file', Buffer.from('data'))
+
+  const beforeMounts = runtimeThreads()
+  const firstPath = await mountFS(t, first)
+  if (!firstPath) return
+  await shell('ls; cat file', firstPath)
+  const firstMount = runtimeThreads()
+
+  // A callback building a runtime of its own would still have it while waiting on the provider
+  assert.strictEqual(await shell('cat lazy', firstPath), 'lazy')
+  assert.strictEqual(duringProvider, firstMount)
+
+  const secondPath = await mountFS(t, second)
+  if (!secondPath) return
+  await shell('ls; cat file', secondPath)
+  // The second mount only adds its own mount and session threads, the callback runtime exists already
+  assert.ok(runtimeThreads() - firstMount < firstMount - beforeMounts)
+})
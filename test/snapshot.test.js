@@ -0,0 +1,27 @@
+const { test } = require('node:test')
+const assert = require('node:assert')
+const { FuseFS } = require('./helpers')
+
+// Snapshot of `path`, with its parents, where the path was then rewritten to `crafted`
+async function craftedSnapshot(path, crafted) {
+  const vfs = new FuseFS()
+  await vfs.addFile(path, Buffer.from('data'))
+  const snapshot = await vfs.snapshot()
+  const at = snapshot.indexOf(path)
+  snapshot.write(crafted, at)
+  return snapshot
+}
+
+test('restore rejects paths that are not reachable from the root', async () => {
+  const vfs = new FuseFS()
+  await vfs.addFile('kept', Buffer.from('data'))
+
+  for (const snapshot of [await craftedSnapshot('a/bb/x', 'a/../x'), await craftedSnapshot('ab/x', 'zz/x')]) {
+    await assert.rejects(vfs.restore(snapshot), /corrupt snapshot/)
+  }
+  assert.strictEqual(await vfs.exists('kept'), 'file')
+  assert.strictEqual(await vfs.exists('x'), null)
+
+  await vfs.restore(await craftedSnapshot('ab/x', 'ab/y'))
+  assert.strictEqual(await vfs.exists('ab/y'), 'file')
+})
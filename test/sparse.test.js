@@ -0,0 +1,28 @@
+const { test } = require('node:test')
+const assert = require('node:assert')
+const { FuseFS, mountFS, shell } = require('./helpers')
+
+test('SEEK_DATA and SEEK_HOLE walk the runs of a sparse file', async (t) => {
+  const vfs = new FuseFS()
+  await vfs.addFile('sparse', Buffer.from('head'))
+  await vfs.writeFileAt('sparse', 1 << 20, Buffer.from('tail'))
+  const mountPath = await mountFS(t, vfs)
+  if (!mountPath) return
+
+  const script = [
+    'import os',
+    'fd = os.open("sparse", os.O_RDONLY)',
+    'print(os.lseek(fd, 0, os.SEEK_HOLE), os.lseek(fd, 4, os.SEEK_DATA), os.lseek(fd, 4, os.SEEK_HOLE), os.lseek(fd, 1 << 20, os.SEEK_HOLE))'
+  ].join('\n')
+  assert.strictEqual(await shell(`python3 -c '${script}'`, mountPath), `4 ${1 << 20} 4 ${(1 << 20) + 4}\n`)
+})
+
+test('reading a huge sparse file whole is refused, ranges still work', async (t) => {
+  const vfs = new FuseFS()
+  await vfs.addFile('huge', Buffer.alloc(0))
+  await vfs.writeFileAt('huge', 4 * 1024 * 1024 * 1024, Buffer.from('end'))
+
+  await assert.rejects(vfs.readFile('huge'), (e) => e.message.startsWith('EFBIG'))
+  assert.strictEqual((await vfs.readFile('huge', 4 * 1024 * 1024 * 1024, 3)).toString(), 'end')
+  assert.deepStrictEqual(await vfs.readFile('huge', 0, 4), Buffer.alloc(4))
+})
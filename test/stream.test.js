@@ -0,0 +1,51 @@
+const { test } = require('node:test')
+const assert = require('node:assert')
+const { Readable } = require('node:stream')
+const { FuseFS } = require('./helpers')
+
+// Emits `chunks` then fails with `error` without ending
+function failingStream(chunks, error) {
+  const stream = new Readable({ read() {} })
+  for (const chunk of chunks) stream.push(Buffer.from(chunk))
+  setImmediate(() => stream.destroy(new Error(error)))
+  return stream
+}
+
+test('a stream replaces an existing file only once it ends', async () => {
+  const vfs = new FuseFS()
+  await vfs.addFile('file', Buffer.from('original'))
+  const stream = new Readable({ read() {} })
+  const ingested = vfs.addFileFromStream('file', stream)
+
+  stream.push(Buffer.from('new '))
+  await new Promise(setImmediate)
+  assert.strictEqual((await vfs.readFile('file')).toString(), 'original')
+
+  stream.push(Buffer.from('content'))
+  stream.push(null)
+  await ingested
+  assert.strictEqual((await vfs.readFile('file')).toString(), 'new content')
+})
+
+test('a failed stream leaves an existing file untouched', async () => {
+  const vfs = new FuseFS()
+  await vfs.addFile('file', Buffer.from('original'))
+
+  await assert.rejects(vfs.addFileFromStream('file', failingStream(['partial'], 'connection reset'), {}), /connection reset/)
+  assert.strictEqual((await vfs.readFile('file')).toString(), 'original')
+})
+
+test('a failed stream creates nothing at a new path', async () => {
+  const vfs = new FuseFS()
+
+  await assert.rejects(vfs.addFileFromStream('dir/file', failingStream(['partial'], 'connection reset')), /connection reset/)
+  assert.strictEqual(await vfs.exists('dir/file'), null)
+})
+
+test('keepPartial stores what was received before the failure', async () => {
+  const vfs = new FuseFS()
+  await vfs.addFile('file', Buffer.from('original'))
+
+  await assert.rejects(vfs.addFileFromStream('file', failingStream(['partial'], 'connection reset'), { keepPartial: true }))
+  assert.strictEqual((await vfs.readFile('file')).toString(), 'partial')
+})
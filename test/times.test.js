@@ -0,0 +1,14 @@
+const { test } = require('node:test')
+const assert = require('node:assert')
+const { FuseFS } = require('./helpers')
+
+test('setTimes rejects times that cannot be represented', async () => {
+  const vfs = new FuseFS()
+  await vfs.addFile('t', Buffer.from('data'))
+
+  assert.throws(() => vfs.setTimes('t', { mtime: 1e300 }), /mtime is not a valid time/)
+  assert.throws(() => vfs.setTimes('t', { atime: -1e300 }), /atime is not a valid time/)
+  assert.throws(() => vfs.setTimes('t', { mtime: new Date('x') }), /mtime is not a valid time/)
+  await vfs.setTimes('t', { mtime: 1000 })
+  assert.strictEqual((await vfs.stat('t')).mtime, 1000)
+})
@@ -0,0 +1,37 @@
+const { test } = require('node:test')
+const assert = require('node:assert')
+const { FuseFS, mountFS, shell } = require('./helpers')
+
+test('redirecting into an existing file replaces its content', async (t) => {
+  const vfs = new FuseFS()
+  await vfs.addFile('existing', Buffer.from('previous content'))
+  const mountPath = await mountFS(t, vfs)
+  if (!mountPath) return
+
+  await shell('echo x > existing', mountPath)
+  assert.strictEqual(await shell('cat existing', mountPath), 'x\n')
+  await shell('echo hi > existing', mountPath)
+  await shell('echo hi > existing', mountPath)
+  assert.strictEqual(await shell('cat existing', mountPath), 'hi\n')
+  assert.strictEqual((await vfs.readFile('existing')).toString(), 'hi\n')
+})
+
+test('redirecting into a lazy file does not bring the provider content back', async (t) => {
+  const vfs = new FuseFS()
+  await vfs.addLazyFile('lazy', 16, () => Buffer.from('provider content'))
+  const mountPath = await mountFS(t, vfs)
+  if (!mountPath) return
+
+  await shell('echo x > lazy', mountPath)
+  assert.strictEqual(await shell('cat lazy', mountPath), 'x\n')
+})
+
+test('truncating a lazy file keeps the start of its content', async (t) => {
+  const vfs = new FuseFS()
+  await vfs.addLazyFile('lazy', 16, () => Buffer.from('provider content'))
+  const mountPath = await mountFS(t, vfs)
+  if (!mountPath) return
+
+  await shell('truncate -s 8 lazy', mountPath)
+  assert.strictEqual(await shell('cat lazy', mountPath), 'provider')
+})
@@ -0,0 +1,24 @@
+const { test } = require('node:test')
+const assert = require('node:assert')
+const fs = require('node:fs')
+const path = require('node:path')
+const { FuseFS, mountFS } = require('./helpers')
+
+test('TTL options must be numbers', () => {
+  const vfs = new FuseFS()
+  // Options are checked before anything is mounted, mount() throws right away
+  assert.throws(() => vfs.mount('/nonexistent', { entryTtlMs: '5' }), /mount option 'entryTtlMs' must be a number/)
+  assert.throws(() => vfs.mount('/nonexistent', { attrTtlMs: {} }), /mount option 'attrTtlMs' must be a number/)
+})
+
+test('a zero attribute TTL shows host changes at once', async (t) => {
+  const vfs = new FuseFS()
+  await vfs.addFile('file', Buffer.from('1234'))
+  const mountPath = await mountFS(t, vfs, { attrTtlMs: 0, entryTtlMs: 0 })
+  if (!mountPath) return
+
+  const file = path.join(mountPath, 'file')
+  assert.strictEqual((await fs.promises.stat(file)).size, 4)
+  await vfs.appendFile('file', Buffer.from('5678'))
+  assert.strictEqual((await fs.promises.stat(file)).size, 8)
+})